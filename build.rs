@@ -0,0 +1,34 @@
+//! Embeds the git commit and rustc version for the `apollo_air1_build_info` metric.
+
+use std::env;
+use std::process::Command;
+
+fn main() {
+    // Builds without a git checkout (e.g. Docker) can pass the commit in
+    let git_sha = env::var("APOLLO_GIT_SHA")
+        .ok()
+        .filter(|sha| !sha.is_empty())
+        .or_else(|| command_output("git", &["rev-parse", "--short", "HEAD"]))
+        .unwrap_or_else(|| "unknown".to_string());
+
+    // "rustc 1.90.0 (1159e78c4 2025-09-14)" -> "1.90.0"
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version = command_output(&rustc, &["--version"])
+        .and_then(|output| output.split_whitespace().nth(1).map(str::to_string))
+        .unwrap_or_else(|| "unknown".to_string());
+
+    println!("cargo:rustc-env=APOLLO_GIT_SHA={}", git_sha);
+    println!("cargo:rustc-env=APOLLO_RUSTC_VERSION={}", rustc_version);
+    println!("cargo:rerun-if-env-changed=APOLLO_GIT_SHA");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+}
+
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let stdout = String::from_utf8(output.stdout).ok()?;
+    Some(stdout.trim().to_string())
+}
@@ -1,36 +1,364 @@
 use anyhow::{Result, anyhow};
-use reqwest::Client;
+use futures_util::{StreamExt, stream};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Certificate, Client, Url};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::time::Duration;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
 use tracing::{debug, info, warn};
 
+use crate::config::WebServerVersion;
+
+/// Sensor ids paired with their display names
+type SensorList = Vec<(String, String)>;
+
+/// Firmware details, `None` if the device has none, and when they were fetched
+type CachedDeviceInfo = (Instant, Option<DeviceInfo>);
+
 #[derive(Debug, Clone)]
 pub struct ApolloClient {
     client: Client,
-    base_url: String,
+    // Client without an overall request timeout, for long-lived event streams
+    stream_client: Client,
+    // Device URL, with a trailing slash so endpoints join below its path
+    base_url: Url,
+    auth: Option<BasicAuth>,
+    // Set once the device has answered the bulk endpoint with a client error
+    // or something other than a list of entities
+    bulk_unsupported: Arc<AtomicBool>,
+    // Set once the device has answered the problem binary sensor with 404
+    problem_unsupported: Arc<AtomicBool>,
+    // Last fetched firmware details
+    device_info: Arc<Mutex<Option<CachedDeviceInfo>>>,
+    // Sensors discovered on the device as (sensor id, name), if discovery succeeded
+    discovered_sensors: Arc<Mutex<Option<SensorList>>>,
+    max_retries: u32,
+    retry_backoff: Duration,
+    connect_timeout: Duration,
+    scrape_timeout: Option<Duration>,
+    sensor_mapping: Arc<SensorMapping>,
+    sensor_concurrency: usize,
+    // Spaces out requests to the device, if rate limited
+    rate_limiter: Option<Arc<RateLimiter>>,
+    web_server_version: WebServerVersion,
+    // Version seen in the first sensor response, when detecting it
+    detected_version: Arc<OnceLock<WebServerVersion>>,
+}
+
+/// How a device's sensor ids map to the ids they are reported as
+#[derive(Debug)]
+struct SensorMapping {
+    aliases: HashMap<String, String>,
+    co2_sensor_ids: Vec<String>,
+    // CO2 candidate that answered first, once one has
+    co2_sensor: Mutex<Option<String>>,
+    // CO2 candidates the device has answered with 404
+    co2_missing: Mutex<HashSet<String>>,
+}
+
+/// HTTP client settings shared by device connections
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    /// Timeout for each HTTP request
+    pub timeout: Duration,
+    /// Timeout for the requests of a connection test
+    pub connect_timeout: Duration,
+    /// Time budget for a whole status fetch, if bounded
+    pub scrape_timeout: Option<Duration>,
+    /// Accept invalid or self-signed TLS certificates
+    pub insecure_skip_verify: bool,
+    /// Additional trusted root certificates
+    pub ca_certs: Vec<Certificate>,
+    /// Retries of a sensor fetch after transport errors or 5xx responses
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled for each further attempt
+    pub retry_backoff: Duration,
+    /// Maps device sensor ids to the canonical ids they should be reported as
+    pub sensor_aliases: HashMap<String, String>,
+    /// Sensor ids the CO2 reading may be reported under, in order of preference
+    pub co2_sensor_ids: Vec<String>,
+    /// How long an idle keep-alive connection is kept for reuse
+    pub pool_idle_timeout: Duration,
+    /// Idle keep-alive connections kept per device
+    pub pool_max_idle_per_host: usize,
+    /// User-Agent header sent with every device request
+    pub user_agent: String,
+    /// Additional headers sent with every device request, as (name, value)
+    pub headers: Vec<(String, String)>,
+    /// Sensors fetched in parallel when the device has no bulk endpoint
+    pub sensor_concurrency: usize,
+    /// Requests sent to a device per second at most, if rate limited
+    pub max_requests_per_second: Option<f64>,
+    /// Version of the devices' `web_server` API, detected unless set
+    pub web_server_version: WebServerVersion,
+}
+
+impl ClientOptions {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            connect_timeout: Duration::from_secs(3),
+            scrape_timeout: None,
+            insecure_skip_verify: false,
+            ca_certs: Vec::new(),
+            max_retries: 2,
+            retry_backoff: Duration::from_millis(200),
+            sensor_aliases: HashMap::new(),
+            co2_sensor_ids: DEFAULT_CO2_SENSOR_IDS.map(String::from).to_vec(),
+            pool_idle_timeout: Duration::from_secs(15),
+            pool_max_idle_per_host: 1,
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            headers: Vec::new(),
+            sensor_concurrency: 4,
+            max_requests_per_second: None,
+            web_server_version: WebServerVersion::Auto,
+        }
+    }
+}
+
+/// Why a request to a device failed, as exported in the `reason` label of
+/// `apollo_air1_device_last_error`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureReason {
+    Timeout,
+    ConnectionRefused,
+    ConnectionFailed,
+    Http(reqwest::StatusCode),
+    InvalidResponse,
+}
+
+impl FailureReason {
+    fn of(error: &reqwest::Error) -> Self {
+        if error.is_timeout() {
+            Self::Timeout
+        } else if error.is_connect() {
+            if io_error_kind(error) == Some(std::io::ErrorKind::ConnectionRefused) {
+                Self::ConnectionRefused
+            } else {
+                Self::ConnectionFailed
+            }
+        } else if error.is_decode() {
+            Self::InvalidResponse
+        } else if let Some(status) = error.status() {
+            Self::Http(status)
+        } else {
+            Self::ConnectionFailed
+        }
+    }
+}
+
+impl std::fmt::Display for FailureReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Timeout => f.write_str("timeout"),
+            Self::ConnectionRefused => f.write_str("connection_refused"),
+            Self::ConnectionFailed => f.write_str("connection_failed"),
+            Self::Http(status) => write!(f, "http_{}", status.as_u16()),
+            Self::InvalidResponse => f.write_str("invalid_response"),
+        }
+    }
+}
+
+/// A failed device request, keeping why it failed next to the message
+#[derive(Debug, thiserror::Error)]
+#[error("{message}")]
+pub struct RequestError {
+    pub reason: FailureReason,
+    message: String,
+}
+
+impl RequestError {
+    pub fn new(reason: FailureReason, message: String) -> Self {
+        Self { reason, message }
+    }
+}
+
+/// Finds the I/O error kind underneath an error, if any
+fn io_error_kind(error: &(dyn std::error::Error + 'static)) -> Option<std::io::ErrorKind> {
+    let mut source = Some(error);
+    while let Some(error) = source {
+        if let Some(io_error) = error.downcast_ref::<std::io::Error>() {
+            return Some(io_error.kind());
+        }
+        source = error.source();
+    }
+    None
+}
+
+/// Classifies a failed device fetch for the `reason` label; errors of no
+/// known kind are reported as `error`
+pub fn failure_reason(error: &anyhow::Error) -> String {
+    for cause in error.chain() {
+        if let Some(request_error) = cause.downcast_ref::<RequestError>() {
+            return request_error.reason.to_string();
+        }
+        match io_error_kind(cause) {
+            Some(std::io::ErrorKind::ConnectionRefused) => {
+                return FailureReason::ConnectionRefused.to_string();
+            }
+            Some(std::io::ErrorKind::TimedOut) => return FailureReason::Timeout.to_string(),
+            _ => {}
+        }
+    }
+    "error".to_string()
+}
+
+/// Spaces requests evenly so no more than a set number per second are sent,
+/// protecting devices whose web server stalls under load
+#[derive(Debug)]
+struct RateLimiter {
+    interval: Duration,
+    next_slot: Mutex<tokio::time::Instant>,
+}
+
+impl RateLimiter {
+    fn new(per_second: f64) -> Self {
+        Self {
+            interval: Duration::from_secs_f64(1.0 / per_second),
+            next_slot: Mutex::new(tokio::time::Instant::now()),
+        }
+    }
+
+    /// Waits for the next free slot
+    async fn acquire(&self) {
+        let slot = {
+            let mut next_slot = self.next_slot.lock().unwrap();
+            let slot = (*next_slot).max(tokio::time::Instant::now());
+            *next_slot = slot + self.interval;
+            slot
+        };
+        tokio::time::sleep_until(slot).await;
+    }
+}
+
+/// HTTP basic auth credentials for devices behind ESPHome `web_server` auth
+#[derive(Clone, PartialEq)]
+pub struct BasicAuth {
+    pub username: String,
+    pub password: Option<String>,
+}
+
+// Hand-written so the password never ends up in debug logs
+impl std::fmt::Debug for BasicAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BasicAuth")
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct SensorData {
     pub id: String,
+    /// NaN when the entity reports no numeric value
+    #[serde(default = "missing_value", deserialize_with = "deserialize_value")]
     pub value: f64,
     pub state: String,
+    /// Entity name, only reported by `web_server` v3
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Unit of measurement, only reported by `web_server` v3
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uom: Option<String>,
 }
 
-#[derive(Debug, Clone)]
+impl SensorData {
+    /// Falls back to the number leading `state` (e.g. "23.4 °C") when the
+    /// entity doesn't populate `value`
+    fn with_state_fallback(mut self) -> Self {
+        if self.value.is_nan()
+            && let Some(value) = parse_state_value(&self.state)
+        {
+            self.value = value;
+        }
+        self
+    }
+}
+
+fn missing_value() -> f64 {
+    f64::NAN
+}
+
+fn deserialize_value<'de, D>(deserializer: D) -> std::result::Result<f64, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(Option::<f64>::deserialize(deserializer)?.unwrap_or(f64::NAN))
+}
+
+#[derive(Debug, Deserialize)]
+struct TextSensorData {
+    state: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct BinarySensorData {
+    value: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct ApolloStatus {
     pub sensors: HashMap<String, SensorValue>,
     pub device_name: String,
+    pub info: Option<DeviceInfo>,
 }
 
-#[derive(Debug, Clone)]
+/// Firmware details reported by the device's text sensors
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct DeviceInfo {
+    pub esphome_version: String,
+    pub project_name: String,
+    pub project_version: String,
+    pub ip_address: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct SensorValue {
     pub value: f64,
     pub unit: String,
     pub name: String,
 }
 
+/// Endpoint returning all sensor states as a JSON array
+const BULK_SENSORS_PATH: &str = "sensor";
+
+/// User-Agent sent to devices unless overridden
+pub const DEFAULT_USER_AGENT: &str = concat!("apollo-air1-exporter/", env!("CARGO_PKG_VERSION"));
+
+/// How long fetched device info is reused before asking the device again
+const DEVICE_INFO_REFRESH: Duration = Duration::from_secs(3600);
+
+/// Binary sensor that is on while the device reports a sensor problem,
+/// exported as the `sensor_problem` sensor (1 or 0)
+pub const PROBLEM_SENSOR: &str = "sensor_problem";
+
+/// Sensors requested by a connection test besides the CO2 candidates; any
+/// one answering is enough
+const CONNECTION_TEST_SENSORS: &[&str] = &["esp_temperature", "uptime"];
+
+/// Id the CO2 reading is reported under
+pub const CO2_SENSOR: &str = "co2";
+
+/// Sensor ids the CO2 reading is looked up under unless configured, as the
+/// entity id depends on the firmware's sensor component
+pub const DEFAULT_CO2_SENSOR_IDS: [&str; 5] =
+    ["co2", "scd4x_co2", "scd40_co2", "scd41_co2", "scd30_co2"];
+
+/// ESPHome Server-Sent Events endpoint
+const EVENTS_PATH: &str = "events";
+
+/// How long to collect the initial state burst of the event stream when
+/// discovering sensors
+const DISCOVERY_WINDOW: Duration = Duration::from_secs(3);
+
+/// Maximum silence on an event stream before it is considered dead. ESPHome
+/// sends periodic ping events, so a healthy stream is never idle this long.
+const EVENTS_READ_TIMEOUT: Duration = Duration::from_secs(60);
+
 // Known Apollo Air-1 sensors - using ESPHome sensor names
 const KNOWN_SENSORS: &[(&str, &str)] = &[
     ("co2", "CO2"),
@@ -41,239 +369,2058 @@ const KNOWN_SENSORS: &[(&str, &str)] = &[
     ("pm__10_m_weight_concentration", "PM10"),
     ("sen55_voc", "VOC"),
     ("sen55_nox", "NOx"),
+    // Raw gas signals behind the indices, only exposed by some firmwares
+    ("sen55_voc_raw", "VOC Raw"),
+    ("sen55_nox_raw", "NOx Raw"),
     ("dps310_pressure", "Pressure"),
     ("illuminance", "Illuminance"),
     ("esp_temperature", "ESP Temperature"),
     ("rssi", "WiFi RSSI"),
+    ("uptime", "Uptime"),
+    // 1 while the SCD4x automatic self-calibration is on, 0 when off
+    ("co2_self_calibration", "CO2 Self-Calibration"),
 ];
 
 impl ApolloClient {
-    pub fn new(base_url: String, timeout: Duration) -> Result<Self> {
-        let client = Client::builder()
-            .timeout(timeout)
+    pub fn new(base_url: String, options: &ClientOptions) -> Result<Self> {
+        let base_url = parse_base_url(&base_url)?;
+
+        let client = client_builder(options)?
+            .timeout(options.timeout)
+            .build()
+            .map_err(|e| anyhow!("Failed to create HTTP client: {}", e))?;
+
+        let stream_client = client_builder(options)?
+            .connect_timeout(options.timeout)
+            .read_timeout(EVENTS_READ_TIMEOUT)
             .build()
             .map_err(|e| anyhow!("Failed to create HTTP client: {}", e))?;
 
-        Ok(Self { client, base_url })
+        Ok(Self {
+            client,
+            stream_client,
+            base_url,
+            auth: None,
+            bulk_unsupported: Arc::new(AtomicBool::new(false)),
+            problem_unsupported: Arc::new(AtomicBool::new(false)),
+            device_info: Arc::new(Mutex::new(None)),
+            discovered_sensors: Arc::new(Mutex::new(None)),
+            max_retries: options.max_retries,
+            retry_backoff: options.retry_backoff,
+            connect_timeout: options.connect_timeout,
+            scrape_timeout: options.scrape_timeout,
+            sensor_mapping: Arc::new(SensorMapping {
+                aliases: options.sensor_aliases.clone(),
+                co2_sensor_ids: options.co2_sensor_ids.clone(),
+                co2_sensor: Mutex::new(None),
+                co2_missing: Mutex::new(HashSet::new()),
+            }),
+            sensor_concurrency: options.sensor_concurrency.max(1),
+            rate_limiter: options
+                .max_requests_per_second
+                .map(|per_second| Arc::new(RateLimiter::new(per_second))),
+            web_server_version: options.web_server_version,
+            detected_version: Arc::new(OnceLock::new()),
+        })
+    }
+
+    /// Sends HTTP basic auth credentials with every request
+    pub fn with_basic_auth(mut self, auth: Option<BasicAuth>) -> Self {
+        self.auth = auth;
+        self
     }
 
+    /// Fetches all sensors and device details, within the scrape timeout if
+    /// one is set
     pub async fn get_status(&self, device_name: &str) -> Result<ApolloStatus> {
+        let Some(scrape_timeout) = self.scrape_timeout else {
+            return self.fetch_status(device_name).await;
+        };
+
+        tokio::time::timeout(scrape_timeout, self.fetch_status(device_name))
+            .await
+            .map_err(|_| {
+                anyhow::Error::new(RequestError::new(
+                    FailureReason::Timeout,
+                    format!(
+                        "Scrape of {} took longer than {:?}",
+                        self.base_url, scrape_timeout
+                    ),
+                ))
+            })?
+    }
+
+    async fn fetch_status(&self, device_name: &str) -> Result<ApolloStatus> {
+        let mut status = self.get_sensor_status(device_name).await?;
+        if let Some(problem) = self.get_problem_sensor().await {
+            status.sensors.insert(PROBLEM_SENSOR.to_string(), problem);
+        }
+        status.info = self.get_device_info().await;
+        Ok(status)
+    }
+
+    /// Fetches the problem binary sensor as a 1/0 reading
+    ///
+    /// Returns `None` if it can't be read; firmware without it isn't asked again.
+    async fn get_problem_sensor(&self) -> Option<SensorValue> {
+        if self.problem_unsupported.load(Ordering::Relaxed) {
+            return None;
+        }
+
+        match self.get_binary_sensor(PROBLEM_SENSOR).await {
+            Ok(Some(problem)) => Some(SensorValue {
+                value: if problem { 1.0 } else { 0.0 },
+                unit: String::new(),
+                name: "Sensor Problem".to_string(),
+            }),
+            Ok(None) => {
+                debug!("No {} binary sensor on {}", PROBLEM_SENSOR, self.base_url);
+                self.problem_unsupported.store(true, Ordering::Relaxed);
+                None
+            }
+            Err(e) => {
+                debug!("Binary sensor {} not available: {}", PROBLEM_SENSOR, e);
+                None
+            }
+        }
+    }
+
+    async fn get_sensor_status(&self, device_name: &str) -> Result<ApolloStatus> {
         debug!("Fetching status from Apollo Air-1 at {}", self.base_url);
 
-        let mut sensors = HashMap::new();
+        // Prefer a single bulk request, falling back to one request per sensor
+        // on devices that don't expose the bulk endpoint
+        if !self.bulk_unsupported.load(Ordering::Relaxed) {
+            match self.get_status_bulk(device_name).await? {
+                Some(status) => return Ok(status),
+                None => {
+                    info!(
+                        "Bulk sensor endpoint not available on {}, fetching sensors individually",
+                        device_name
+                    );
+                    self.bulk_unsupported.store(true, Ordering::Relaxed);
+                }
+            }
+        }
+
+        // Try to fetch each discovered (or known) sensor, a few at a time so
+        // the device's web server isn't flooded with connections
+        let mut fetched: Vec<_> = stream::iter(self.sensor_ids())
+            .map(|(sensor_id, sensor_name)| async move {
+                let result = self.get_sensor(&sensor_id).await;
+                (sensor_id, sensor_name, result)
+            })
+            .buffer_unordered(self.sensor_concurrency)
+            .collect()
+            .await;
+        // Responses arrive in any order; sort so duplicates resolve the same
+        // way every time, CO2 candidates in order of preference
+        fetched.sort_by_cached_key(|(sensor_id, _, _)| {
+            (self.co2_candidate_rank(sensor_id), sensor_id.clone())
+        });
+        if let Some((sensor_id, _, _)) = fetched.iter().find(|(sensor_id, _, result)| {
+            result.is_ok() && self.co2_candidate_rank(sensor_id).is_some()
+        }) {
+            self.record_co2_sensor(sensor_id);
+        }
 
-        // Try to fetch each known sensor
-        for (sensor_id, sensor_name) in KNOWN_SENSORS {
-            match self.get_sensor(sensor_id).await {
+        let mut sensors = HashMap::new();
+        let mut first_error = None;
+        for (sensor_id, sensor_name, result) in fetched {
+            match result {
                 Ok(data) => {
-                    let unit = extract_unit(&data.state, data.value);
-                    let sensor = SensorValue {
-                        value: data.value,
-                        unit,
-                        name: sensor_name.to_string(),
-                    };
-                    debug!("Got {}: {} {}", sensor.name, sensor.value, sensor.unit);
-                    sensors.insert(sensor_id.to_string(), sensor);
+                    let (id, name) = self.canonical_sensor(&sensor_id, &sensor_name);
+                    insert_reading(&mut sensors, &sensor_id, id, sensor_value(&data, &name));
                 }
                 Err(e) => {
                     debug!("Sensor {} not available: {}", sensor_id, e);
+                    self.record_missing_co2_sensor(&sensor_id, &e);
+                    first_error.get_or_insert(e);
                 }
             }
         }
 
-        if sensors.is_empty() {
-            return Err(anyhow!("No sensors found on device"));
+        // With nothing fetched, report why the requests failed
+        match first_error {
+            Some(e) if sensors.is_empty() => Err(e),
+            _ => build_status(sensors, device_name),
         }
+    }
 
-        info!("Retrieved {} sensors from {}", sensors.len(), device_name);
+    /// Fetches all sensors in a single request
+    ///
+    /// Returns `Ok(None)` if the device doesn't support the bulk endpoint;
+    /// only transport errors and server errors are returned as errors.
+    pub async fn get_status_bulk(&self, device_name: &str) -> Result<Option<ApolloStatus>> {
+        let Some(entities) = self.get_entities().await? else {
+            return Ok(None);
+        };
 
-        Ok(ApolloStatus {
-            sensors,
-            device_name: device_name.to_string(),
-        })
+        let mut sensors = HashMap::new();
+        for data in &entities {
+            match sensor_entity(&data.id) {
+                Some((sensor_id, sensor_name)) => {
+                    let (id, name) = self.canonical_sensor(&sensor_id, &sensor_name);
+                    insert_reading(&mut sensors, &sensor_id, id, sensor_value(data, &name));
+                }
+                None => {
+                    debug!("Ignoring non-sensor entity {}", data.id);
+                }
+            }
+        }
+
+        build_status(sensors, device_name).map(Some)
     }
 
-    async fn get_sensor(&self, sensor_id: &str) -> Result<SensorData> {
-        let url = format!("{}/sensor/{}", self.base_url, sensor_id);
+    /// Fetches all entity states in one request, or `None` if the firmware
+    /// has no bulk endpoint
+    ///
+    /// Firmware without it answers 404, but also 405, 401 or a 200 with an
+    /// HTML page; any client error or unparseable body counts as unsupported.
+    async fn get_entities(&self) -> Result<Option<Vec<SensorData>>> {
+        let url = self.endpoint(BULK_SENSORS_PATH)?;
 
         let response = self
-            .client
-            .get(&url)
-            .send()
+            .send(self.sensor_request(&url))
             .await
-            .map_err(|e| anyhow!("Failed to fetch sensor {}: {}", sensor_id, e))?;
+            .map_err(|e| request_error(&e, format!("Failed to fetch sensors: {}", e)))?;
 
-        if !response.status().is_success() {
-            return Err(anyhow!(
-                "Failed to fetch sensor {}: HTTP {}",
-                sensor_id,
+        if response.status().is_client_error() {
+            debug!(
+                "Bulk sensor endpoint on {} answered HTTP {}",
+                self.base_url,
                 response.status()
+            );
+            return Ok(None);
+        }
+
+        if !response.status().is_success() {
+            return Err(status_error(
+                response.status(),
+                format!("Failed to fetch sensors: HTTP {}", response.status()),
             ));
         }
 
-        let data = response
-            .json::<SensorData>()
+        let body = response
+            .bytes()
             .await
-            .map_err(|e| anyhow!("Failed to parse sensor {} data: {}", sensor_id, e))?;
+            .map_err(|e| request_error(&e, format!("Failed to fetch sensors: {}", e)))?;
+        let entities = match serde_json::from_slice::<Vec<SensorData>>(&body) {
+            Ok(entities) => entities,
+            Err(e) => {
+                debug!(
+                    "Bulk sensor endpoint on {} returned no entity list: {}",
+                    self.base_url, e
+                );
+                return Ok(None);
+            }
+        };
 
-        Ok(data)
+        Ok(Some(
+            entities
+                .into_iter()
+                .map(|data| self.normalize(data))
+                .collect(),
+        ))
     }
 
-    pub async fn test_connection(&self) -> Result<bool> {
-        // Try to fetch CO2 sensor as a connection test
-        match self.get_sensor("co2").await {
-            Ok(_) => Ok(true),
-            Err(_) => {
-                // Try ESP temperature as fallback
-                match self.get_sensor("esp_temperature").await {
-                    Ok(_) => Ok(true),
-                    Err(_) => {
-                        // Try uptime as last resort
-                        match self.get_sensor("uptime").await {
-                            Ok(_) => Ok(true),
-                            Err(e) => {
-                                warn!("Connection test failed: {}", e);
-                                Ok(false)
-                            }
-                        }
-                    }
-                }
+    /// Applies the configured alias to a sensor, reporting CO2 candidates as
+    /// `co2`; aliased known sensors take the name of their canonical id
+    fn canonical_sensor(&self, sensor_id: &str, sensor_name: &str) -> (String, String) {
+        let canonical = self
+            .sensor_mapping
+            .aliases
+            .get(sensor_id)
+            .map(String::as_str)
+            .or_else(|| {
+                self.co2_candidate_rank(sensor_id)
+                    .is_some()
+                    .then_some(CO2_SENSOR)
+            });
+        match canonical {
+            Some(canonical) => {
+                let name = KNOWN_SENSORS
+                    .iter()
+                    .find(|(id, _)| id == &canonical)
+                    .map_or(canonical, |(_, name)| name);
+                (canonical.to_string(), name.to_string())
             }
+            None => (sensor_id.to_string(), sensor_name.to_string()),
         }
     }
-}
 
-/// Extract unit from state string
-fn extract_unit(state: &str, value: f64) -> String {
-    // Try to extract unit from state string
-    // Format is usually "value unit" e.g. "25.5 °C"
-    let value_str = format!("{value}");
-    let value_str_formatted = format!("{value:.1}");
+    /// Discovers which sensors the device exposes and fetches those from now on
+    ///
+    /// Uses the bulk endpoint when available, otherwise the initial burst of
+    /// states ESPHome sends when an event stream is opened. The known sensor
+    /// list stays in use if discovery fails.
+    pub async fn discover_sensors(&self, device_name: &str) -> Result<SensorList> {
+        let found: HashMap<String, String> = match self.get_entities().await {
+            Ok(Some(entities)) => entities
+                .iter()
+                .filter_map(|data| sensor_entity(&data.id))
+                .collect(),
+            _ => self.discover_from_events().await?,
+        };
 
-    if let Some(pos) = state.find(&value_str) {
-        state[pos + value_str.len()..].trim().to_string()
-    } else if let Some(pos) = state.find(&value_str_formatted) {
-        state[pos + value_str_formatted.len()..].trim().to_string()
-    } else {
-        // Try to find common units
-        if state.contains("°C") {
-            "°C".to_string()
-        } else if state.contains("°F") {
-            "°F".to_string()
-        } else if state.contains("%") {
-            "%".to_string()
-        } else if state.contains("ppm") {
-            "ppm".to_string()
-        } else if state.contains("µg/m³") {
-            "µg/m³".to_string()
-        } else if state.contains("hPa") {
-            "hPa".to_string()
-        } else if state.contains("lx") {
-            "lx".to_string()
-        } else if state.contains("dBm") {
-            "dBm".to_string()
-        } else if state.contains(" s") {
-            "s".to_string()
-        } else {
-            String::new()
+        if found.is_empty() {
+            return Err(anyhow!("No sensors discovered on device"));
         }
+
+        let mut sensors: SensorList = found.into_iter().collect();
+        sensors.sort();
+
+        info!(
+            "Discovered {} sensors on {}: {}",
+            sensors.len(),
+            device_name,
+            sensors
+                .iter()
+                .map(|(id, _)| id.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+
+        *self.discovered_sensors.lock().unwrap() = Some(sensors.clone());
+        Ok(sensors)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use wiremock::{
-        Mock, MockServer, ResponseTemplate,
-        matchers::{method, path},
-    };
+    async fn discover_from_events(&self) -> Result<HashMap<String, String>> {
+        let mut events = self.stream_events().await?;
+        let deadline = tokio::time::Instant::now() + DISCOVERY_WINDOW;
+        let mut found = HashMap::new();
 
-    #[tokio::test]
-    async fn test_get_sensor() {
-        let mock_server = MockServer::start().await;
+        while let Ok(Ok(Some((id, sensor)))) =
+            tokio::time::timeout_at(deadline, events.next_update()).await
+        {
+            found.insert(id, sensor.name);
+        }
 
-        let sensor_response = r#"{
-            "id": "sensor-co2",
-            "value": 450.0,
-            "state": "450 ppm"
-        }"#;
+        Ok(found)
+    }
 
-        Mock::given(method("GET"))
-            .and(path("/sensor/co2"))
-            .respond_with(ResponseTemplate::new(200).set_body_string(sensor_response))
-            .mount(&mock_server)
-            .await;
+    /// Sensors to fetch individually: the discovered ones, or the known list
+    ///
+    /// The known list asks for the CO2 candidate that answered before, or
+    /// for those not answered with 404 until one has.
+    fn sensor_ids(&self) -> SensorList {
+        if let Some(sensors) = self.discovered_sensors.lock().unwrap().as_ref() {
+            return sensors.clone();
+        }
 
-        let client = ApolloClient::new(mock_server.uri(), Duration::from_secs(5)).unwrap();
+        let co2_sensors = match self.sensor_mapping.co2_sensor.lock().unwrap().as_ref() {
+            Some(sensor_id) => vec![sensor_id.clone()],
+            None => self.co2_candidates(),
+        };
+        KNOWN_SENSORS
+            .iter()
+            .filter(|(id, _)| *id != CO2_SENSOR)
+            .map(|(id, name)| (id.to_string(), name.to_string()))
+            .chain(co2_sensors.into_iter().map(|id| (id, "CO2".to_string())))
+            .collect()
+    }
 
-        let data = client.get_sensor("co2").await.unwrap();
-        assert_eq!(data.value, 450.0);
-        assert_eq!(data.state, "450 ppm");
+    /// Position of a sensor id among the CO2 candidates, if it is one
+    fn co2_candidate_rank(&self, sensor_id: &str) -> Option<usize> {
+        self.sensor_mapping
+            .co2_sensor_ids
+            .iter()
+            .position(|id| id == sensor_id)
     }
 
-    #[tokio::test]
-    async fn test_get_status() {
-        let mock_server = MockServer::start().await;
+    /// CO2 candidates that may still exist on the device, in order of preference
+    fn co2_candidates(&self) -> Vec<String> {
+        let missing = self.sensor_mapping.co2_missing.lock().unwrap();
+        self.sensor_mapping
+            .co2_sensor_ids
+            .iter()
+            .filter(|id| !missing.contains(*id))
+            .cloned()
+            .collect()
+    }
 
-        // Mock CO2 sensor
-        Mock::given(method("GET"))
-            .and(path("/sensor/co2"))
-            .respond_with(ResponseTemplate::new(200).set_body_string(
-                r#"{
-                "id": "sensor-co2",
-                "value": 520.0,
-                "state": "520 ppm"
-            }"#,
-            ))
-            .mount(&mock_server)
-            .await;
+    /// Remembers a CO2 candidate the device answered with 404, so it isn't
+    /// asked for again
+    fn record_missing_co2_sensor(&self, sensor_id: &str, error: &anyhow::Error) {
+        if self.co2_candidate_rank(sensor_id).is_some() && is_not_found(error) {
+            debug!("No {} sensor on {}", sensor_id, self.base_url);
+            self.sensor_mapping
+                .co2_missing
+                .lock()
+                .unwrap()
+                .insert(sensor_id.to_string());
+        }
+    }
 
-        // Mock temperature sensor
-        Mock::given(method("GET"))
-            .and(path("/sensor/sen55_temperature"))
-            .respond_with(ResponseTemplate::new(200).set_body_string(
-                r#"{
-                "id": "sensor-sen55_temperature",
-                "value": 22.5,
-                "state": "22.5 °C"
-            }"#,
-            ))
-            .mount(&mock_server)
-            .await;
+    /// Remembers which CO2 candidate the device answered for
+    fn record_co2_sensor(&self, sensor_id: &str) {
+        let mut co2_sensor = self.sensor_mapping.co2_sensor.lock().unwrap();
+        if co2_sensor.as_deref() != Some(sensor_id) {
+            info!("Using {} as the CO2 sensor of {}", sensor_id, self.base_url);
+            *co2_sensor = Some(sensor_id.to_string());
+        }
+    }
 
-        // Mock other sensors as not found
-        for (sensor, _) in KNOWN_SENSORS.iter().skip(2) {
-            Mock::given(method("GET"))
-                .and(path(format!("/sensor/{}", sensor)))
-                .respond_with(ResponseTemplate::new(404))
-                .mount(&mock_server)
-                .await;
+    /// Returns the device's firmware details, cached for `DEVICE_INFO_REFRESH`
+    ///
+    /// Returns `None` if the device exposes none of the version text sensors,
+    /// which is cached as well.
+    pub async fn get_device_info(&self) -> Option<DeviceInfo> {
+        if let Some((fetched, info)) = self.device_info.lock().unwrap().as_ref()
+            && fetched.elapsed() < DEVICE_INFO_REFRESH
+        {
+            return info.clone();
         }
 
-        let client = ApolloClient::new(mock_server.uri(), Duration::from_secs(5)).unwrap();
+        let esphome_version = self.get_text_sensor("esphome_version").await.ok();
+        let project_name = self.get_text_sensor("project_name").await.ok();
+        let project_version = self.get_text_sensor("project_version").await.ok();
+        let ip_address = self.get_text_sensor("ip_address").await.ok();
 
-        let status = client.get_status("Test Device").await.unwrap();
-        assert_eq!(status.device_name, "Test Device");
-        assert_eq!(status.sensors.len(), 2);
+        if esphome_version.is_none()
+            && project_name.is_none()
+            && project_version.is_none()
+            && ip_address.is_none()
+        {
+            debug!("No version information available from {}", self.base_url);
+            *self.device_info.lock().unwrap() = Some((Instant::now(), None));
+            return None;
+        }
 
-        let co2 = status.sensors.get("co2").unwrap();
-        assert_eq!(co2.value, 520.0);
-        assert_eq!(co2.unit, "ppm");
-        assert_eq!(co2.name, "CO2");
+        let info = DeviceInfo {
+            // The version sensor may append the build timestamp, e.g. "2024.6.0 Jun 10 2024, 12:00:00"
+            esphome_version: esphome_version
+                .and_then(|v| v.split_whitespace().next().map(str::to_string))
+                .unwrap_or_default(),
+            project_name: project_name.unwrap_or_default(),
+            project_version: project_version.unwrap_or_default(),
+            ip_address: ip_address.unwrap_or_default(),
+        };
 
-        let temp = status.sensors.get("sen55_temperature").unwrap();
-        assert_eq!(temp.value, 22.5);
-        assert_eq!(temp.unit, "°C");
-        assert_eq!(temp.name, "Temperature");
+        *self.device_info.lock().unwrap() = Some((Instant::now(), Some(info.clone())));
+        Some(info)
     }
 
-    #[test]
-    fn test_extract_unit() {
-        assert_eq!(extract_unit("450 ppm", 450.0), "ppm");
-        assert_eq!(extract_unit("22.5 °C", 22.5), "°C");
-        assert_eq!(extract_unit("65 %", 65.0), "%");
-        assert_eq!(extract_unit("1013.25 hPa", 1013.25), "hPa");
-        assert_eq!(extract_unit("-62 dBm", -62.0), "dBm");
-        assert_eq!(extract_unit("2.5 µg/m³", 2.5), "µg/m³");
+    /// Fetches the friendly name from the title of the web server's index
+    /// page, or `None` if the page has no title
+    pub async fn get_friendly_name(&self) -> Option<String> {
+        let url = self.endpoint("").ok()?;
+        let page = match self.send(self.request(&url)).await {
+            Ok(response) if response.status().is_success() => response.text().await.ok()?,
+            Ok(response) => {
+                debug!(
+                    "No index page on {}: HTTP {}",
+                    self.base_url,
+                    response.status()
+                );
+                return None;
+            }
+            Err(e) => {
+                debug!("Failed to fetch the index page of {}: {}", self.base_url, e);
+                return None;
+            }
+        };
+        page_title(&page)
+    }
+
+    /// Fetches the state of a text sensor, e.g. the firmware version
+    pub async fn get_text_sensor(&self, sensor_id: &str) -> Result<String> {
+        let data = self
+            .get_entity::<TextSensorData>("text_sensor", sensor_id)
+            .await?
+            .ok_or_else(|| anyhow!("Failed to fetch text sensor {}: HTTP 404", sensor_id))?;
+        Ok(data.state)
+    }
+
+    /// Fetches the state of a binary sensor, or `None` if the device has no
+    /// such entity
+    pub async fn get_binary_sensor(&self, sensor_id: &str) -> Result<Option<bool>> {
+        let data = self
+            .get_entity::<BinarySensorData>("binary_sensor", sensor_id)
+            .await?;
+        Ok(data.map(|data| data.value))
+    }
+
+    /// Fetches `/<domain>/<id>`, returning `None` on 404
+    async fn get_entity<T: DeserializeOwned>(
+        &self,
+        domain: &str,
+        sensor_id: &str,
+    ) -> Result<Option<T>> {
+        let entity = domain.replace('_', " ");
+        let url = self.endpoint(&format!("{}/{}", domain, sensor_id))?;
+
+        let response = self.send(self.request(&url)).await.map_err(|e| {
+            request_error(
+                &e,
+                format!("Failed to fetch {} {}: {}", entity, sensor_id, e),
+            )
+        })?;
+
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        if !response.status().is_success() {
+            return Err(status_error(
+                response.status(),
+                format!(
+                    "Failed to fetch {} {}: HTTP {}",
+                    entity,
+                    sensor_id,
+                    response.status()
+                ),
+            ));
+        }
+
+        let data = response.json::<T>().await.map_err(|e| {
+            parse_error(format!(
+                "Failed to parse {} {} data: {}",
+                entity, sensor_id, e
+            ))
+        })?;
+
+        Ok(Some(data))
+    }
+
+    /// Opens the ESPHome event stream for live sensor updates
+    pub async fn stream_events(&self) -> Result<SensorEvents> {
+        let url = self.endpoint(EVENTS_PATH)?;
+
+        let response = self
+            .with_auth(self.stream_client.get(&url))
+            .header(reqwest::header::ACCEPT, "text/event-stream")
+            .send()
+            .await
+            .map_err(|e| anyhow!("Failed to open event stream: {}", e))?;
+
+        if !response.status().is_success() {
+            return Err(anyhow!(
+                "Failed to open event stream: HTTP {}",
+                response.status()
+            ));
+        }
+
+        Ok(SensorEvents {
+            response,
+            buffer: Vec::new(),
+        })
+    }
+
+    /// Resolves an endpoint path relative to the device URL, keeping any
+    /// path prefix such as a reverse proxy subpath
+    fn endpoint(&self, path: &str) -> Result<String> {
+        self.base_url
+            .join(path)
+            .map(String::from)
+            .map_err(|e| anyhow!("Invalid endpoint {} for {}: {}", path, self.base_url, e))
+    }
+
+    fn request(&self, url: &str) -> reqwest::RequestBuilder {
+        self.with_auth(self.client.get(url))
+    }
+
+    /// Requests sensor states, asking `web_server` v3 for the entity name and
+    /// unit as well; older versions ignore the query
+    fn sensor_request(&self, url: &str) -> reqwest::RequestBuilder {
+        let version = self
+            .detected_version
+            .get()
+            .copied()
+            .unwrap_or(self.web_server_version);
+        match version {
+            WebServerVersion::V2 => self.request(url),
+            WebServerVersion::Auto | WebServerVersion::V3 => {
+                self.request(url).query(&[("detail", "all")])
+            }
+        }
+    }
+
+    /// Notes which `web_server` version the first sensor response came from,
+    /// when detecting it, and fills in a missing value from the state
+    fn normalize(&self, data: SensorData) -> SensorData {
+        if self.web_server_version == WebServerVersion::Auto {
+            let version = if data.name.is_some() || data.uom.is_some() {
+                WebServerVersion::V3
+            } else {
+                WebServerVersion::V2
+            };
+            if self.detected_version.set(version).is_ok() {
+                info!(
+                    "Detected web_server version {} on {}",
+                    if version == WebServerVersion::V3 {
+                        3
+                    } else {
+                        2
+                    },
+                    self.base_url
+                );
+            }
+        }
+        data.with_state_fallback()
+    }
+
+    /// Sends a request once the rate limit allows it
+    async fn send(&self, request: reqwest::RequestBuilder) -> reqwest::Result<reqwest::Response> {
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.acquire().await;
+        }
+        request.send().await
+    }
+
+    fn with_auth(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match &self.auth {
+            Some(auth) => request.basic_auth(&auth.username, auth.password.as_deref()),
+            None => request,
+        }
+    }
+
+    /// Fetches one sensor, retrying transport errors and 5xx responses with
+    /// exponential backoff. A 404 means the sensor is absent and is not retried.
+    async fn get_sensor(&self, sensor_id: &str) -> Result<SensorData> {
+        let url = self.endpoint(&format!("sensor/{}", sensor_id))?;
+
+        let mut attempt = 0;
+        let result = loop {
+            let result = self.send(self.sensor_request(&url)).await;
+            let retryable = match &result {
+                Ok(response) => response.status().is_server_error(),
+                Err(_) => true,
+            };
+            if !retryable || attempt >= self.max_retries {
+                break result;
+            }
+
+            let delay = self.retry_backoff * 2u32.pow(attempt);
+            attempt += 1;
+            debug!(
+                "Retrying sensor {} in {:?} (retry {}/{})",
+                sensor_id, delay, attempt, self.max_retries
+            );
+            tokio::time::sleep(delay).await;
+        };
+
+        let response = result.map_err(|e| {
+            request_error(&e, format!("Failed to fetch sensor {}: {}", sensor_id, e))
+        })?;
+
+        if !response.status().is_success() {
+            return Err(status_error(
+                response.status(),
+                format!(
+                    "Failed to fetch sensor {}: HTTP {}",
+                    sensor_id,
+                    response.status()
+                ),
+            ));
+        }
+
+        let data = response.json::<SensorData>().await.map_err(|e| {
+            parse_error(format!("Failed to parse sensor {} data: {}", sensor_id, e))
+        })?;
+
+        Ok(self.normalize(data))
+    }
+
+    /// Checks that the device answers by fetching any of a few sensors
+    ///
+    /// The sensors are requested a few at a time, without retries and with
+    /// the short connect timeout, so an offline device is given up on quickly.
+    pub async fn test_connection(&self) -> Result<bool> {
+        match self.check_connection().await {
+            Ok(()) => Ok(true),
+            Err(e) => {
+                warn!("Connection test failed: {}", e);
+                Ok(false)
+            }
+        }
+    }
+
+    /// Like [`Self::test_connection`], but returns why the device didn't answer
+    pub async fn check_connection(&self) -> Result<()> {
+        let mut sensor_ids = self.co2_candidates();
+        sensor_ids.extend(CONNECTION_TEST_SENSORS.iter().map(|id| id.to_string()));
+        let mut probes = stream::iter(sensor_ids)
+            .map(|sensor_id| async move {
+                let result = self.probe_sensor(&sensor_id).await;
+                (sensor_id, result)
+            })
+            .buffer_unordered(self.sensor_concurrency);
+
+        // The first sensor to answer is enough; the rest are dropped
+        let mut last_error = None;
+        while let Some((sensor_id, result)) = probes.next().await {
+            match result {
+                Ok(_) => {
+                    if self.co2_candidate_rank(&sensor_id).is_some() {
+                        self.record_co2_sensor(&sensor_id);
+                    }
+                    return Ok(());
+                }
+                Err(e) => {
+                    self.record_missing_co2_sensor(&sensor_id, &e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| anyhow!("No sensors to test the connection with")))
+    }
+
+    /// Fetches one sensor once, within the connect timeout
+    async fn probe_sensor(&self, sensor_id: &str) -> Result<SensorData> {
+        let url = self.endpoint(&format!("sensor/{}", sensor_id))?;
+
+        let response = self
+            .send(self.request(&url).timeout(self.connect_timeout))
+            .await
+            .map_err(|e| {
+                request_error(&e, format!("Failed to fetch sensor {}: {}", sensor_id, e))
+            })?;
+
+        if !response.status().is_success() {
+            return Err(status_error(
+                response.status(),
+                format!(
+                    "Failed to fetch sensor {}: HTTP {}",
+                    sensor_id,
+                    response.status()
+                ),
+            ));
+        }
+
+        response
+            .json::<SensorData>()
+            .await
+            .map_err(|e| parse_error(format!("Failed to parse sensor {} data: {}", sensor_id, e)))
+    }
+}
+
+/// Live sensor updates parsed from an ESPHome event stream
+pub struct SensorEvents {
+    response: reqwest::Response,
+    buffer: Vec<u8>,
+}
+
+impl SensorEvents {
+    /// Waits for the next state update of a known sensor
+    ///
+    /// Returns `Ok(None)` when the device closes the stream.
+    pub async fn next_update(&mut self) -> Result<Option<(String, SensorValue)>> {
+        loop {
+            while let Some(frame) = take_frame(&mut self.buffer) {
+                if let Some(update) = parse_state_frame(&frame) {
+                    return Ok(Some(update));
+                }
+            }
+
+            match self.response.chunk().await? {
+                Some(chunk) => self
+                    .buffer
+                    .extend(chunk.iter().filter(|&&byte| byte != b'\r')),
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+/// Removes and returns the next complete event frame from the buffer
+fn take_frame(buffer: &mut Vec<u8>) -> Option<String> {
+    let end = buffer.windows(2).position(|window| window == b"\n\n")?;
+    let frame: Vec<u8> = buffer.drain(..end + 2).collect();
+    Some(String::from_utf8_lossy(&frame[..end]).into_owned())
+}
+
+/// Parses an `event: state` frame into a sensor update
+fn parse_state_frame(frame: &str) -> Option<(String, SensorValue)> {
+    let mut event = "message";
+    let mut data = String::new();
+
+    for line in frame.lines() {
+        if let Some(value) = line.strip_prefix("event:") {
+            event = value.trim();
+        } else if let Some(value) = line.strip_prefix("data:") {
+            data.push_str(value.trim());
+        }
+    }
+
+    if event != "state" {
+        return None;
+    }
+
+    let sensor = match serde_json::from_str::<SensorData>(&data) {
+        Ok(sensor) => sensor.with_state_fallback(),
+        Err(e) => {
+            debug!("Ignoring non-sensor state event: {}", e);
+            return None;
+        }
+    };
+
+    let (id, sensor_name) = sensor_entity(&sensor.id)?;
+    Some((id, sensor_value(&sensor, &sensor_name)))
+}
+
+/// Extracts the contents of an HTML page's `<title>`, if not empty
+fn page_title(page: &str) -> Option<String> {
+    let start = page.find("<title>")? + "<title>".len();
+    let end = start + page[start..].find("</title>")?;
+    let title = page[start..end].trim();
+    (!title.is_empty()).then(|| title.to_string())
+}
+
+/// Parses a device URL, ending its path with a slash so relative endpoints
+/// resolve below it rather than replacing its last segment
+fn parse_base_url(base_url: &str) -> Result<Url> {
+    let mut url =
+        Url::parse(base_url).map_err(|e| anyhow!("Invalid device URL {}: {}", base_url, e))?;
+    if url.cannot_be_a_base() {
+        return Err(anyhow!("Invalid device URL {}", base_url));
+    }
+    if !url.path().ends_with('/') {
+        let path = format!("{}/", url.path());
+        url.set_path(&path);
+    }
+    Ok(url)
+}
+
+fn client_builder(options: &ClientOptions) -> Result<reqwest::ClientBuilder> {
+    let mut builder = Client::builder()
+        .danger_accept_invalid_certs(options.insecure_skip_verify)
+        .pool_idle_timeout(options.pool_idle_timeout)
+        .pool_max_idle_per_host(options.pool_max_idle_per_host)
+        .user_agent(options.user_agent.as_str())
+        .default_headers(header_map(&options.headers)?);
+
+    for cert in &options.ca_certs {
+        builder = builder.add_root_certificate(cert.clone());
+    }
+
+    Ok(builder)
+}
+
+/// Builds the extra request headers, hiding their values from debug output
+/// since they often carry API keys
+fn header_map(headers: &[(String, String)]) -> Result<HeaderMap> {
+    let mut map = HeaderMap::new();
+    for (name, value) in headers {
+        let header_name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| anyhow!("Invalid header name {:?}", name))?;
+        let mut header_value = HeaderValue::from_str(value)
+            .map_err(|_| anyhow!("Invalid value for header {}", name))?;
+        header_value.set_sensitive(true);
+        map.insert(header_name, header_value);
+    }
+    Ok(map)
+}
+
+/// Maps an ESPHome entity id to a sensor id and name
+///
+/// Known sensors get their curated name; other entities of the sensor domain
+/// are named after their id. Returns `None` for non-sensor entities.
+pub fn sensor_entity(entity_id: &str) -> Option<(String, String)> {
+    // ESPHome prefixes entity ids with their domain, e.g. "sensor-co2"
+    let stripped = entity_id.strip_prefix("sensor-");
+    let sensor_id = stripped.unwrap_or(entity_id);
+
+    match KNOWN_SENSORS.iter().find(|(id, _)| *id == sensor_id) {
+        Some((id, name)) => Some((id.to_string(), name.to_string())),
+        None => stripped.map(|id| (id.to_string(), id.to_string())),
+    }
+}
+
+/// Adds a reading under its canonical id, where a sensor reporting under
+/// that id itself takes precedence over others aliased to it
+fn insert_reading(
+    sensors: &mut HashMap<String, SensorValue>,
+    sensor_id: &str,
+    id: String,
+    sensor: SensorValue,
+) {
+    if sensor_id != id && sensors.contains_key(&id) {
+        debug!("Ignoring {}: {} is already reported", sensor_id, id);
+        return;
+    }
+    sensors.insert(id, sensor);
+}
+
+fn sensor_value(data: &SensorData, sensor_name: &str) -> SensorValue {
+    let sensor = SensorValue {
+        value: data.value,
+        // web_server v3 reports the unit; older versions only in the state
+        unit: match &data.uom {
+            Some(uom) => uom.clone(),
+            None => extract_unit(&data.state, data.value),
+        },
+        name: sensor_name.to_string(),
+    };
+    debug!("Got {}: {} {}", sensor.name, sensor.value, sensor.unit);
+    sensor
+}
+
+/// Whether a failed fetch was answered with 404
+fn is_not_found(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<RequestError>().is_some_and(|e| {
+        matches!(e.reason, FailureReason::Http(status) if status == reqwest::StatusCode::NOT_FOUND)
+    })
+}
+
+fn request_error(error: &reqwest::Error, message: String) -> anyhow::Error {
+    RequestError::new(FailureReason::of(error), message).into()
+}
+
+fn status_error(status: reqwest::StatusCode, message: String) -> anyhow::Error {
+    RequestError::new(FailureReason::Http(status), message).into()
+}
+
+fn parse_error(message: String) -> anyhow::Error {
+    RequestError::new(FailureReason::InvalidResponse, message).into()
+}
+
+fn build_status(sensors: HashMap<String, SensorValue>, device_name: &str) -> Result<ApolloStatus> {
+    if sensors.is_empty() {
+        return Err(anyhow!("No sensors found on device"));
+    }
+
+    info!("Retrieved {} sensors from {}", sensors.len(), device_name);
+
+    Ok(ApolloStatus {
+        sensors,
+        device_name: device_name.to_string(),
+        info: None,
+    })
+}
+
+/// Parses the number leading a state string, e.g. 23.4 from "23.4 °C"
+///
+/// Locale formatting is tolerated: "1.013,25" and "1,013.25" both read as
+/// 1013.25, and "22,5" as 22.5.
+fn parse_state_value(state: &str) -> Option<f64> {
+    let state = state.trim_start();
+    let end = state
+        .find(|c: char| !(c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | ',')))
+        .unwrap_or(state.len());
+    normalize_number(state[..end].trim_end_matches(['.', ',']))
+        .parse()
+        .ok()
+}
+
+/// Drops thousands separators from a number and makes its decimal separator
+/// a point
+///
+/// With both separators present, the last one is the decimal separator.
+/// Otherwise a repeated separator groups thousands, as does a single comma
+/// followed by exactly three digits ("101,325"), unless the integer part is
+/// zero ("0,125"); a single point is always decimal.
+fn normalize_number(number: &str) -> String {
+    let single = |separator: char| number.matches(separator).count() == 1;
+    let decimal = match (number.rfind('.'), number.rfind(',')) {
+        (Some(point), Some(comma)) => Some(if point > comma { '.' } else { ',' }),
+        (Some(_), None) => single('.').then_some('.'),
+        (None, Some(comma)) => {
+            let integer = number[..comma].trim_start_matches(['-', '+']);
+            let thousands = number.len() - comma - 1 == 3 && integer != "0";
+            (single(',') && !thousands).then_some(',')
+        }
+        (None, None) => None,
+    };
+
+    number
+        .chars()
+        .filter_map(|c| match c {
+            '.' | ',' if Some(c) == decimal => Some('.'),
+            '.' | ',' => None,
+            c => Some(c),
+        })
+        .collect()
+}
+
+/// Extract unit from state string
+fn extract_unit(state: &str, value: f64) -> String {
+    // Try to extract unit from state string
+    // Format is usually "value unit" e.g. "25.5 °C"
+    let value_str = format!("{value}");
+    let value_str_formatted = format!("{value:.1}");
+
+    if let Some(pos) = state.find(&value_str) {
+        state[pos + value_str.len()..].trim().to_string()
+    } else if let Some(pos) = state.find(&value_str_formatted) {
+        state[pos + value_str_formatted.len()..].trim().to_string()
+    } else {
+        // Try to find common units
+        if state.contains("°C") {
+            "°C".to_string()
+        } else if state.contains("°F") {
+            "°F".to_string()
+        } else if state.contains("%") {
+            "%".to_string()
+        } else if state.contains("ppm") {
+            "ppm".to_string()
+        } else if state.contains("µg/m³") {
+            "µg/m³".to_string()
+        } else if state.contains("hPa") {
+            "hPa".to_string()
+        } else if state.contains("lx") {
+            "lx".to_string()
+        } else if state.contains("dBm") {
+            "dBm".to_string()
+        } else if state.contains(" s") {
+            "s".to_string()
+        } else {
+            String::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::{
+        Mock, MockServer, ResponseTemplate,
+        matchers::{basic_auth, header, method, path, query_param, query_param_is_missing},
+    };
+
+    #[tokio::test]
+    async fn test_get_sensor() {
+        let mock_server = MockServer::start().await;
+
+        let sensor_response = r#"{
+            "id": "sensor-co2",
+            "value": 450.0,
+            "state": "450 ppm"
+        }"#;
+
+        Mock::given(method("GET"))
+            .and(path("/sensor/co2"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(sensor_response))
+            .mount(&mock_server)
+            .await;
+
+        let client = ApolloClient::new(
+            mock_server.uri(),
+            &ClientOptions::new(Duration::from_secs(5)),
+        )
+        .unwrap();
+
+        let data = client.get_sensor("co2").await.unwrap();
+        assert_eq!(data.value, 450.0);
+        assert_eq!(data.state, "450 ppm");
+    }
+
+    #[tokio::test]
+    async fn test_get_sensor_with_trailing_slash() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("GET"))
+            .and(path("/sensor/co2"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(r#"{"id": "sensor-co2", "value": 450.0, "state": "450 ppm"}"#),
+            )
+            .mount(&mock_server)
+            .await;
+
+        let client = ApolloClient::new(
+            format!("{}/", mock_server.uri()),
+            &ClientOptions::new(Duration::from_secs(5)),
+        )
+        .unwrap();
+        assert_eq!(
+            client.endpoint("sensor/co2").unwrap(),
+            format!("{}/sensor/co2", mock_server.uri())
+        );
+
+        let data = client.get_sensor("co2").await.unwrap();
+        assert_eq!(data.value, 450.0);
+
+        let requests = mock_server.received_requests().await.unwrap();
+        assert_eq!(requests[0].url.path(), "/sensor/co2");
+    }
+
+    #[tokio::test]
+    async fn test_get_sensor_behind_subpath() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("GET"))
+            .and(path("/apollo-livingroom/sensor/co2"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(r#"{"id": "sensor-co2", "value": 450.0, "state": "450 ppm"}"#),
+            )
+            .mount(&mock_server)
+            .await;
+
+        for base_url in ["/apollo-livingroom/", "/apollo-livingroom"] {
+            let client = ApolloClient::new(
+                format!("{}{}", mock_server.uri(), base_url),
+                &ClientOptions::new(Duration::from_secs(5)),
+            )
+            .unwrap();
+
+            let data = client.get_sensor("co2").await.unwrap();
+            assert_eq!(data.value, 450.0);
+        }
+    }
+
+    #[test]
+    fn test_endpoint() {
+        let options = ClientOptions::new(Duration::from_secs(5));
+
+        let client =
+            ApolloClient::new("http://proxy/apollo-livingroom/".to_string(), &options).unwrap();
+        assert_eq!(
+            client.endpoint("sensor/co2").unwrap(),
+            "http://proxy/apollo-livingroom/sensor/co2"
+        );
+        assert_eq!(
+            client.endpoint(EVENTS_PATH).unwrap(),
+            "http://proxy/apollo-livingroom/events"
+        );
+
+        let client = ApolloClient::new("http://192.168.1.100/".to_string(), &options).unwrap();
+        assert_eq!(
+            client.endpoint("sensor/co2").unwrap(),
+            "http://192.168.1.100/sensor/co2"
+        );
+
+        let client = ApolloClient::new("http://192.168.1.100:8080".to_string(), &options).unwrap();
+        assert_eq!(
+            client.endpoint(BULK_SENSORS_PATH).unwrap(),
+            "http://192.168.1.100:8080/sensor"
+        );
+
+        assert!(ApolloClient::new("not a url".to_string(), &options).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_get_sensor_with_basic_auth() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("GET"))
+            .and(path("/sensor/co2"))
+            .and(basic_auth("admin", "secret"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(r#"{"id": "sensor-co2", "value": 450.0, "state": "450 ppm"}"#),
+            )
+            .mount(&mock_server)
+            .await;
+
+        Mock::given(method("GET"))
+            .and(path("/sensor/co2"))
+            .respond_with(ResponseTemplate::new(401))
+            .mount(&mock_server)
+            .await;
+
+        let client = ApolloClient::new(
+            mock_server.uri(),
+            &ClientOptions::new(Duration::from_secs(5)),
+        )
+        .unwrap();
+        assert!(client.get_sensor("co2").await.is_err());
+
+        let auth = BasicAuth {
+            username: "admin".to_string(),
+            password: Some("secret".to_string()),
+        };
+        let client = client.with_basic_auth(Some(auth));
+        let data = client.get_sensor("co2").await.unwrap();
+        assert_eq!(data.value, 450.0);
+
+        let debug = format!("{:?}", client);
+        assert!(!debug.contains("secret"));
+    }
+
+    #[tokio::test]
+    async fn test_get_sensor_with_headers() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("GET"))
+            .and(path("/sensor/co2"))
+            .and(header("X-Api-Key", "secret"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(r#"{"id": "sensor-co2", "value": 450.0, "state": "450 ppm"}"#),
+            )
+            .mount(&mock_server)
+            .await;
+
+        let mut options = ClientOptions::new(Duration::from_secs(5));
+        options.headers = vec![("X-Api-Key".to_string(), "secret".to_string())];
+        let client = ApolloClient::new(mock_server.uri(), &options).unwrap();
+        assert_eq!(client.get_sensor("co2").await.unwrap().value, 450.0);
+
+        options.headers = vec![("Bad Name".to_string(), "secret".to_string())];
+        assert!(ApolloClient::new(mock_server.uri(), &options).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_get_status() {
+        let mock_server = MockServer::start().await;
+
+        // Mock CO2 sensor
+        Mock::given(method("GET"))
+            .and(path("/sensor/co2"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                r#"{
+                "id": "sensor-co2",
+                "value": 520.0,
+                "state": "520 ppm"
+            }"#,
+            ))
+            .mount(&mock_server)
+            .await;
+
+        // Mock temperature sensor
+        Mock::given(method("GET"))
+            .and(path("/sensor/sen55_temperature"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                r#"{
+                "id": "sensor-sen55_temperature",
+                "value": 22.5,
+                "state": "22.5 °C"
+            }"#,
+            ))
+            .mount(&mock_server)
+            .await;
+
+        // Mock other sensors as not found
+        for (sensor, _) in KNOWN_SENSORS.iter().skip(2) {
+            Mock::given(method("GET"))
+                .and(path(format!("/sensor/{}", sensor)))
+                .respond_with(ResponseTemplate::new(404))
+                .mount(&mock_server)
+                .await;
+        }
+
+        let client = ApolloClient::new(
+            mock_server.uri(),
+            &ClientOptions::new(Duration::from_secs(5)),
+        )
+        .unwrap();
+
+        let status = client.get_status("Test Device").await.unwrap();
+        assert_eq!(status.device_name, "Test Device");
+        assert_eq!(status.sensors.len(), 2);
+
+        let co2 = status.sensors.get("co2").unwrap();
+        assert_eq!(co2.value, 520.0);
+        assert_eq!(co2.unit, "ppm");
+        assert_eq!(co2.name, "CO2");
+
+        let temp = status.sensors.get("sen55_temperature").unwrap();
+        assert_eq!(temp.value, 22.5);
+        assert_eq!(temp.unit, "°C");
+        assert_eq!(temp.name, "Temperature");
+    }
+
+    #[tokio::test]
+    async fn test_sensor_aliases() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("GET"))
+            .and(path("/sensor"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                r#"[{"id": "sensor-scd40_co2", "value": 530.0, "state": "530 ppm"}]"#,
+            ))
+            .mount(&mock_server)
+            .await;
+
+        let mut options = ClientOptions::new(Duration::from_secs(5));
+        options
+            .sensor_aliases
+            .insert("scd40_co2".to_string(), "co2".to_string());
+        let client = ApolloClient::new(mock_server.uri(), &options).unwrap();
+
+        let status = client.get_status("Test Device").await.unwrap();
+        assert_eq!(status.sensors["co2"].value, 530.0);
+        assert_eq!(status.sensors["co2"].name, "CO2");
+        assert!(!status.sensors.contains_key("scd40_co2"));
+    }
+
+    #[tokio::test]
+    async fn test_sensor_alias_duplicates() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("GET"))
+            .and(path("/sensor"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                r#"[
+                {"id": "sensor-co2", "value": 500.0, "state": "500 ppm"},
+                {"id": "sensor-scd40_co2", "value": 530.0, "state": "530 ppm"}
+            ]"#,
+            ))
+            .mount(&mock_server)
+            .await;
+
+        let mut options = ClientOptions::new(Duration::from_secs(5));
+        options
+            .sensor_aliases
+            .insert("scd40_co2".to_string(), "co2".to_string());
+        let client = ApolloClient::new(mock_server.uri(), &options).unwrap();
+
+        // The device's own co2 sensor wins over the alias
+        let status = client.get_status("Test Device").await.unwrap();
+        assert_eq!(status.sensors["co2"].value, 500.0);
+    }
+
+    #[tokio::test]
+    async fn test_get_status_bulk_unsupported() {
+        let responses = [
+            ResponseTemplate::new(405),
+            ResponseTemplate::new(401),
+            ResponseTemplate::new(200).set_body_string("<html>Not here</html>"),
+        ];
+        for response in responses {
+            let mock_server = MockServer::start().await;
+
+            // Only asked once, then sensors are fetched individually
+            Mock::given(method("GET"))
+                .and(path("/sensor"))
+                .respond_with(response)
+                .expect(1)
+                .mount(&mock_server)
+                .await;
+            Mock::given(method("GET"))
+                .and(path("/sensor/co2"))
+                .respond_with(
+                    ResponseTemplate::new(200).set_body_string(
+                        r#"{"id": "sensor-co2", "value": 520.0, "state": "520 ppm"}"#,
+                    ),
+                )
+                .mount(&mock_server)
+                .await;
+
+            let client = ApolloClient::new(
+                mock_server.uri(),
+                &ClientOptions::new(Duration::from_secs(5)),
+            )
+            .unwrap();
+
+            for _ in 0..2 {
+                let status = client.get_status("Test Device").await.unwrap();
+                assert_eq!(status.sensors["co2"].value, 520.0);
+            }
+        }
+
+        // Server errors still fail the scrape
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/sensor"))
+            .respond_with(ResponseTemplate::new(500))
+            .mount(&mock_server)
+            .await;
+        let client = ApolloClient::new(
+            mock_server.uri(),
+            &ClientOptions::new(Duration::from_secs(5)),
+        )
+        .unwrap();
+        assert!(client.get_status_bulk("Test Device").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_get_status_bulk() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("GET"))
+            .and(path("/sensor"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                r#"[
+                {"id": "sensor-co2", "value": 610.0, "state": "610 ppm"},
+                {"id": "sensor-sen55_humidity", "value": 41.5, "state": "41.5 %"},
+                {"id": "sensor-something_else", "value": 1.0, "state": "1"}
+            ]"#,
+            ))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let client = ApolloClient::new(
+            mock_server.uri(),
+            &ClientOptions::new(Duration::from_secs(5)),
+        )
+        .unwrap();
+
+        let status = client.get_status("Test Device").await.unwrap();
+        assert_eq!(status.sensors.len(), 3);
+        assert_eq!(status.sensors["co2"].value, 610.0);
+        assert_eq!(status.sensors["co2"].name, "CO2");
+        assert_eq!(status.sensors["sen55_humidity"].unit, "%");
+        assert_eq!(status.sensors["something_else"].name, "something_else");
+    }
+
+    #[tokio::test]
+    async fn test_get_problem_sensor() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("GET"))
+            .and(path("/sensor"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_string(
+                    r#"[{"id": "sensor-co2", "value": 610.0, "state": "610 ppm"}]"#,
+                ),
+            )
+            .mount(&mock_server)
+            .await;
+
+        Mock::given(method("GET"))
+            .and(path("/binary_sensor/sensor_problem"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                r#"{"id": "binary_sensor-sensor_problem", "value": true, "state": "ON"}"#,
+            ))
+            .mount(&mock_server)
+            .await;
+
+        let client = ApolloClient::new(
+            mock_server.uri(),
+            &ClientOptions::new(Duration::from_secs(5)),
+        )
+        .unwrap();
+
+        assert_eq!(
+            client.get_binary_sensor("sensor_problem").await.unwrap(),
+            Some(true)
+        );
+        assert_eq!(client.get_binary_sensor("missing").await.unwrap(), None);
+
+        let status = client.get_status("Test Device").await.unwrap();
+        assert_eq!(status.sensors[PROBLEM_SENSOR].value, 1.0);
+        assert_eq!(status.sensors[PROBLEM_SENSOR].unit, "");
+    }
+
+    #[tokio::test]
+    async fn test_problem_sensor_unsupported() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("GET"))
+            .and(path("/sensor"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_string(
+                    r#"[{"id": "sensor-co2", "value": 610.0, "state": "610 ppm"}]"#,
+                ),
+            )
+            .mount(&mock_server)
+            .await;
+
+        // Firmware without the entity is only asked once
+        Mock::given(method("GET"))
+            .and(path("/binary_sensor/sensor_problem"))
+            .respond_with(ResponseTemplate::new(404))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let client = ApolloClient::new(
+            mock_server.uri(),
+            &ClientOptions::new(Duration::from_secs(5)),
+        )
+        .unwrap();
+
+        for _ in 0..2 {
+            let status = client.get_status("Test Device").await.unwrap();
+            assert!(!status.sensors.contains_key(PROBLEM_SENSOR));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_co2_sensor_candidates() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("GET"))
+            .and(path("/sensor/scd30_co2"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                r#"{"id": "sensor-scd30_co2", "value": 480.0, "state": "480 ppm"}"#,
+            ))
+            .mount(&mock_server)
+            .await;
+        // Not asked for again once another candidate has answered
+        Mock::given(method("GET"))
+            .and(path("/sensor/scd4x_co2"))
+            .respond_with(ResponseTemplate::new(404))
+            .expect(0..=1)
+            .mount(&mock_server)
+            .await;
+
+        let client = ApolloClient::new(
+            mock_server.uri(),
+            &ClientOptions::new(Duration::from_secs(5)),
+        )
+        .unwrap();
+
+        assert!(client.test_connection().await.unwrap());
+        assert_eq!(
+            client.sensor_mapping.co2_sensor.lock().unwrap().as_deref(),
+            Some("scd30_co2")
+        );
+
+        for _ in 0..2 {
+            let status = client.get_status("Test Device").await.unwrap();
+            assert_eq!(status.sensors["co2"].value, 480.0);
+            assert_eq!(status.sensors["co2"].name, "CO2");
+            assert!(!status.sensors.contains_key("scd30_co2"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_missing_co2_candidates() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("GET"))
+            .and(path("/sensor/esp_temperature"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                r#"{"id": "sensor-esp_temperature", "value": 41.0, "state": "41.0 °C"}"#,
+            ))
+            .mount(&mock_server)
+            .await;
+        // Candidates answered with 404 are only asked for once
+        for sensor_id in DEFAULT_CO2_SENSOR_IDS {
+            Mock::given(method("GET"))
+                .and(path(format!("/sensor/{}", sensor_id)))
+                .respond_with(ResponseTemplate::new(404))
+                .expect(1)
+                .mount(&mock_server)
+                .await;
+        }
+        // Never reached, as probes run one at a time and stop at the first answer
+        Mock::given(method("GET"))
+            .and(path("/sensor/uptime"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(0)
+            .mount(&mock_server)
+            .await;
+
+        let mut options = ClientOptions::new(Duration::from_secs(5));
+        options.sensor_concurrency = 1;
+        let client = ApolloClient::new(mock_server.uri(), &options).unwrap();
+
+        for _ in 0..2 {
+            client.check_connection().await.unwrap();
+        }
+        assert!(
+            client
+                .sensor_ids()
+                .iter()
+                .all(|(id, _)| !DEFAULT_CO2_SENSOR_IDS.contains(&id.as_str()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connection_fails_fast() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("GET"))
+            .and(path("/sensor/co2"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(5)))
+            .mount(&mock_server)
+            .await;
+
+        Mock::given(method("GET"))
+            .and(path("/sensor/uptime"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_string(
+                    r#"{"id": "sensor-uptime", "value": 3600.0, "state": "3600 s"}"#,
+                ),
+            )
+            .mount(&mock_server)
+            .await;
+
+        let mut options = ClientOptions::new(Duration::from_secs(10));
+        options.connect_timeout = Duration::from_millis(200);
+        let client = ApolloClient::new(mock_server.uri(), &options).unwrap();
+
+        // Uptime answers while co2 is still hanging
+        let started = Instant::now();
+        assert!(client.test_connection().await.unwrap());
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        // Nothing answers in time
+        let hanging = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(5)))
+            .mount(&hanging)
+            .await;
+        let client = ApolloClient::new(hanging.uri(), &options).unwrap();
+
+        let started = Instant::now();
+        assert!(!client.test_connection().await.unwrap());
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_failure_reason() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(401))
+            .mount(&mock_server)
+            .await;
+        let client = ApolloClient::new(
+            mock_server.uri(),
+            &ClientOptions::new(Duration::from_secs(10)),
+        )
+        .unwrap();
+        let error = client.get_status("Test Device").await.unwrap_err();
+        assert_eq!(failure_reason(&error), "http_401");
+
+        let hanging = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(5)))
+            .mount(&hanging)
+            .await;
+        let client = ApolloClient::new(
+            hanging.uri(),
+            &ClientOptions::new(Duration::from_millis(200)),
+        )
+        .unwrap();
+        let error = client.get_status("Test Device").await.unwrap_err();
+        assert_eq!(failure_reason(&error), "timeout");
+
+        // Nothing listens on a port that was just released
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let client = ApolloClient::new(
+            format!("http://127.0.0.1:{}", port),
+            &ClientOptions::new(Duration::from_secs(10)),
+        )
+        .unwrap();
+        let error = client.get_status("Test Device").await.unwrap_err();
+        assert_eq!(failure_reason(&error), "connection_refused");
+
+        assert_eq!(
+            failure_reason(&anyhow!("No sensors found on device")),
+            "error"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_friendly_name() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                "<!DOCTYPE html><html><head><title> Living Room </title></head></html>",
+            ))
+            .mount(&mock_server)
+            .await;
+        let client = ApolloClient::new(
+            mock_server.uri(),
+            &ClientOptions::new(Duration::from_secs(5)),
+        )
+        .unwrap();
+        assert_eq!(
+            client.get_friendly_name().await.as_deref(),
+            Some("Living Room")
+        );
+
+        assert_eq!(page_title("<html><title></title></html>"), None);
+        assert_eq!(page_title("<html><body>ok</body></html>"), None);
+    }
+
+    #[tokio::test]
+    async fn test_web_server_versions() {
+        const PM25: &str = "pm__2_5_m_weight_concentration";
+
+        // Version 2 reports the unit only as part of the state
+        let v2 = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path(format!("/sensor/{PM25}")))
+            .respond_with(ResponseTemplate::new(200).set_body_string(format!(
+                r#"{{"id": "sensor-{PM25}", "value": 12.5, "state": "12.5 µg/m³"}}"#
+            )))
+            .mount(&v2)
+            .await;
+        let client =
+            ApolloClient::new(v2.uri(), &ClientOptions::new(Duration::from_secs(5))).unwrap();
+
+        let data = client.get_sensor(PM25).await.unwrap();
+        assert_eq!(sensor_value(&data, "PM2.5").unit, "µg/m³");
+        assert_eq!(client.detected_version.get(), Some(&WebServerVersion::V2));
+
+        // Version 3 adds the name and unit when asked for details, which also
+        // covers sensors without a current value
+        let v3 = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path(format!("/sensor/{PM25}")))
+            .and(query_param("detail", "all"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(format!(
+                r#"{{"id": "sensor-{PM25}", "name": "PM <2.5µm Weight concentration", "icon": "", "entity_category": 0, "value": null, "state": "NA", "uom": "µg/m³"}}"#
+            )))
+            .mount(&v3)
+            .await;
+        let client =
+            ApolloClient::new(v3.uri(), &ClientOptions::new(Duration::from_secs(5))).unwrap();
+
+        let data = client.get_sensor(PM25).await.unwrap();
+        let sensor = sensor_value(&data, "PM2.5");
+        assert!(sensor.value.is_nan());
+        assert_eq!(sensor.unit, "µg/m³");
+        assert_eq!(client.detected_version.get(), Some(&WebServerVersion::V3));
+
+        // Forcing version 2 skips the details query
+        let plain = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(query_param_is_missing("detail"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(format!(
+                r#"{{"id": "sensor-{PM25}", "value": 12.5, "state": "12.5 µg/m³"}}"#
+            )))
+            .expect(1)
+            .mount(&plain)
+            .await;
+        let mut options = ClientOptions::new(Duration::from_secs(5));
+        options.web_server_version = WebServerVersion::V2;
+        let client = ApolloClient::new(plain.uri(), &options).unwrap();
+        assert_eq!(client.get_sensor(PM25).await.unwrap().value, 12.5);
+        assert_eq!(client.detected_version.get(), None);
+    }
+
+    #[tokio::test]
+    async fn test_scrape_timeout() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(r#"{"id": "sensor-co2", "value": 450.0, "state": "450 ppm"}"#)
+                    .set_delay(Duration::from_millis(300)),
+            )
+            .mount(&mock_server)
+            .await;
+
+        let mut options = ClientOptions::new(Duration::from_secs(5));
+        options.scrape_timeout = Some(Duration::from_millis(200));
+        let client = ApolloClient::new(mock_server.uri(), &options).unwrap();
+
+        let started = Instant::now();
+        let error = client.get_status("Test Device").await.unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(failure_reason(&error), "timeout");
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("GET"))
+            .and(path("/sensor/co2"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(r#"{"id": "sensor-co2", "value": 450.0, "state": "450 ppm"}"#),
+            )
+            .expect(4)
+            .mount(&mock_server)
+            .await;
+
+        let mut options = ClientOptions::new(Duration::from_secs(5));
+        options.max_requests_per_second = Some(20.0);
+        let client = ApolloClient::new(mock_server.uri(), &options).unwrap();
+
+        // Concurrent fetches are spaced 50ms apart
+        let started = Instant::now();
+        let results =
+            futures_util::future::join_all((0..4).map(|_| client.get_sensor("co2"))).await;
+        assert!(results.iter().all(Result::is_ok));
+        assert!(started.elapsed() >= Duration::from_millis(150));
+    }
+
+    #[tokio::test]
+    async fn test_get_sensor_retries_server_errors() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("GET"))
+            .and(path("/sensor/co2"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(2)
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+
+        Mock::given(method("GET"))
+            .and(path("/sensor/co2"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(r#"{"id": "sensor-co2", "value": 450.0, "state": "450 ppm"}"#),
+            )
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        Mock::given(method("GET"))
+            .and(path("/sensor/missing"))
+            .respond_with(ResponseTemplate::new(404))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let mut options = ClientOptions::new(Duration::from_secs(5));
+        options.retry_backoff = Duration::from_millis(1);
+        let client = ApolloClient::new(mock_server.uri(), &options).unwrap();
+
+        assert_eq!(client.get_sensor("co2").await.unwrap().value, 450.0);
+        // Absent sensors are not retried
+        assert!(client.get_sensor("missing").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_user_agent() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("GET"))
+            .and(path("/sensor/co2"))
+            .and(header("user-agent", DEFAULT_USER_AGENT))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(r#"{"id": "sensor-co2", "value": 450.0, "state": "450 ppm"}"#),
+            )
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        Mock::given(method("GET"))
+            .and(path("/sensor/co2"))
+            .and(header("user-agent", "home-lab/1.0"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(r#"{"id": "sensor-co2", "value": 450.0, "state": "450 ppm"}"#),
+            )
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let mut options = ClientOptions::new(Duration::from_secs(5));
+        let client = ApolloClient::new(mock_server.uri(), &options).unwrap();
+        client.get_sensor("co2").await.unwrap();
+
+        options.user_agent = "home-lab/1.0".to_string();
+        let client = ApolloClient::new(mock_server.uri(), &options).unwrap();
+        client.get_sensor("co2").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_get_sensor_value_from_state() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("GET"))
+            .and(path("/sensor/sen55_temperature"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                r#"{"id": "sensor-sen55_temperature", "value": null, "state": "23.4 °C"}"#,
+            ))
+            .mount(&mock_server)
+            .await;
+
+        Mock::given(method("GET"))
+            .and(path("/sensor/co2"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(r#"{"id": "sensor-co2", "state": "450 ppm"}"#),
+            )
+            .mount(&mock_server)
+            .await;
+
+        let client = ApolloClient::new(
+            mock_server.uri(),
+            &ClientOptions::new(Duration::from_secs(5)),
+        )
+        .unwrap();
+
+        let data = client.get_sensor("sen55_temperature").await.unwrap();
+        assert_eq!(data.value, 23.4);
+        assert_eq!(extract_unit(&data.state, data.value), "°C");
+        assert_eq!(client.get_sensor("co2").await.unwrap().value, 450.0);
+    }
+
+    #[tokio::test]
+    async fn test_discover_sensors_from_events() {
+        let mock_server = MockServer::start().await;
+
+        let body = "event: state\ndata: {\"id\":\"sensor-co2\",\"value\":480,\"state\":\"480 ppm\"}\n\n\
+                    event: state\ndata: {\"id\":\"sensor-probe_temperature\",\"value\":19.5,\"state\":\"19.5 °C\"}\n\n";
+
+        Mock::given(method("GET"))
+            .and(path("/events"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(body))
+            .mount(&mock_server)
+            .await;
+
+        Mock::given(method("GET"))
+            .and(path("/sensor/probe_temperature"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                r#"{"id": "sensor-probe_temperature", "value": 19.5, "state": "19.5 °C"}"#,
+            ))
+            .mount(&mock_server)
+            .await;
+
+        Mock::given(method("GET"))
+            .and(path("/sensor/co2"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(r#"{"id": "sensor-co2", "value": 480.0, "state": "480 ppm"}"#),
+            )
+            .mount(&mock_server)
+            .await;
+
+        let client = ApolloClient::new(
+            mock_server.uri(),
+            &ClientOptions::new(Duration::from_secs(5)),
+        )
+        .unwrap();
+
+        let sensors = client.discover_sensors("Test Device").await.unwrap();
+        assert_eq!(
+            sensors,
+            vec![
+                ("co2".to_string(), "CO2".to_string()),
+                (
+                    "probe_temperature".to_string(),
+                    "probe_temperature".to_string()
+                ),
+            ]
+        );
+
+        let status = client.get_status("Test Device").await.unwrap();
+        assert_eq!(status.sensors.len(), 2);
+        assert_eq!(status.sensors["probe_temperature"].value, 19.5);
+    }
+
+    #[tokio::test]
+    async fn test_stream_events() {
+        let mock_server = MockServer::start().await;
+
+        let body = "retry: 30000\r\nid: 1\r\nevent: ping\r\ndata: \r\n\r\n\
+                    event: state\r\ndata: {\"id\":\"sensor-co2\",\"value\":480,\"state\":\"480 ppm\"}\r\n\r\n\
+                    event: state\r\ndata: {\"id\":\"binary_sensor-ok\",\"value\":true,\"state\":\"ON\"}\r\n\r\n\
+                    event: state\r\ndata: {\"id\":\"sensor-sen55_temperature\",\"value\":21.5,\"state\":\"21.5 °C\"}\r\n\r\n";
+
+        Mock::given(method("GET"))
+            .and(path("/events"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("content-type", "text/event-stream")
+                    .set_body_string(body),
+            )
+            .mount(&mock_server)
+            .await;
+
+        let client = ApolloClient::new(
+            mock_server.uri(),
+            &ClientOptions::new(Duration::from_secs(5)),
+        )
+        .unwrap();
+
+        let mut events = client.stream_events().await.unwrap();
+
+        let (id, value) = events.next_update().await.unwrap().unwrap();
+        assert_eq!(id, "co2");
+        assert_eq!(value.value, 480.0);
+        assert_eq!(value.unit, "ppm");
+
+        let (id, value) = events.next_update().await.unwrap().unwrap();
+        assert_eq!(id, "sen55_temperature");
+        assert_eq!(value.unit, "°C");
+
+        assert!(events.next_update().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_get_device_info() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("GET"))
+            .and(path("/text_sensor/esphome_version"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                r#"{"id": "text_sensor-esphome_version", "value": "2024.6.0 Jun 10 2024, 12:00:00", "state": "2024.6.0 Jun 10 2024, 12:00:00"}"#,
+            ))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        Mock::given(method("GET"))
+            .and(path("/text_sensor/project_name"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                r#"{"id": "text_sensor-project_name", "value": "ApolloAutomation.AIR-1", "state": "ApolloAutomation.AIR-1"}"#,
+            ))
+            .mount(&mock_server)
+            .await;
+
+        Mock::given(method("GET"))
+            .and(path("/text_sensor/ip_address"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                r#"{"id": "text_sensor-ip_address", "value": "192.168.1.100", "state": "192.168.1.100"}"#,
+            ))
+            .mount(&mock_server)
+            .await;
+
+        let client = ApolloClient::new(
+            mock_server.uri(),
+            &ClientOptions::new(Duration::from_secs(5)),
+        )
+        .unwrap();
+
+        let expected = DeviceInfo {
+            esphome_version: "2024.6.0".to_string(),
+            project_name: "ApolloAutomation.AIR-1".to_string(),
+            project_version: String::new(),
+            ip_address: "192.168.1.100".to_string(),
+        };
+        assert_eq!(client.get_device_info().await, Some(expected.clone()));
+
+        // Served from the cache the second time
+        assert_eq!(client.get_device_info().await, Some(expected));
+    }
+
+    #[tokio::test]
+    async fn test_get_device_info_missing() {
+        let mock_server = MockServer::start().await;
+
+        // Each version sensor is asked for once, not on every scrape
+        for sensor in [
+            "esphome_version",
+            "project_name",
+            "project_version",
+            "ip_address",
+        ] {
+            Mock::given(method("GET"))
+                .and(path(format!("/text_sensor/{}", sensor)))
+                .respond_with(ResponseTemplate::new(404))
+                .expect(1)
+                .mount(&mock_server)
+                .await;
+        }
+
+        let client = ApolloClient::new(
+            mock_server.uri(),
+            &ClientOptions::new(Duration::from_secs(5)),
+        )
+        .unwrap();
+
+        assert_eq!(client.get_device_info().await, None);
+        assert_eq!(client.get_device_info().await, None);
+    }
+
+    #[test]
+    fn test_extract_unit() {
+        assert_eq!(extract_unit("450 ppm", 450.0), "ppm");
+        assert_eq!(extract_unit("22.5 °C", 22.5), "°C");
+        assert_eq!(extract_unit("65 %", 65.0), "%");
+        assert_eq!(extract_unit("1013.25 hPa", 1013.25), "hPa");
+        assert_eq!(extract_unit("-62 dBm", -62.0), "dBm");
+        assert_eq!(extract_unit("2.5 µg/m³", 2.5), "µg/m³");
+    }
+
+    #[test]
+    fn test_parse_state_value() {
+        assert_eq!(parse_state_value("23.4 °C"), Some(23.4));
+        assert_eq!(parse_state_value("-62dBm"), Some(-62.0));
+        assert_eq!(parse_state_value("unknown"), None);
+        assert_eq!(parse_state_value(""), None);
+    }
+
+    #[test]
+    fn test_parse_state_value_locales() {
+        assert_eq!(parse_state_value("1.013,25 hPa"), Some(1013.25));
+        assert_eq!(parse_state_value("1,013.25 hPa"), Some(1013.25));
+        assert_eq!(parse_state_value("22,5 °C"), Some(22.5));
+        assert_eq!(parse_state_value("-0,125 ppm"), Some(-0.125));
+        assert_eq!(parse_state_value("101,325 Pa"), Some(101325.0));
+        assert_eq!(parse_state_value("1.013.250 lx"), Some(1013250.0));
+        assert_eq!(parse_state_value("1.013 hPa"), Some(1.013));
     }
 }
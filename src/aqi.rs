@@ -2,10 +2,17 @@
 ///
 /// Based on US EPA standards for PM2.5 and PM10.
 /// PM2.5 breakpoints updated to 2024 EPA revision (effective May 6, 2024).
+/// Also provides the Canadian Air Quality Health Index (AQHI) and the European
+/// Common Air Quality Index (CAQI).
 ///
 /// References:
 /// - EPA AQI Breakpoints: https://aqs.epa.gov/aqsweb/documents/codetables/aqi_breakpoints.html
 /// - Federal Register Final Rule: https://www.federalregister.gov/documents/2024/03/06/2024-02637/
+/// - NowCast: https://usepa.servicenowservices.com/airnow?id=kb_article&sys_id=fed0037b1b62545040a1a7dbe54bcbd4
+/// - AQHI: https://www.canada.ca/en/environment-climate-change/services/air-quality-health-index/about.html
+/// - CAQI: https://www.airqualitynow.eu/about_indices_definition.php
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum AqiCategory {
@@ -29,6 +36,54 @@ impl AqiCategory {
         }
     }
 
+    /// Severity from 0 (Good) to 5 (Hazardous), for numeric dashboards
+    pub fn level(&self) -> u8 {
+        match self {
+            AqiCategory::Good => 0,
+            AqiCategory::Moderate => 1,
+            AqiCategory::UnhealthyForSensitiveGroups => 2,
+            AqiCategory::Unhealthy => 3,
+            AqiCategory::VeryUnhealthy => 4,
+            AqiCategory::Hazardous => 5,
+        }
+    }
+
+    /// EPA standard display color as a hex RGB string
+    pub fn color(&self) -> &'static str {
+        match self {
+            AqiCategory::Good => "#00E400",
+            AqiCategory::Moderate => "#FFFF00",
+            AqiCategory::UnhealthyForSensitiveGroups => "#FF7E00",
+            AqiCategory::Unhealthy => "#FF0000",
+            AqiCategory::VeryUnhealthy => "#8F3F97",
+            AqiCategory::Hazardous => "#7E0023",
+        }
+    }
+
+    /// EPA (AirNow) health statement for the category
+    pub fn health_message(&self) -> &'static str {
+        match self {
+            AqiCategory::Good => {
+                "Air quality is satisfactory, and air pollution poses little or no risk."
+            }
+            AqiCategory::Moderate => {
+                "Air quality is acceptable. However, there may be a risk for some people, particularly those who are unusually sensitive to air pollution."
+            }
+            AqiCategory::UnhealthyForSensitiveGroups => {
+                "Members of sensitive groups may experience health effects. The general public is less likely to be affected."
+            }
+            AqiCategory::Unhealthy => {
+                "Some members of the general public may experience health effects; members of sensitive groups may experience more serious health effects."
+            }
+            AqiCategory::VeryUnhealthy => {
+                "Health alert: The risk of health effects is increased for everyone."
+            }
+            AqiCategory::Hazardous => {
+                "Health warning of emergency conditions: everyone is more likely to be affected."
+            }
+        }
+    }
+
     fn from_aqi(aqi: f64) -> Self {
         match aqi as u16 {
             0..=50 => AqiCategory::Good,
@@ -55,10 +110,16 @@ pub struct AqiResult {
     pub pm10_aqi: Option<f64>,
 }
 
+/// Concentration range (low, high) mapped onto an index range (low, high)
+pub type Breakpoint = (f64, f64, u16, u16);
+
+/// Pollutant name, concentration and the breakpoints it is indexed with
+pub type Pollutant<'a> = (&'a str, f64, &'a [Breakpoint]);
+
 /// PM2.5 breakpoints (24-hour average, µg/m³)
 /// Updated to 2024 EPA revision (effective May 6, 2024)
 /// Source: https://aqs.epa.gov/aqsweb/documents/codetables/aqi_breakpoints.html
-const PM25_BREAKPOINTS: [(f64, f64, u16, u16); 7] = [
+pub const PM25_BREAKPOINTS: [Breakpoint; 7] = [
     (0.0, 9.0, 0, 50),        // Good
     (9.1, 35.4, 51, 100),     // Moderate
     (35.5, 55.4, 101, 150),   // Unhealthy for Sensitive Groups
@@ -68,9 +129,21 @@ const PM25_BREAKPOINTS: [(f64, f64, u16, u16); 7] = [
     (325.5, 999.9, 501, 999), // Beyond AQI scale
 ];
 
+/// PM2.5 breakpoints before the 2024 revision (2012 NAAQS), for continuity
+/// with historical data
+pub const PM25_BREAKPOINTS_2012: [Breakpoint; 7] = [
+    (0.0, 12.0, 0, 50),       // Good
+    (12.1, 35.4, 51, 100),    // Moderate
+    (35.5, 55.4, 101, 150),   // Unhealthy for Sensitive Groups
+    (55.5, 150.4, 151, 200),  // Unhealthy
+    (150.5, 250.4, 201, 300), // Very Unhealthy
+    (250.5, 350.4, 301, 400), // Hazardous
+    (350.5, 500.4, 401, 500), // Hazardous
+];
+
 /// PM10 breakpoints (24-hour average, µg/m³)
 /// Source: https://aqs.epa.gov/aqsweb/documents/codetables/aqi_breakpoints.html
-const PM10_BREAKPOINTS: [(f64, f64, u16, u16); 7] = [
+const PM10_BREAKPOINTS: [Breakpoint; 7] = [
     (0.0, 54.0, 0, 50),       // Good
     (55.0, 154.0, 51, 100),   // Moderate
     (155.0, 254.0, 101, 150), // Unhealthy for Sensitive Groups
@@ -80,6 +153,18 @@ const PM10_BREAKPOINTS: [(f64, f64, u16, u16); 7] = [
     (605.0, 999.0, 501, 999), // Beyond AQI scale
 ];
 
+/// CO2 bands (ppm) for the indoor air quality score, on the AQI scale.
+/// Not an EPA standard: 1000 ppm is a common ventilation target, 5000 ppm the
+/// occupational exposure limit and 40000 ppm immediately dangerous to health.
+const CO2_BREAKPOINTS: [Breakpoint; 6] = [
+    (400.0, 1000.0, 0, 50),        // Good
+    (1001.0, 2000.0, 51, 100),     // Moderate
+    (2001.0, 5000.0, 101, 150),    // Unhealthy for Sensitive Groups
+    (5001.0, 10000.0, 151, 200),   // Unhealthy
+    (10001.0, 40000.0, 201, 300),  // Very Unhealthy
+    (40001.0, 100000.0, 301, 500), // Hazardous
+];
+
 /// Truncate PM2.5 concentration to 1 decimal place per EPA specification
 fn truncate_pm25(value: f64) -> f64 {
     (value * 10.0).floor() / 10.0
@@ -92,10 +177,7 @@ fn truncate_pm10(value: f64) -> f64 {
 
 /// Calculate AQI for a pollutant using EPA formula
 /// AQI = [(IHi - ILo)/(BPHi - BPLo)] × (Cp - BPLo) + ILo
-fn calculate_pollutant_aqi(
-    concentration: f64,
-    breakpoints: &[(f64, f64, u16, u16)],
-) -> Option<f64> {
+fn calculate_pollutant_aqi(concentration: f64, breakpoints: &[Breakpoint]) -> Option<f64> {
     for &(bp_lo, bp_hi, i_lo, i_hi) in breakpoints {
         if concentration >= bp_lo && concentration <= bp_hi {
             let aqi = ((i_hi as f64 - i_lo as f64) / (bp_hi - bp_lo)) * (concentration - bp_lo)
@@ -112,33 +194,169 @@ fn calculate_pollutant_aqi(
     None
 }
 
+/// Number of hourly averages considered by the NowCast
+const NOWCAST_HOURS: u64 = 12;
+
+/// Minimum NowCast weight factor for particulate matter
+const NOWCAST_MIN_WEIGHT: f64 = 0.5;
+
+/// Rolling window of hourly average concentrations for the EPA NowCast
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct NowCastWindow {
+    /// (hour, sum, sample count) per clock hour, oldest first
+    hours: VecDeque<(u64, f64, u32)>,
+}
+
+impl NowCastWindow {
+    /// Adds a concentration sample taken during the given clock hour
+    pub fn add_sample(&mut self, hour: u64, concentration: f64) {
+        match self.hours.back_mut() {
+            Some((last_hour, sum, count)) if *last_hour == hour => {
+                *sum += concentration;
+                *count += 1;
+            }
+            _ => self.hours.push_back((hour, concentration, 1)),
+        }
+
+        while let Some(&(oldest, _, _)) = self.hours.front() {
+            if hour.saturating_sub(oldest) < NOWCAST_HOURS {
+                break;
+            }
+            self.hours.pop_front();
+        }
+    }
+
+    /// Calculate the NowCast concentration as of the given clock hour
+    ///
+    /// Returns `None` unless at least 2 of the 3 most recent hours have data,
+    /// as required by the EPA method.
+    pub fn nowcast(&self, current_hour: u64) -> Option<f64> {
+        // Hourly averages keyed by age in hours (0 = current hour)
+        let averages: Vec<(u64, f64)> = self
+            .hours
+            .iter()
+            .filter_map(|&(hour, sum, count)| {
+                let age = current_hour.checked_sub(hour)?;
+                (age < NOWCAST_HOURS).then(|| (age, sum / count as f64))
+            })
+            .collect();
+
+        if averages.iter().filter(|(age, _)| *age < 3).count() < 2 {
+            return None;
+        }
+
+        let max = averages.iter().map(|(_, c)| *c).fold(f64::MIN, f64::max);
+        let min = averages.iter().map(|(_, c)| *c).fold(f64::MAX, f64::min);
+        let weight = if max > 0.0 {
+            (min / max).max(NOWCAST_MIN_WEIGHT)
+        } else {
+            1.0
+        };
+
+        let (weighted_sum, weight_total) =
+            averages
+                .iter()
+                .fold((0.0, 0.0), |(weighted_sum, weight_total), (age, c)| {
+                    let w = weight.powi(*age as i32);
+                    (weighted_sum + w * c, weight_total + w)
+                });
+
+        Some(weighted_sum / weight_total)
+    }
+}
+
+/// Exponential moving average of a concentration, a lighter way than the
+/// NowCast to damp jumpy readings
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExponentialAverage {
+    value: Option<f64>,
+}
+
+impl ExponentialAverage {
+    /// Folds in a reading weighted by `alpha` (0 < alpha <= 1) and returns
+    /// the new average; the first reading is taken as-is
+    pub fn update(&mut self, alpha: f64, reading: f64) -> f64 {
+        let value = match self.value {
+            Some(previous) => alpha * reading + (1.0 - alpha) * previous,
+            None => reading,
+        };
+        self.value = Some(value);
+        value
+    }
+}
+
+/// Which pollutant's sub-index sets the overall AQI and its category
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PrimaryPolicy {
+    /// The higher sub-index of PM2.5 and PM10
+    #[default]
+    Max,
+    /// PM2.5, or PM10 while PM2.5 is missing
+    Pm25,
+    /// PM10, or PM2.5 while PM10 is missing
+    Pm10,
+}
+
 /// Calculate overall AQI from PM2.5 and PM10 concentrations
 ///
+/// `pm25_breakpoints` selects the PM2.5 table, normally [`PM25_BREAKPOINTS`].
+/// `policy` picks the pollutant whose sub-AQI becomes the overall AQI and
+/// category; a pinned pollutant without a reading falls back to the other.
+///
 /// Concentrations are truncated per EPA specification before calculation:
 /// - PM2.5: truncated to 1 decimal place
 /// - PM10: truncated to integer
-pub fn calculate_aqi(pm25_ugm3: Option<f64>, pm10_ugm3: Option<f64>) -> Option<AqiResult> {
+pub fn calculate_aqi(
+    pm25_ugm3: Option<f64>,
+    pm10_ugm3: Option<f64>,
+    pm25_breakpoints: &[Breakpoint],
+    policy: PrimaryPolicy,
+) -> Option<AqiResult> {
+    let mut pollutants: Vec<Pollutant> = Vec::new();
+    if let Some(pm25) = pm25_ugm3 {
+        pollutants.push(("PM2.5", truncate_pm25(pm25), pm25_breakpoints));
+    }
+    if let Some(pm10) = pm10_ugm3 {
+        pollutants.push(("PM10", truncate_pm10(pm10), &PM10_BREAKPOINTS));
+    }
+
+    let result = calculate_max_aqi(&pollutants)?;
+    let pinned = match policy {
+        PrimaryPolicy::Max => None,
+        PrimaryPolicy::Pm25 => result.pm25_aqi.map(|aqi| ("PM2.5", aqi)),
+        PrimaryPolicy::Pm10 => result.pm10_aqi.map(|aqi| ("PM10", aqi)),
+    };
+
+    Some(match pinned {
+        Some((pollutant, aqi)) => AqiResult {
+            aqi,
+            category: AqiCategory::from_aqi(aqi),
+            primary_pollutant: pollutant.to_string(),
+            ..result
+        },
+        None => result,
+    })
+}
+
+/// Calculate overall AQI as the highest sub-AQI of any pollutant
+///
+/// Concentrations are used as given, so they should already be truncated as
+/// the pollutant's standard requires. On a tie the earlier pollutant is the
+/// primary one.
+pub fn calculate_max_aqi(pollutants: &[Pollutant]) -> Option<AqiResult> {
     let mut max_aqi = 0.0;
     let mut primary_pollutant = String::new();
+    let mut sub_indices = Vec::new();
 
-    // Calculate PM2.5 AQI (truncate to 1 decimal per EPA spec)
-    let pm25_aqi =
-        pm25_ugm3.and_then(|pm25| calculate_pollutant_aqi(truncate_pm25(pm25), &PM25_BREAKPOINTS));
-    if let Some(aqi) = pm25_aqi
-        && aqi > max_aqi
-    {
-        max_aqi = aqi;
-        primary_pollutant = "PM2.5".to_string();
-    }
-
-    // Calculate PM10 AQI (truncate to integer per EPA spec)
-    let pm10_aqi =
-        pm10_ugm3.and_then(|pm10| calculate_pollutant_aqi(truncate_pm10(pm10), &PM10_BREAKPOINTS));
-    if let Some(aqi) = pm10_aqi
-        && aqi > max_aqi
-    {
-        max_aqi = aqi;
-        primary_pollutant = "PM10".to_string();
+    for &(name, concentration, breakpoints) in pollutants {
+        let Some(aqi) = calculate_pollutant_aqi(concentration, breakpoints) else {
+            continue;
+        };
+        if aqi > max_aqi {
+            max_aqi = aqi;
+            primary_pollutant = name.to_string();
+        }
+        sub_indices.push((name.to_string(), aqi));
     }
 
     // Return None if no valid pollutant data
@@ -146,12 +364,190 @@ pub fn calculate_aqi(pm25_ugm3: Option<f64>, pm10_ugm3: Option<f64>) -> Option<A
         return None;
     }
 
+    let sub_index = |pollutant: &str| {
+        sub_indices
+            .iter()
+            .find(|(name, _)| name == pollutant)
+            .map(|&(_, aqi)| aqi)
+    };
     Some(AqiResult {
         aqi: max_aqi,
         category: AqiCategory::from_aqi(max_aqi),
         primary_pollutant,
-        pm25_aqi,
-        pm10_aqi,
+        pm25_aqi: sub_index("PM2.5"),
+        pm10_aqi: sub_index("PM10"),
+    })
+}
+
+/// Calculate an indoor air quality score on the AQI scale
+///
+/// The score is the highest of the CO2 sub-index and the PM sub-indices of
+/// `aqi`, so poor ventilation shows up even when particulates are low.
+/// Readings below the outdoor baseline of 400 ppm count as 400 ppm.
+pub fn calculate_iaq(co2_ppm: Option<f64>, aqi: Option<&AqiResult>) -> Option<f64> {
+    let co2_index =
+        co2_ppm.and_then(|co2| calculate_pollutant_aqi(co2.max(400.0).floor(), &CO2_BREAKPOINTS));
+    let pm_index = aqi.and_then(|result| {
+        result
+            .pm25_aqi
+            .into_iter()
+            .chain(result.pm10_aqi)
+            .reduce(f64::max)
+    });
+
+    match (co2_index, pm_index) {
+        (Some(co2), Some(pm)) => Some(co2.max(pm)),
+        (co2, pm) => co2.or(pm),
+    }
+}
+
+/// Canadian AQHI health risk category
+#[derive(Debug, Clone, PartialEq)]
+pub enum AqhiCategory {
+    Low,
+    Moderate,
+    High,
+    VeryHigh,
+}
+
+impl AqhiCategory {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AqhiCategory::Low => "Low Risk",
+            AqhiCategory::Moderate => "Moderate Risk",
+            AqhiCategory::High => "High Risk",
+            AqhiCategory::VeryHigh => "Very High Risk",
+        }
+    }
+
+    fn from_aqhi(aqhi: f64) -> Self {
+        match aqhi as u16 {
+            0..=3 => AqhiCategory::Low,
+            4..=6 => AqhiCategory::Moderate,
+            7..=10 => AqhiCategory::High,
+            _ => AqhiCategory::VeryHigh,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct AqhiResult {
+    /// AQHI on the 1-10+ scale
+    pub aqhi: f64,
+    pub category: AqhiCategory,
+}
+
+/// Calculate the Canadian AQHI from PM2.5 alone
+///
+/// Uses the PM2.5 term of the official formula
+/// `AQHI = 10/10.4 × 100 × [(e^(0.000871×NO2) − 1) + (e^(0.000537×O3) − 1) + (e^(0.000487×PM2.5) − 1)]`
+/// with the NO2 and O3 terms omitted, as the device doesn't measure them.
+pub fn calculate_aqhi(pm25_ugm3: Option<f64>) -> Option<AqhiResult> {
+    let pm25 = pm25_ugm3?;
+    if pm25 < 0.0 {
+        return None;
+    }
+
+    let aqhi = (10.0 / 10.4 * 100.0 * ((0.000487 * pm25).exp() - 1.0))
+        .round()
+        .max(1.0);
+
+    Some(AqhiResult {
+        aqhi,
+        category: AqhiCategory::from_aqhi(aqhi),
+    })
+}
+
+/// European CAQI category
+#[derive(Debug, Clone, PartialEq)]
+pub enum CaqiCategory {
+    VeryLow,
+    Low,
+    Medium,
+    High,
+    VeryHigh,
+}
+
+impl CaqiCategory {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CaqiCategory::VeryLow => "Very Low",
+            CaqiCategory::Low => "Low",
+            CaqiCategory::Medium => "Medium",
+            CaqiCategory::High => "High",
+            CaqiCategory::VeryHigh => "Very High",
+        }
+    }
+
+    fn from_caqi(caqi: f64) -> Self {
+        match caqi as u16 {
+            0..=25 => CaqiCategory::VeryLow,
+            26..=50 => CaqiCategory::Low,
+            51..=75 => CaqiCategory::Medium,
+            76..=100 => CaqiCategory::High,
+            _ => CaqiCategory::VeryHigh,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct CaqiResult {
+    /// Overall CAQI (max of all pollutants)
+    pub caqi: f64,
+    /// Category based on overall CAQI
+    pub category: CaqiCategory,
+    /// Pollutant with highest CAQI
+    pub primary_pollutant: String,
+    /// Individual PM2.5 sub-index (if available)
+    pub pm25_caqi: Option<f64>,
+    /// Individual PM10 sub-index (if available)
+    pub pm10_caqi: Option<f64>,
+}
+
+/// Hourly CAQI grid for PM2.5: upper bound (µg/m³) of each 25-point band
+const CAQI_PM25_GRID: [f64; 4] = [15.0, 30.0, 55.0, 110.0];
+
+/// Hourly CAQI grid for PM10: upper bound (µg/m³) of each 25-point band
+const CAQI_PM10_GRID: [f64; 4] = [25.0, 50.0, 90.0, 180.0];
+
+/// Interpolate a CAQI sub-index within the grid's bands. Above the grid
+/// (Very High) the slope of the last band is continued.
+fn calculate_pollutant_caqi(concentration: f64, grid: &[f64; 4]) -> Option<f64> {
+    if concentration < 0.0 {
+        return None;
+    }
+
+    let mut lower = 0.0;
+    for (band, &upper) in grid.iter().enumerate() {
+        if concentration <= upper {
+            let index = band as f64 * 25.0 + (concentration - lower) / (upper - lower) * 25.0;
+            return Some(index.round());
+        }
+        lower = upper;
+    }
+
+    let (lo, hi) = (grid[2], grid[3]);
+    Some((100.0 + (concentration - hi) / (hi - lo) * 25.0).round())
+}
+
+/// Calculate the European CAQI (hourly grid) from PM2.5 and PM10 concentrations
+pub fn calculate_caqi(pm25_ugm3: Option<f64>, pm10_ugm3: Option<f64>) -> Option<CaqiResult> {
+    let pm25_caqi = pm25_ugm3.and_then(|pm25| calculate_pollutant_caqi(pm25, &CAQI_PM25_GRID));
+    let pm10_caqi = pm10_ugm3.and_then(|pm10| calculate_pollutant_caqi(pm10, &CAQI_PM10_GRID));
+
+    let (caqi, primary_pollutant) = match (pm25_caqi, pm10_caqi) {
+        (Some(pm25), Some(pm10)) if pm10 > pm25 => (pm10, "PM10"),
+        (Some(pm25), _) => (pm25, "PM2.5"),
+        (None, Some(pm10)) => (pm10, "PM10"),
+        (None, None) => return None,
+    };
+
+    Some(CaqiResult {
+        caqi,
+        category: CaqiCategory::from_caqi(caqi),
+        primary_pollutant: primary_pollutant.to_string(),
+        pm25_caqi,
+        pm10_caqi,
     })
 }
 
@@ -203,7 +599,13 @@ mod tests {
     #[test]
     fn test_overall_aqi_calculation() {
         // PM2.5 higher than PM10 (2024 breakpoints)
-        let result = calculate_aqi(Some(20.0), Some(30.0)).unwrap();
+        let result = calculate_aqi(
+            Some(20.0),
+            Some(30.0),
+            &PM25_BREAKPOINTS,
+            PrimaryPolicy::Max,
+        )
+        .unwrap();
         assert_eq!(result.aqi, 71.0);
         assert_eq!(result.category, AqiCategory::Moderate);
         assert_eq!(result.primary_pollutant, "PM2.5");
@@ -211,7 +613,13 @@ mod tests {
         assert_eq!(result.pm10_aqi, Some(28.0));
 
         // PM10 higher than PM2.5
-        let result = calculate_aqi(Some(5.0), Some(100.0)).unwrap();
+        let result = calculate_aqi(
+            Some(5.0),
+            Some(100.0),
+            &PM25_BREAKPOINTS,
+            PrimaryPolicy::Max,
+        )
+        .unwrap();
         assert_eq!(result.aqi, 73.0);
         assert_eq!(result.category, AqiCategory::Moderate);
         assert_eq!(result.primary_pollutant, "PM10");
@@ -219,14 +627,274 @@ mod tests {
         assert_eq!(result.pm10_aqi, Some(73.0));
 
         // Only PM2.5 available
-        let result = calculate_aqi(Some(15.0), None).unwrap();
+        let result =
+            calculate_aqi(Some(15.0), None, &PM25_BREAKPOINTS, PrimaryPolicy::Max).unwrap();
         assert_eq!(result.aqi, 62.0);
         assert_eq!(result.primary_pollutant, "PM2.5");
         assert_eq!(result.pm25_aqi, Some(62.0));
         assert_eq!(result.pm10_aqi, None);
 
         // No data available
-        assert!(calculate_aqi(None, None).is_none());
+        assert!(calculate_aqi(None, None, &PM25_BREAKPOINTS, PrimaryPolicy::Max).is_none());
+    }
+
+    #[test]
+    fn test_aqi_primary_policy() {
+        // PM10 has the higher sub-AQI, but PM2.5 is pinned
+        let result = calculate_aqi(
+            Some(5.0),
+            Some(100.0),
+            &PM25_BREAKPOINTS,
+            PrimaryPolicy::Pm25,
+        )
+        .unwrap();
+        assert_eq!(result.aqi, 28.0);
+        assert_eq!(result.category, AqiCategory::Good);
+        assert_eq!(result.primary_pollutant, "PM2.5");
+        assert_eq!(result.pm10_aqi, Some(73.0));
+
+        let result = calculate_aqi(
+            Some(20.0),
+            Some(30.0),
+            &PM25_BREAKPOINTS,
+            PrimaryPolicy::Pm10,
+        )
+        .unwrap();
+        assert_eq!(result.aqi, 28.0);
+        assert_eq!(result.primary_pollutant, "PM10");
+
+        // Without a reading of the pinned pollutant, the other one is used
+        let result =
+            calculate_aqi(Some(15.0), None, &PM25_BREAKPOINTS, PrimaryPolicy::Pm10).unwrap();
+        assert_eq!(result.aqi, 62.0);
+        assert_eq!(result.primary_pollutant, "PM2.5");
+
+        // The IAQ score still considers both pollutants
+        let result = calculate_aqi(
+            Some(5.0),
+            Some(100.0),
+            &PM25_BREAKPOINTS,
+            PrimaryPolicy::Pm25,
+        )
+        .unwrap();
+        assert_eq!(calculate_iaq(None, Some(&result)), Some(73.0));
+    }
+
+    /// EPA 8-hour ozone breakpoints (ppm)
+    const O3_8H_BREAKPOINTS: [Breakpoint; 5] = [
+        (0.0, 0.054, 0, 50),
+        (0.055, 0.070, 51, 100),
+        (0.071, 0.085, 101, 150),
+        (0.086, 0.105, 151, 200),
+        (0.106, 0.200, 201, 300),
+    ];
+
+    #[test]
+    fn test_max_aqi_with_ozone() {
+        // Ozone dominates moderate particulates
+        let result = calculate_max_aqi(&[
+            ("PM2.5", 20.0, &PM25_BREAKPOINTS),
+            ("PM10", 30.0, &PM10_BREAKPOINTS),
+            ("O3", 0.080, &O3_8H_BREAKPOINTS),
+        ])
+        .unwrap();
+        assert_eq!(result.aqi, 133.0);
+        assert_eq!(result.category, AqiCategory::UnhealthyForSensitiveGroups);
+        assert_eq!(result.primary_pollutant, "O3");
+        assert_eq!(result.pm25_aqi, Some(71.0));
+        assert_eq!(result.pm10_aqi, Some(28.0));
+
+        // Ozone alone
+        let result = calculate_max_aqi(&[("O3", 0.060, &O3_8H_BREAKPOINTS)]).unwrap();
+        assert_eq!(result.primary_pollutant, "O3");
+        assert_eq!(result.aqi, 67.0);
+        assert_eq!(result.pm25_aqi, None);
+
+        // Particulates win again once ozone is low
+        let result = calculate_max_aqi(&[
+            ("PM2.5", 20.0, &PM25_BREAKPOINTS),
+            ("O3", 0.030, &O3_8H_BREAKPOINTS),
+        ])
+        .unwrap();
+        assert_eq!(result.primary_pollutant, "PM2.5");
+
+        assert!(calculate_max_aqi(&[]).is_none());
+    }
+
+    #[test]
+    fn test_category_level() {
+        let levels: Vec<u8> = [0.0, 51.0, 101.0, 151.0, 201.0, 301.0]
+            .iter()
+            .map(|&aqi| AqiCategory::from_aqi(aqi).level())
+            .collect();
+        assert_eq!(levels, vec![0, 1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_category_color() {
+        let colors: Vec<&str> = [0.0, 51.0, 101.0, 151.0, 201.0, 301.0]
+            .iter()
+            .map(|&aqi| AqiCategory::from_aqi(aqi).color())
+            .collect();
+        assert_eq!(
+            colors,
+            vec![
+                "#00E400", "#FFFF00", "#FF7E00", "#FF0000", "#8F3F97", "#7E0023"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pm25_breakpoint_tables() {
+        // 12.0 µg/m³ was the top of "Good" before the 2024 revision
+        let current =
+            calculate_aqi(Some(12.0), None, &PM25_BREAKPOINTS, PrimaryPolicy::Max).unwrap();
+        let legacy =
+            calculate_aqi(Some(12.0), None, &PM25_BREAKPOINTS_2012, PrimaryPolicy::Max).unwrap();
+        assert_eq!(current.aqi, 56.0);
+        assert_eq!(current.category, AqiCategory::Moderate);
+        assert_eq!(legacy.aqi, 50.0);
+        assert_eq!(legacy.category, AqiCategory::Good);
+
+        let current =
+            calculate_aqi(Some(100.0), None, &PM25_BREAKPOINTS, PrimaryPolicy::Max).unwrap();
+        let legacy = calculate_aqi(
+            Some(100.0),
+            None,
+            &PM25_BREAKPOINTS_2012,
+            PrimaryPolicy::Max,
+        )
+        .unwrap();
+        assert_eq!(current.aqi, 182.0);
+        assert_eq!(legacy.aqi, 174.0);
+
+        // Both tables agree in the overlapping Moderate band
+        assert_eq!(
+            calculate_pollutant_aqi(35.4, &PM25_BREAKPOINTS_2012),
+            Some(100.0)
+        );
+    }
+
+    #[test]
+    fn test_iaq_score() {
+        // CO2 alone
+        assert_eq!(calculate_iaq(Some(400.0), None), Some(0.0));
+        assert_eq!(calculate_iaq(Some(350.0), None), Some(0.0));
+        assert_eq!(calculate_iaq(Some(1000.0), None), Some(50.0));
+        assert_eq!(calculate_iaq(Some(1500.0), None), Some(75.0));
+
+        // The worse of CO2 and PM wins
+        let pm = calculate_aqi(Some(20.0), None, &PM25_BREAKPOINTS, PrimaryPolicy::Max).unwrap();
+        assert_eq!(calculate_iaq(Some(600.0), Some(&pm)), Some(71.0));
+        assert_eq!(calculate_iaq(Some(2500.0), Some(&pm)), Some(109.0));
+        assert_eq!(calculate_iaq(None, Some(&pm)), Some(71.0));
+
+        assert_eq!(calculate_iaq(None, None), None);
+    }
+
+    #[test]
+    fn test_aqhi() {
+        let result = calculate_aqhi(Some(10.0)).unwrap();
+        assert_eq!(result.aqhi, 1.0);
+        assert_eq!(result.category, AqhiCategory::Low);
+
+        let result = calculate_aqhi(Some(100.0)).unwrap();
+        assert_eq!(result.aqhi, 5.0);
+        assert_eq!(result.category, AqhiCategory::Moderate);
+
+        let result = calculate_aqhi(Some(200.0)).unwrap();
+        assert_eq!(result.aqhi, 10.0);
+        assert_eq!(result.category, AqhiCategory::High);
+
+        let result = calculate_aqhi(Some(250.0)).unwrap();
+        assert_eq!(result.aqhi, 12.0);
+        assert_eq!(result.category, AqhiCategory::VeryHigh);
+
+        assert!(calculate_aqhi(None).is_none());
+    }
+
+    #[test]
+    fn test_caqi() {
+        // Band edges
+        assert_eq!(calculate_pollutant_caqi(15.0, &CAQI_PM25_GRID), Some(25.0));
+        assert_eq!(calculate_pollutant_caqi(55.0, &CAQI_PM25_GRID), Some(75.0));
+        assert_eq!(
+            calculate_pollutant_caqi(180.0, &CAQI_PM10_GRID),
+            Some(100.0)
+        );
+
+        // Within a band: PM2.5 20 µg/m³ → 25 + 5/15 × 25
+        let result = calculate_caqi(Some(20.0), Some(30.0)).unwrap();
+        assert_eq!(result.pm25_caqi, Some(33.0));
+        assert_eq!(result.pm10_caqi, Some(30.0));
+        assert_eq!(result.caqi, 33.0);
+        assert_eq!(result.category, CaqiCategory::Low);
+        assert_eq!(result.primary_pollutant, "PM2.5");
+
+        // PM10 dominates
+        let result = calculate_caqi(Some(10.0), Some(120.0)).unwrap();
+        assert_eq!(result.caqi, 83.0);
+        assert_eq!(result.category, CaqiCategory::High);
+        assert_eq!(result.primary_pollutant, "PM10");
+
+        // Above the grid the index keeps rising
+        let result = calculate_caqi(Some(165.0), None).unwrap();
+        assert_eq!(result.caqi, 125.0);
+        assert_eq!(result.category, CaqiCategory::VeryHigh);
+
+        assert!(calculate_caqi(None, None).is_none());
+    }
+
+    #[test]
+    fn test_exponential_average() {
+        let mut average = ExponentialAverage::default();
+        assert_eq!(average.update(0.5, 10.0), 10.0);
+        assert_eq!(average.update(0.5, 30.0), 20.0);
+        assert_eq!(average.update(0.5, 20.0), 20.0);
+
+        // An alpha of 1 follows the readings exactly
+        let mut average = ExponentialAverage::default();
+        average.update(1.0, 10.0);
+        assert_eq!(average.update(1.0, 55.0), 55.0);
+    }
+
+    #[test]
+    fn test_nowcast() {
+        let mut window = NowCastWindow::default();
+
+        // A single hour isn't enough for a NowCast
+        window.add_sample(100, 10.0);
+        window.add_sample(100, 10.0);
+        assert_eq!(window.nowcast(100), None);
+
+        // Two hours: weight = max(10/20, 0.5) = 0.5 → (20 + 0.5 × 10) / 1.5
+        window.add_sample(101, 15.0);
+        window.add_sample(101, 25.0);
+        let nowcast = window.nowcast(101).unwrap();
+        assert!((nowcast - 16.667).abs() < 0.001);
+
+        // Steady concentrations give the same value back
+        let mut window = NowCastWindow::default();
+        for hour in 0..12 {
+            window.add_sample(hour, 8.0);
+        }
+        assert_eq!(window.nowcast(11), Some(8.0));
+    }
+
+    #[test]
+    fn test_nowcast_requires_recent_hours() {
+        let mut window = NowCastWindow::default();
+        window.add_sample(100, 10.0);
+        window.add_sample(102, 20.0);
+
+        // Hours 0 and 2 of the 3 most recent are present
+        assert!(window.nowcast(102).is_some());
+        // Only hour 1 and 3 of the 3 most recent are present
+        assert!(window.nowcast(103).is_none());
+
+        // Samples older than 12 hours are dropped
+        window.add_sample(114, 5.0);
+        assert_eq!(window.hours.len(), 1);
     }
 
     #[test]
@@ -0,0 +1,85 @@
+//! Derived comfort metrics computed from raw sensor readings
+//!
+//! References:
+//! - Magnus formula: https://en.wikipedia.org/wiki/Dew_point#Calculating_the_dew_point
+//! - Absolute humidity: https://carnotcycle.wordpress.com/2012/08/04/how-to-convert-relative-humidity-to-absolute-humidity/
+//! - Sea-level pressure: https://en.wikipedia.org/wiki/Barometric_formula
+
+/// Magnus coefficients (Sonntag 1990), valid for -45°C to 60°C
+const MAGNUS_A: f64 = 17.62;
+const MAGNUS_B: f64 = 243.12;
+
+/// Calculate dew point in °C from temperature (°C) and relative humidity (%)
+///
+/// Returns `None` for a non-positive humidity, where the formula is undefined.
+pub fn dew_point(temperature_c: f64, humidity_percent: f64) -> Option<f64> {
+    if humidity_percent <= 0.0 {
+        return None;
+    }
+
+    let gamma =
+        (humidity_percent / 100.0).ln() + (MAGNUS_A * temperature_c) / (MAGNUS_B + temperature_c);
+    Some(MAGNUS_B * gamma / (MAGNUS_A - gamma))
+}
+
+/// Calculate absolute humidity in g/m³ from temperature (°C) and relative humidity (%)
+///
+/// AH = 6.112 × e^((17.67 × T) / (T + 243.5)) × RH × 2.1674 / (273.15 + T)
+pub fn absolute_humidity(temperature_c: f64, humidity_percent: f64) -> f64 {
+    6.112 * ((17.67 * temperature_c) / (temperature_c + 243.5)).exp() * humidity_percent * 2.1674
+        / (273.15 + temperature_c)
+}
+
+/// Temperature lapse rate of the standard atmosphere in K/m
+const LAPSE_RATE: f64 = 0.0065;
+
+/// Standard atmosphere temperature at sea level in °C
+const SEA_LEVEL_TEMPERATURE: f64 = 15.0;
+
+/// Reduce station pressure (hPa) measured at `altitude_m` to sea level
+///
+/// P0 = P × (1 - 0.0065 × h / (T + 0.0065 × h + 273.15))^-5.257
+///
+/// Without a temperature reading, the standard atmosphere temperature at
+/// that altitude is assumed.
+pub fn sea_level_pressure(pressure_hpa: f64, temperature_c: Option<f64>, altitude_m: f64) -> f64 {
+    let temperature_c = temperature_c.unwrap_or(SEA_LEVEL_TEMPERATURE - LAPSE_RATE * altitude_m);
+    let lapse = LAPSE_RATE * altitude_m;
+    pressure_hpa * (1.0 - lapse / (temperature_c + lapse + 273.15)).powf(-5.257)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round1(value: f64) -> f64 {
+        (value * 10.0).round() / 10.0
+    }
+
+    #[test]
+    fn test_dew_point() {
+        assert_eq!(dew_point(20.0, 50.0).map(round1), Some(9.3));
+        assert_eq!(dew_point(25.0, 80.0).map(round1), Some(21.3));
+        assert_eq!(dew_point(0.0, 100.0).map(round1), Some(0.0));
+    }
+
+    #[test]
+    fn test_absolute_humidity() {
+        assert_eq!(round1(absolute_humidity(20.0, 50.0)), 8.6);
+        assert_eq!(round1(absolute_humidity(25.0, 80.0)), 18.4);
+        assert_eq!(absolute_humidity(20.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn test_sea_level_pressure() {
+        assert_eq!(round1(sea_level_pressure(950.0, Some(20.0), 500.0)), 1006.7);
+        assert_eq!(round1(sea_level_pressure(954.6, None, 500.0)), 1013.3);
+        assert_eq!(sea_level_pressure(1013.25, Some(20.0), 0.0), 1013.25);
+    }
+
+    #[test]
+    fn test_dew_point_invalid_humidity() {
+        assert_eq!(dew_point(20.0, 0.0), None);
+        assert_eq!(dew_point(20.0, -5.0), None);
+    }
+}
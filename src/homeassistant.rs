@@ -0,0 +1,130 @@
+//! Reshapes the latest device readings into Home Assistant state objects, for
+//! feeding Home Assistant REST sensors from the same exporter.
+//!
+//! Each sensor becomes an entry like the ones Home Assistant's own
+//! `/api/states` returns, keyed by entity id, e.g. `sensor.office_co2`:
+//! `{"state": "450", "attributes": {"unit_of_measurement": "ppm", "friendly_name": "Office CO2"}}`
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::apollo::ApolloStatus;
+
+/// State Home Assistant uses for entities without a current reading
+const UNAVAILABLE: &str = "unavailable";
+
+#[derive(Debug, Serialize)]
+pub struct HaState {
+    pub state: String,
+    pub attributes: HaAttributes,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HaAttributes {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unit_of_measurement: Option<String>,
+    pub friendly_name: String,
+}
+
+/// Converts the latest status of each device into state objects keyed by
+/// entity id
+pub fn states<'a>(
+    snapshots: impl IntoIterator<Item = &'a ApolloStatus>,
+) -> BTreeMap<String, HaState> {
+    let mut states = BTreeMap::new();
+
+    for status in snapshots {
+        let device = slugify(&status.device_name);
+        for (sensor_id, sensor) in &status.sensors {
+            let state = if sensor.value.is_finite() {
+                sensor.value.to_string()
+            } else {
+                UNAVAILABLE.to_string()
+            };
+
+            states.insert(
+                format!("sensor.{}_{}", device, slugify(sensor_id)),
+                HaState {
+                    state,
+                    attributes: HaAttributes {
+                        unit_of_measurement: (!sensor.unit.is_empty()).then(|| sensor.unit.clone()),
+                        friendly_name: format!("{} {}", status.device_name, sensor.name),
+                    },
+                },
+            );
+        }
+    }
+
+    states
+}
+
+/// Lowercases a name and joins its alphanumeric runs with underscores, the
+/// way Home Assistant derives object ids
+fn slugify(name: &str) -> String {
+    name.split(|c: char| !c.is_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join("_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apollo::SensorValue;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_states() {
+        let mut sensors = HashMap::new();
+        sensors.insert(
+            "co2".to_string(),
+            SensorValue {
+                value: 450.0,
+                unit: "ppm".to_string(),
+                name: "CO2".to_string(),
+            },
+        );
+        sensors.insert(
+            "sen55_voc".to_string(),
+            SensorValue {
+                value: f64::NAN,
+                unit: String::new(),
+                name: "SEN55 VOC".to_string(),
+            },
+        );
+        let snapshots = HashMap::from([(
+            "http://192.168.1.100".to_string(),
+            ApolloStatus {
+                sensors,
+                device_name: "Living Room".to_string(),
+                info: None,
+            },
+        )]);
+
+        let states = states(snapshots.values());
+        assert_eq!(states.len(), 2);
+
+        let co2 = &states["sensor.living_room_co2"];
+        assert_eq!(co2.state, "450");
+        assert_eq!(co2.attributes.unit_of_measurement.as_deref(), Some("ppm"));
+        assert_eq!(co2.attributes.friendly_name, "Living Room CO2");
+
+        let voc = &states["sensor.living_room_sen55_voc"];
+        assert_eq!(voc.state, "unavailable");
+        assert_eq!(voc.attributes.unit_of_measurement, None);
+    }
+
+    #[test]
+    fn test_slugify() {
+        assert_eq!(slugify("Living Room"), "living_room");
+        assert_eq!(
+            slugify("apollo-air-1 (2nd floor)"),
+            "apollo_air_1_2nd_floor"
+        );
+        assert_eq!(
+            slugify("pm__2_5_m_weight_concentration"),
+            "pm_2_5_m_weight_concentration"
+        );
+    }
+}
@@ -1,152 +1,1616 @@
 mod apollo;
 mod aqi;
 mod config;
+mod derived;
+mod homeassistant;
+mod json_api;
 mod metrics;
+mod mqtt;
+mod native_api;
+mod openmetrics;
+mod push;
+mod state;
+mod status_page;
 
 use anyhow::Result;
+use axum::extract::{FromRef, Query, Request, State};
+use axum::http::{HeaderMap, StatusCode, header};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Json, Response};
 use axum::{Router, routing::get};
-use clap::Parser;
-use std::collections::HashMap;
+use axum_server::tls_rustls::RustlsConfig;
+use prometheus::{Encoder, TextEncoder};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::io::IsTerminal;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::sync::{Mutex, RwLock};
-use tokio::time::interval;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
+use tokio::signal::unix::{Signal, SignalKind, signal};
+use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
+use tokio::time::{interval, interval_at};
+use tower_http::compression::CompressionLayer;
 use tracing::{debug, error, info, warn};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-use crate::apollo::ApolloClient;
-use crate::config::Config;
-use crate::metrics::Metrics;
+use crate::apollo::{ApolloClient, ApolloStatus, ClientOptions};
+use crate::config::{Config, Device, Mode, Protocol, extract_device_name};
+use crate::metrics::{Metrics, MetricsOptions};
+use crate::mqtt::MqttBroker;
+use crate::native_api::NativeApiClient;
+use crate::openmetrics::{OPENMETRICS_FORMAT, OpenMetricsEncoder};
+use crate::push::Pushgateway;
 
-type SharedMetrics = Arc<RwLock<String>>;
-type DeviceClients = Arc<Mutex<HashMap<String, (ApolloClient, String)>>>;
+type SharedMetrics = Arc<RwLock<RenderedMetrics>>;
+
+/// Set once a successful scrape has been rendered into the shared metrics
+type Readiness = Arc<AtomicBool>;
+
+/// Latest status fetched from each device, keyed by host
+type DeviceSnapshots = Arc<RwLock<HashMap<String, Snapshot>>>;
+
+/// A device's latest status and when it was received
+#[derive(Debug, Clone)]
+struct Snapshot {
+    status: ApolloStatus,
+    updated_at: SystemTime,
+}
+
+impl Snapshot {
+    fn new(status: ApolloStatus) -> Self {
+        Self {
+            status,
+            updated_at: SystemTime::now(),
+        }
+    }
+}
+
+/// Parsed configuration with secrets redacted, updated on reload
+type EffectiveConfig = Arc<RwLock<serde_json::Value>>;
+
+/// Latest metrics, pre-rendered in each supported exposition format
+#[derive(Debug, Default)]
+struct RenderedMetrics {
+    text: String,
+    openmetrics: String,
+}
+
+/// Settings used to build a client and metrics for an on-demand probe
+#[derive(Debug)]
+struct ProbeSettings {
+    client_options: ClientOptions,
+    metrics_options: MetricsOptions,
+}
+
+#[derive(Clone)]
+struct AppState {
+    metrics: SharedMetrics,
+    /// Live device metrics, for the status on /
+    device_metrics: Arc<Metrics>,
+    ready: Readiness,
+    snapshots: DeviceSnapshots,
+    config: EffectiveConfig,
+    probe: Arc<ProbeSettings>,
+    metrics_token: Option<Arc<str>>,
+    metrics_path: Arc<str>,
+    /// Serve the latest readings as Home Assistant states on /homeassistant
+    enable_ha_json: bool,
+    /// Serve the latest readings as a flat JSON array on /json
+    enable_json_api: bool,
+}
+
+impl FromRef<AppState> for SharedMetrics {
+    fn from_ref(state: &AppState) -> Self {
+        state.metrics.clone()
+    }
+}
+
+impl FromRef<AppState> for Readiness {
+    fn from_ref(state: &AppState) -> Self {
+        state.ready.clone()
+    }
+}
+
+impl FromRef<AppState> for DeviceSnapshots {
+    fn from_ref(state: &AppState) -> Self {
+        state.snapshots.clone()
+    }
+}
+
+impl FromRef<AppState> for EffectiveConfig {
+    fn from_ref(state: &AppState) -> Self {
+        state.config.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<ProbeSettings> {
+    fn from_ref(state: &AppState) -> Self {
+        state.probe.clone()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ProbeParams {
+    target: Option<String>,
+}
+
+/// Result of a `/test` connection check
+#[derive(Debug, Serialize)]
+struct ConnectionTest {
+    reachable: bool,
+    sensors_found: usize,
+}
+
+/// How often the AQI state is saved to the state file
+const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often devices that didn't respond when added are retried
+const DEVICE_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often devices are checked for missing updates
+const STALE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Bounds for the event stream and MQTT reconnect backoff
+const RECONNECT_MIN_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// How long a connection has to last before the reconnect backoff starts
+/// over, so a device that drops right after accepting doesn't reset it
+const RECONNECT_STABLE_AFTER: Duration = Duration::from_secs(30);
 
 #[tokio::main]
 async fn main() -> Result<()> {
     // Parse configuration
-    let config = Config::parse();
+    let config = Config::load()?;
 
-    // Initialize logging
+    // Initialize logging; a dry run logs to stderr, keeping stdout for the metrics
+    let (log_writer, is_terminal) = if config.dry_run {
+        (
+            BoxMakeWriter::new(std::io::stderr),
+            std::io::stderr().is_terminal(),
+        )
+    } else {
+        (
+            BoxMakeWriter::new(std::io::stdout),
+            std::io::stdout().is_terminal(),
+        )
+    };
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::EnvFilter::try_from_default_env()
                 .unwrap_or_else(|_| config.log_level.clone().into()),
         )
-        .with(tracing_subscriber::fmt::layer())
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_writer(log_writer)
+                .with_ansi(config.log_color.use_ansi(is_terminal)),
+        )
         .init();
 
     info!("Starting Apollo Air-1 Prometheus Exporter");
-    info!("Monitoring {} devices", config.hosts.len());
+    warn_on_names_mismatch(&config);
+    for (name, hosts) in config.duplicate_device_names() {
+        warn!(
+            "Devices {} share the name {:?}; give them distinct names so their metrics can be told apart",
+            hosts.join(", "),
+            name
+        );
+    }
+    let devices = config.get_devices();
+    info!("Monitoring {} devices", devices.len());
     info!("Metrics port: {}", config.port);
-    info!("Poll interval: {}s", config.poll_interval);
+    match config.mode {
+        Mode::Poll => info!("Default poll interval: {}s", config.poll_interval),
+        Mode::Sse => info!("Using event streams for live updates"),
+        Mode::Mqtt => info!("Using MQTT state topics for live updates"),
+    }
+    if config.protocol == Protocol::Native && config.mode == Mode::Sse {
+        warn!(
+            "Devices using the native API are polled; --mode sse only applies to --protocol http"
+        );
+    }
+    if config.protocol == Protocol::Native && config.mode == Mode::Mqtt {
+        warn!("--protocol native is ignored with --mode mqtt, which reads from the broker");
+    }
 
     // Initialize metrics
-    let metrics = Arc::new(Metrics::new()?);
-    let shared_metrics: SharedMetrics = Arc::new(RwLock::new(String::new()));
+    let metrics = Arc::new(Metrics::new(config.metrics_options())?);
+    metrics.set_poll_interval(config.poll_interval_duration());
+
+    if config.dry_run {
+        let client_options = config.client_options()?;
+        let output = scrape_once(
+            &devices,
+            config.protocol,
+            config.api_password.clone(),
+            &client_options,
+            &metrics,
+        )
+        .await?;
+        print!("{}", output);
+        return Ok(());
+    }
+    let shared_metrics: SharedMetrics = Arc::new(RwLock::new(RenderedMetrics::default()));
+    let ready = Readiness::default();
+    let snapshots = DeviceSnapshots::default();
+
+    // Load the server certificate up front so a bad one fails at startup
+    let tls_config = config.tls_config().await?;
+
+    let client_options = config.client_options()?;
+    let pushgateway = config.pushgateway()?;
+    let mqtt_broker = config.mqtt_broker()?;
+    if let (Mode::Mqtt, Some(broker)) = (config.mode, &mqtt_broker) {
+        info!("Subscribing to MQTT broker at {}", broker.address());
+    }
+    if let Some(pushgateway) = &pushgateway {
+        info!(
+            "Pushing metrics to Pushgateway at {}",
+            pushgateway.address()
+        );
+    }
+    if client_options.insecure_skip_verify {
+        warn!(
+            "TLS certificate verification is DISABLED for device connections (--insecure-skip-verify)"
+        );
+    }
 
-    // Initialize device clients
-    let device_clients: DeviceClients = Arc::new(Mutex::new(HashMap::new()));
+    if let Some(path) = &config.state_file {
+        restore_state(&metrics, path, &devices);
+        tokio::spawn(save_state_periodically(metrics.clone(), path.clone()));
+    }
+
+    if config.stale_after > 0 {
+        tokio::spawn(watch_stale_devices(metrics.clone(), shared_metrics.clone()));
+    }
+
+    // Render once so /metrics serves a valid payload before any device is scraped
+    refresh_shared_metrics(&metrics, &shared_metrics).await;
+
+    // Start one task per device so each runs independently and a slow
+    // device never delays the others
+    let state_metrics = metrics.clone();
+    let mut device_tasks = DeviceTasks {
+        mode: config.mode,
+        protocol: config.protocol,
+        api_password: config.api_password.clone(),
+        mqtt_broker,
+        samples_per_interval: config.samples_per_interval,
+        poll_jitter: Duration::from_secs(config.poll_jitter),
+        client_options: client_options.clone(),
+        sinks: Sinks {
+            metrics,
+            shared_metrics: shared_metrics.clone(),
+            ready: ready.clone(),
+            snapshots: snapshots.clone(),
+            pushgateway,
+        },
+        running: HashMap::new(),
+        pending: HashMap::new(),
+    };
+
+    // Register the handlers before serving so an early signal can't kill the process
+    let hangup = signal(SignalKind::hangup())?;
+    let terminate = signal(SignalKind::terminate())?;
+    let effective_config = EffectiveConfig::new(RwLock::new(config.effective_config()));
 
-    // Setup initial devices
-    for (host, name) in config.get_device_names() {
-        let client = ApolloClient::new(host.clone(), config.http_timeout_duration())?;
+    // Devices are set up while the server already answers, so /health doesn't
+    // wait for slow or offline devices
+    let reload_config = effective_config.clone();
+    let setup = async move {
+        device_tasks.reconcile(devices).await?;
+        tokio::spawn(manage_devices(device_tasks, reload_config, hangup));
+        anyhow::Ok(())
+    };
+
+    // Initialize HTTP server
+    let app = router(AppState {
+        metrics: shared_metrics,
+        device_metrics: state_metrics.clone(),
+        ready,
+        snapshots,
+        config: effective_config,
+        probe: Arc::new(ProbeSettings {
+            client_options,
+            // Probes return only the device's series
+            metrics_options: MetricsOptions {
+                process_metrics: false,
+                ..config.metrics_options()
+            },
+        }),
+        metrics_token: config.metrics_token.as_deref().map(Arc::from),
+        metrics_path: Arc::from(config.metrics_path.as_str()),
+        enable_ha_json: config.enable_ha_json,
+        enable_json_api: config.enable_json_api,
+    });
+
+    let server = serve(app, config.metrics_bind_address(), tls_config, terminate);
+    tokio::pin!(server);
+    tokio::select! {
+        result = &mut server => result?,
+        result = setup => {
+            result?;
+            server.await?;
+        }
+    }
+
+    if let Some(path) = &config.state_file {
+        save_state(&state_metrics, path);
+    }
+    info!("Shut down");
+
+    Ok(())
+}
+
+/// Serves the metrics endpoints until SIGINT or SIGTERM
+async fn serve(
+    app: Router,
+    addr: SocketAddr,
+    tls_config: Option<RustlsConfig>,
+    terminate: Signal,
+) -> Result<()> {
+    match tls_config {
+        Some(tls_config) => {
+            info!("Starting metrics server on {} (HTTPS)", &addr);
+
+            let listener = std::net::TcpListener::bind(addr)?;
+            listener.set_nonblocking(true)?;
+            let handle = axum_server::Handle::new();
+            let shutdown_handle = handle.clone();
+            tokio::spawn(async move {
+                shutdown_signal(terminate).await;
+                shutdown_handle.graceful_shutdown(None);
+            });
+            axum_server::from_tcp_rustls(listener, tls_config)
+                .handle(handle)
+                .serve(app.into_make_service())
+                .await?;
+        }
+        None => {
+            info!("Starting metrics server on {}", &addr);
+
+            let listener = tokio::net::TcpListener::bind(addr).await?;
+            axum::serve(listener, app)
+                .with_graceful_shutdown(shutdown_signal(terminate))
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Resolves once SIGINT or SIGTERM is received
+async fn shutdown_signal(mut terminate: Signal) {
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+    info!("Shutting down");
+}
+
+/// Loads the saved AQI state, skipping devices that are no longer configured
+fn restore_state(metrics: &Metrics, path: &Path, devices: &[Device]) {
+    let mut saved = match state::load(path) {
+        Ok(saved) => saved,
+        Err(e) => {
+            warn!("Starting without saved AQI state: {:#}", e);
+            return;
+        }
+    };
+
+    saved.retain_mut(|entry| {
+        let Some(device) = devices.iter().find(|device| device.host == entry.host) else {
+            return false;
+        };
+        // Devices named after their friendly name were saved under it; their
+        // history moves along once they are renamed again
+        if device.name_from_host {
+            entry.device = device.name.clone();
+        }
+        device.name == entry.device
+    });
+    if !saved.is_empty() {
+        info!(
+            "Restored AQI state of {} devices from {}",
+            saved.len(),
+            path.display()
+        );
+    }
+    metrics.restore_pm_history(saved);
+}
+
+fn save_state(metrics: &Metrics, path: &Path) {
+    match state::save(path, metrics.save_pm_history()) {
+        Ok(()) => debug!("Saved AQI state to {}", path.display()),
+        Err(e) => error!("Failed to save AQI state: {:#}", e),
+    }
+}
+
+/// Saves the AQI state on a timer, so a crash loses at most one interval
+async fn save_state_periodically(metrics: Arc<Metrics>, path: PathBuf) {
+    let mut interval = interval(STATE_SAVE_INTERVAL);
+    interval.tick().await; // First tick completes immediately
+
+    loop {
+        interval.tick().await;
+        save_state(&metrics, &path);
+    }
+}
+
+fn router(state: AppState) -> Router {
+    // Compressed when the scraper sends Accept-Encoding: gzip
+    let mut metrics_route = get(metrics_handler).layer(CompressionLayer::new());
+    let mut debug_devices_route = get(debug_devices_handler);
+    let mut config_route = get(config_handler);
+    let mut homeassistant_route = get(homeassistant_handler);
+    let mut json_route = get(json_handler);
+    let mut probe_route = get(probe_handler);
+    let mut test_route = get(test_handler);
+    if let Some(token) = state.metrics_token.clone() {
+        metrics_route = metrics_route.route_layer(middleware::from_fn_with_state(
+            token.clone(),
+            require_bearer_token,
+        ));
+        debug_devices_route = debug_devices_route.route_layer(middleware::from_fn_with_state(
+            token.clone(),
+            require_bearer_token,
+        ));
+        homeassistant_route = homeassistant_route.route_layer(middleware::from_fn_with_state(
+            token.clone(),
+            require_bearer_token,
+        ));
+        json_route = json_route.route_layer(middleware::from_fn_with_state(
+            token.clone(),
+            require_bearer_token,
+        ));
+        // Probes and connection tests reach arbitrary targets
+        probe_route = probe_route.route_layer(middleware::from_fn_with_state(
+            token.clone(),
+            require_bearer_token,
+        ));
+        test_route = test_route.route_layer(middleware::from_fn_with_state(
+            token.clone(),
+            require_bearer_token,
+        ));
+        config_route =
+            config_route.route_layer(middleware::from_fn_with_state(token, require_bearer_token));
+    }
+
+    let mut router = Router::new();
+    if state.enable_ha_json {
+        router = router.route("/homeassistant", homeassistant_route);
+    }
+    if state.enable_json_api {
+        router = router.route("/json", json_route);
+    }
+
+    router
+        .route(&state.metrics_path, metrics_route)
+        .route("/debug/devices", debug_devices_route)
+        .route("/config", config_route)
+        .route("/probe", probe_route)
+        .route("/test", test_route)
+        .route("/health", get(health_handler))
+        .route("/ready", get(ready_handler))
+        .route("/", get(root_handler))
+        .with_state(state)
+}
+
+/// Where device tasks publish what they collect
+#[derive(Clone)]
+struct Sinks {
+    metrics: Arc<Metrics>,
+    shared_metrics: SharedMetrics,
+    ready: Readiness,
+    snapshots: DeviceSnapshots,
+    pushgateway: Option<Pushgateway>,
+}
+
+impl Sinks {
+    /// Records a fetched status; returns whether the metrics were updated
+    async fn record_status(&self, device: &Device, status: &ApolloStatus) -> bool {
+        self.snapshots
+            .write()
+            .await
+            .insert(device.host.clone(), Snapshot::new(status.clone()));
+
+        match self.metrics.update_device(&device.host, status) {
+            Ok(()) => true,
+            Err(e) => {
+                error!("Failed to update metrics for {}: {}", device.name, e);
+                false
+            }
+        }
+    }
+
+    /// Records that a device's event stream or MQTT subscription is up
+    async fn record_connect(&self, device: &Device) {
+        self.metrics
+            .set_device_connected(&device.name, &device.host, true);
+        refresh_shared_metrics(&self.metrics, &self.shared_metrics).await;
+    }
+
+    /// Marks a device down after losing its event stream or MQTT connection
+    async fn record_disconnect(&self, device: &Device) {
+        self.metrics
+            .set_device_connected(&device.name, &device.host, false);
+        self.metrics.record_scrape_error(&device.name, &device.host);
+        self.metrics.mark_device_down(&device.name, &device.host);
+        self.publish(device, false).await;
+    }
+
+    /// Re-renders the served metrics and pushes the device's series
+    async fn publish(&self, device: &Device, scraped: bool) {
+        refresh_shared_metrics(&self.metrics, &self.shared_metrics).await;
+        if scraped {
+            self.ready.store(true, Ordering::Relaxed);
+        }
+        push_device_metrics(self.pushgateway.as_ref(), &self.metrics, device).await;
+    }
+}
+
+/// A device client for the configured protocol
+enum DeviceClient {
+    Http(ApolloClient),
+    Native(NativeApiClient),
+}
+
+impl DeviceClient {
+    async fn get_status(&self, device_name: &str) -> Result<ApolloStatus> {
+        match self {
+            DeviceClient::Http(client) => client.get_status(device_name).await,
+            DeviceClient::Native(client) => client.get_status(device_name).await,
+        }
+    }
+}
+
+/// Collection tasks of the running devices, keyed by host
+struct DeviceTasks {
+    mode: Mode,
+    protocol: Protocol,
+    api_password: Option<String>,
+    mqtt_broker: Option<MqttBroker>,
+    samples_per_interval: u32,
+    poll_jitter: Duration,
+    client_options: ClientOptions,
+    sinks: Sinks,
+    running: HashMap<String, (Device, JoinHandle<()>)>,
+    // Devices that didn't respond when added, retried periodically
+    pending: HashMap<String, Device>,
+}
+
+impl DeviceTasks {
+    /// Stops devices that are gone or changed and starts the new ones;
+    /// unchanged devices keep running with their state
+    async fn reconcile(&mut self, devices: Vec<Device>) -> Result<()> {
+        self.sinks.metrics.set_devices_total(devices.len());
+
+        let departed: Vec<String> = self
+            .running
+            .iter()
+            .filter(|(_, (device, _))| !devices.iter().any(|d| d.same_config(device)))
+            .map(|(host, _)| host.clone())
+            .collect();
+        for host in &departed {
+            self.remove(host).await;
+        }
+        for (host, device) in std::mem::take(&mut self.pending) {
+            if !devices.contains(&device) {
+                self.sinks.metrics.remove_device(&device.name, &host);
+            }
+        }
+
+        let mut added = 0;
+        for device in devices {
+            if self.running.contains_key(&device.host) {
+                continue;
+            }
+            if self.add(device.clone()).await? {
+                added += 1;
+            } else {
+                self.sinks
+                    .metrics
+                    .mark_device_down(&device.name, &device.host);
+                self.pending.insert(device.host.clone(), device);
+            }
+        }
+
+        refresh_shared_metrics(&self.sinks.metrics, &self.sinks.shared_metrics).await;
+        debug!(
+            "Devices reconciled: {} added, {} removed, {} running, {} pending",
+            added,
+            departed.len(),
+            self.running.len(),
+            self.pending.len()
+        );
+        Ok(())
+    }
+
+    /// Tries again to add the devices that didn't respond earlier
+    async fn retry_pending(&mut self) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        let mut added = 0;
+        for (host, device) in std::mem::take(&mut self.pending) {
+            if self.add(device.clone()).await? {
+                added += 1;
+            } else {
+                self.pending.insert(host, device);
+            }
+        }
+
+        if added > 0 {
+            refresh_shared_metrics(&self.sinks.metrics, &self.sinks.shared_metrics).await;
+        }
+        debug!(
+            "Retried pending devices: {} added, {} still pending",
+            added,
+            self.pending.len()
+        );
+        Ok(())
+    }
+
+    /// Connects to a device and starts collecting from it; returns false if
+    /// the device didn't respond
+    async fn add(&mut self, device: Device) -> Result<bool> {
+        self.sinks
+            .metrics
+            .set_location(&device.host, &device.location);
+
+        // MQTT devices are reached through the broker, never directly
+        if let (Mode::Mqtt, Some(broker)) = (self.mode, &self.mqtt_broker) {
+            info!(
+                "Added device: {} (MQTT topics under {}/)",
+                device.name, device.mqtt_topic_prefix
+            );
+            let task = tokio::spawn(subscribe_device(
+                broker.clone(),
+                device.clone(),
+                self.sinks.clone(),
+            ));
+            self.track(device, task);
+            return Ok(true);
+        }
+
+        let client = match self.protocol {
+            Protocol::Http => self.connect_http(&device).await?.map(DeviceClient::Http),
+            Protocol::Native => self
+                .connect_native(&device)
+                .await?
+                .map(DeviceClient::Native),
+        };
+        let Some(client) = client else {
+            return Ok(false);
+        };
+        let device = match &client {
+            DeviceClient::Http(client) if device.name_from_host => {
+                self.with_friendly_name(device, client).await
+            }
+            _ => device,
+        };
+
+        let sinks = self.sinks.clone();
+        let task = match (self.mode, client) {
+            (Mode::Sse, DeviceClient::Http(client)) => {
+                tokio::spawn(stream_device(client, device.clone(), sinks))
+            }
+            (_, client) => tokio::spawn(poll_device(
+                client,
+                device.clone(),
+                sinks,
+                self.samples_per_interval,
+                poll_offset(self.poll_jitter),
+            )),
+        };
+        self.track(device, task);
+        Ok(true)
+    }
+
+    /// Names a device after its ESPHome friendly name, if it has one
+    async fn with_friendly_name(&self, mut device: Device, client: &ApolloClient) -> Device {
+        let Some(name) = client.get_friendly_name().await else {
+            return device;
+        };
+        if name != device.name {
+            info!("Naming device at {} {}", device.host, name);
+            // Keep the PM history, possibly restored from the state file, but
+            // drop the series recorded under the host-derived name while the
+            // device was pending
+            self.sinks
+                .metrics
+                .rename_pm_history(&device.host, &device.name, &name);
+            self.sinks.metrics.remove_device(&device.name, &device.host);
+            self.sinks
+                .metrics
+                .set_location(&device.host, &device.location);
+            device.name = name;
+        }
+        device
+    }
+
+    /// Registers a started device task, watching the device for missing
+    /// updates if configured
+    fn track(&mut self, device: Device, task: JoinHandle<()>) {
+        if let Some(stale_after) = device.stale_after {
+            self.sinks
+                .metrics
+                .watch_device(&device.name, &device.host, stale_after);
+        }
+        self.running.insert(device.host.clone(), (device, task));
+    }
+
+    /// Builds a web server client and tests the connection
+    async fn connect_http(&self, device: &Device) -> Result<Option<ApolloClient>> {
+        let mut client_options = self.client_options.clone();
+        client_options.timeout = device.http_timeout;
+        client_options.headers = device.headers.clone();
+        let client = ApolloClient::new(device.host.clone(), &client_options)?
+            .with_basic_auth(device.auth.clone());
 
         // Test connection
-        match client.test_connection().await {
-            Ok(true) => {
-                info!("Added device: {} at {}", name, host);
-                let mut clients = device_clients.lock().await;
-                clients.insert(host, (client, name));
+        match client.check_connection().await {
+            Ok(()) => {
+                info!("Added device: {} at {}", device.name, device.host);
+                if let Err(e) = client.discover_sensors(&device.name).await {
+                    warn!(
+                        "Sensor discovery failed for {}, using known sensors: {}",
+                        device.name, e
+                    );
+                }
+                Ok(Some(client))
             }
-            Ok(false) => {
-                warn!("Device {} at {} is not responding", name, host);
+            Err(e) => {
+                warn!(
+                    "Failed to connect to device {} at {}: {}",
+                    device.name, device.host, e
+                );
+                self.sinks.metrics.record_last_error(
+                    &device.name,
+                    &device.host,
+                    &apollo::failure_reason(&e),
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    /// Builds a native API client and tests it with a first status read
+    async fn connect_native(&self, device: &Device) -> Result<Option<NativeApiClient>> {
+        let client = native_client(device, self.api_password.clone(), &self.client_options)?;
+
+        match client.get_status(&device.name).await {
+            Ok(status) => {
+                info!(
+                    "Added device: {} at {} (native API, {} sensors)",
+                    device.name,
+                    device.host,
+                    status.sensors.len()
+                );
+                Ok(Some(client))
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to connect to device {} at {} over the native API: {:#}",
+                    device.name, device.host, e
+                );
+                self.sinks.metrics.record_last_error(
+                    &device.name,
+                    &device.host,
+                    &apollo::failure_reason(&e),
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    /// Stops collecting from a device and drops its series
+    async fn remove(&mut self, host: &str) {
+        let Some((device, task)) = self.running.remove(host) else {
+            return;
+        };
+
+        // Wait for the task to stop so it can't re-create the series
+        task.abort();
+        let _ = task.await;
+
+        self.sinks.metrics.remove_device(&device.name, &device.host);
+        self.sinks.snapshots.write().await.remove(host);
+        info!("Removed device: {} at {}", device.name, device.host);
+    }
+}
+
+/// Retries devices that didn't respond when added, and re-reads the
+/// configuration on every SIGHUP to apply device changes
+///
+/// Only the device list is reloaded; other settings need a restart.
+async fn manage_devices(
+    mut device_tasks: DeviceTasks,
+    effective_config: EffectiveConfig,
+    mut hangup: Signal,
+) {
+    let mut retry = interval(DEVICE_RETRY_INTERVAL);
+    retry.tick().await; // First tick completes immediately
+
+    loop {
+        tokio::select! {
+            _ = retry.tick() => {
+                if let Err(e) = device_tasks.retry_pending().await {
+                    error!("Failed to add pending devices: {:#}", e);
+                }
+                continue;
+            }
+            received = hangup.recv() => {
+                if received.is_none() {
+                    return;
+                }
+            }
+        }
+        info!("Received SIGHUP, reloading devices");
+
+        let config = match Config::load() {
+            Ok(config) => config,
+            Err(e) => {
+                error!(
+                    "Failed to reload configuration, keeping current devices: {:#}",
+                    e
+                );
+                continue;
+            }
+        };
+        warn_on_names_mismatch(&config);
+        for (name, hosts) in config.duplicate_device_names() {
+            warn!("Devices {} share the name {:?}", hosts.join(", "), name);
+        }
+
+        *effective_config.write().await = config.effective_config();
+        if let Err(e) = device_tasks.reconcile(config.get_devices()).await {
+            error!("Failed to apply reloaded devices: {:#}", e);
+        }
+    }
+}
+
+/// Warns when `--names` and `--hosts` have different lengths, naming the
+/// hosts that fell back to a derived name
+fn warn_on_names_mismatch(config: &Config) {
+    for (host, name) in config.unnamed_hosts() {
+        warn!(
+            "--names has fewer entries than --hosts; {} uses the derived name {:?}",
+            host, name
+        );
+    }
+    if !config.unused_names().is_empty() {
+        warn!(
+            "--names has more entries than --hosts; ignoring {}",
+            config.unused_names().join(", ")
+        );
+    }
+}
+
+/// Picks a random delay of up to `jitter` for a device's poll schedule
+fn poll_offset(jitter: Duration) -> Duration {
+    if jitter.is_zero() {
+        return Duration::ZERO;
+    }
+    rand::random_range(Duration::ZERO..=jitter)
+}
+
+/// Polls a single device on its configured interval, shifted by `offset`,
+/// forever
+async fn poll_device(
+    client: DeviceClient,
+    device: Device,
+    sinks: Sinks,
+    samples: u32,
+    offset: Duration,
+) {
+    let metrics = &sinks.metrics;
+
+    // The initial fetch already happened during setup
+    let first_poll = tokio::time::Instant::now() + offset + device.poll_interval;
+    let mut interval = interval_at(first_poll, device.poll_interval);
+    if !offset.is_zero() {
+        debug!(
+            "Polling {} with a {:.1}s offset",
+            device.name,
+            offset.as_secs_f64()
+        );
+    }
+
+    loop {
+        interval.tick().await;
+
+        let started = Instant::now();
+        let scraped = match fetch_samples(&client, &device, samples).await {
+            Ok(sampled) => {
+                debug!(
+                    "Successfully fetched status from {} ({})",
+                    device.name, device.host
+                );
+
+                metrics.record_scrape_duration(
+                    &device.name,
+                    &device.host,
+                    sampled.fetch_duration.as_secs_f64(),
+                );
+
+                let updated = sinks.record_status(&device, &sampled.latest).await;
+                if samples > 1 {
+                    metrics.update_sample_stats(&device.name, &device.host, &sampled.readings);
+                }
+                updated
             }
             Err(e) => {
-                warn!("Failed to connect to device {} at {}: {}", name, host, e);
+                warn!(
+                    "Failed to fetch status from {} ({}): {}",
+                    device.name, device.host, e
+                );
+                metrics.record_scrape_error(&device.name, &device.host);
+                metrics.record_last_error(&device.name, &device.host, &apollo::failure_reason(&e));
+                metrics.mark_device_down(&device.name, &device.host);
+                false
+            }
+        };
+
+        sinks.publish(&device, scraped).await;
+        metrics.record_poll_cycle(&device.name, &device.host, started.elapsed().as_secs_f64());
+    }
+}
+
+/// Builds a native API client for a device; a session is given up on after
+/// the device's HTTP timeout or the scrape timeout, whichever is shorter
+fn native_client(
+    device: &Device,
+    api_password: Option<String>,
+    client_options: &ClientOptions,
+) -> Result<NativeApiClient> {
+    let timeout = client_options
+        .scrape_timeout
+        .map_or(device.http_timeout, |budget| {
+            budget.min(device.http_timeout)
+        });
+    NativeApiClient::new(&device.host, api_password, timeout)
+}
+
+/// Fetches every device once, directly over the configured protocol, and
+/// renders the resulting metrics; fails if no device responded
+async fn scrape_once(
+    devices: &[Device],
+    protocol: Protocol,
+    api_password: Option<String>,
+    client_options: &ClientOptions,
+    metrics: &Metrics,
+) -> Result<String> {
+    metrics.set_devices_total(devices.len());
+
+    let scrapes = devices.iter().map(|device| {
+        let api_password = api_password.clone();
+        async move {
+            metrics.set_location(&device.host, &device.location);
+            let client = match protocol {
+                Protocol::Http => {
+                    let mut options = client_options.clone();
+                    options.timeout = device.http_timeout;
+                    options.headers = device.headers.clone();
+                    DeviceClient::Http(
+                        ApolloClient::new(device.host.clone(), &options)?
+                            .with_basic_auth(device.auth.clone()),
+                    )
+                }
+                Protocol::Native => {
+                    DeviceClient::Native(native_client(device, api_password, client_options)?)
+                }
+            };
+
+            let name = match &client {
+                DeviceClient::Http(client) if device.name_from_host => client
+                    .get_friendly_name()
+                    .await
+                    .unwrap_or_else(|| device.name.clone()),
+                _ => device.name.clone(),
+            };
+
+            let started = Instant::now();
+            match client.get_status(&name).await {
+                Ok(status) => {
+                    metrics.record_scrape_duration(
+                        &name,
+                        &device.host,
+                        started.elapsed().as_secs_f64(),
+                    );
+                    metrics.update_device(&device.host, &status)?;
+                    Ok(true)
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to fetch status from {} ({}): {}",
+                        name, device.host, e
+                    );
+                    metrics.record_scrape_error(&name, &device.host);
+                    metrics.record_last_error(&name, &device.host, &apollo::failure_reason(&e));
+                    metrics.mark_device_down(&name, &device.host);
+                    anyhow::Ok(false)
+                }
             }
         }
+    });
+
+    let responded = futures_util::future::try_join_all(scrapes)
+        .await?
+        .into_iter()
+        .filter(|&responded| responded)
+        .count();
+    if responded == 0 {
+        anyhow::bail!("No device responded");
     }
+    info!("{} of {} devices responded", responded, devices.len());
 
-    // Start polling task
-    let poll_metrics = metrics.clone();
-    let poll_shared_metrics = shared_metrics.clone();
-    let poll_interval = config.poll_interval_duration();
-    let poll_clients = device_clients.clone();
+    metrics.gather()
+}
 
-    tokio::spawn(async move {
-        let mut interval = interval(poll_interval);
-        interval.tick().await; // First tick completes immediately
+/// Statuses fetched from a device over one poll interval
+struct Sampled {
+    /// The most recent status
+    latest: ApolloStatus,
+    /// How long fetching the most recent status took
+    fetch_duration: Duration,
+    /// Every fetched reading of each sensor
+    readings: HashMap<String, Vec<f64>>,
+}
 
-        loop {
-            interval.tick().await;
+/// Fetches `samples` statuses spaced evenly across the poll interval,
+/// failing only if none of them could be fetched
+async fn fetch_samples(client: &DeviceClient, device: &Device, samples: u32) -> Result<Sampled> {
+    let spacing = device.poll_interval / samples.max(1);
+    let mut sampled: Option<Sampled> = None;
+    let mut last_error = None;
 
-            let clients = poll_clients.lock().await;
-            for (host, (client, device_name)) in clients.iter() {
-                match client.get_status(device_name).await {
-                    Ok(status) => {
-                        debug!(
-                            "Successfully fetched status from {} ({})",
-                            device_name, host
-                        );
+    for sample in 0..samples.max(1) {
+        if sample > 0 {
+            tokio::time::sleep(spacing).await;
+        }
+
+        let started = Instant::now();
+        match client.get_status(&device.name).await {
+            Ok(status) => {
+                let mut readings = sampled.take().map(|s| s.readings).unwrap_or_default();
+                for (sensor_id, sensor) in &status.sensors {
+                    readings
+                        .entry(sensor_id.clone())
+                        .or_default()
+                        .push(sensor.value);
+                }
+                sampled = Some(Sampled {
+                    latest: status,
+                    fetch_duration: started.elapsed(),
+                    readings,
+                });
+            }
+            Err(e) => {
+                debug!(
+                    "Sample {} from {} ({}) failed: {}",
+                    sample + 1,
+                    device.name,
+                    device.host,
+                    e
+                );
+                last_error = Some(e);
+            }
+        }
+    }
+
+    match (sampled, last_error) {
+        (Some(sampled), _) => Ok(sampled),
+        (None, Some(e)) => Err(e),
+        (None, None) => unreachable!("at least one sample is fetched"),
+    }
+}
 
-                        if let Err(e) = poll_metrics.update_device(host, &status) {
-                            error!("Failed to update metrics for {}: {}", device_name, e);
-                            continue;
+/// Follows a single device's event stream, reconnecting with exponential
+/// backoff whenever the stream drops
+async fn stream_device(client: ApolloClient, device: Device, sinks: Sinks) {
+    let mut backoff = ReconnectBackoff::default();
+
+    loop {
+        match client.stream_events().await {
+            Ok(mut events) => {
+                info!(
+                    "Subscribed to events from {} ({})",
+                    device.name, device.host
+                );
+                backoff.connected();
+                sinks.record_connect(&device).await;
+
+                let mut status = ApolloStatus {
+                    sensors: HashMap::new(),
+                    device_name: device.name.clone(),
+                    info: client.get_device_info().await,
+                };
+
+                loop {
+                    match events.next_update().await {
+                        Ok(Some((sensor_id, value))) => {
+                            status.sensors.insert(sensor_id, value);
+
+                            let updated = sinks.record_status(&device, &status).await;
+                            sinks.publish(&device, updated).await;
+                        }
+                        Ok(None) => {
+                            warn!("Event stream from {} ({}) closed", device.name, device.host);
+                            break;
+                        }
+                        Err(e) => {
+                            warn!(
+                                "Event stream from {} ({}) failed: {}",
+                                device.name, device.host, e
+                            );
+                            break;
                         }
                     }
-                    Err(e) => {
-                        warn!(
-                            "Failed to fetch status from {} ({}): {}",
-                            device_name, host, e
-                        );
-                        poll_metrics.mark_device_down(device_name, host);
+                }
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to subscribe to events from {} ({}): {}",
+                    device.name, device.host, e
+                );
+            }
+        }
+
+        sinks.record_disconnect(&device).await;
+
+        let delay = backoff.next_delay();
+        debug!("Reconnecting to {} in {:?}", device.name, delay);
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Follows a single device's MQTT state topics, reconnecting with
+/// exponential backoff whenever the broker connection drops
+async fn subscribe_device(broker: MqttBroker, device: Device, sinks: Sinks) {
+    let mut backoff = ReconnectBackoff::default();
+
+    loop {
+        match broker.subscribe(&device.mqtt_topic_prefix).await {
+            Ok(mut updates) => {
+                let mut status = ApolloStatus {
+                    sensors: HashMap::new(),
+                    device_name: device.name.clone(),
+                    info: None,
+                };
+
+                loop {
+                    match updates.next_update().await {
+                        Ok((sensor_id, value)) => {
+                            // The broker connection is only made once updates
+                            // are read, so the first one confirms it
+                            if !backoff.is_connected() {
+                                backoff.connected();
+                                sinks.record_connect(&device).await;
+                            }
+                            status.sensors.insert(sensor_id, value);
+
+                            let updated = sinks.record_status(&device, &status).await;
+                            sinks.publish(&device, updated).await;
+                        }
+                        Err(e) => {
+                            warn!(
+                                "MQTT subscription for {} ({}) failed: {:#}",
+                                device.name, device.host, e
+                            );
+                            break;
+                        }
                     }
                 }
             }
+            Err(e) => {
+                warn!(
+                    "Failed to subscribe to MQTT topics of {} ({}): {:#}",
+                    device.name, device.host, e
+                );
+            }
+        }
+
+        sinks.record_disconnect(&device).await;
+
+        let delay = backoff.next_delay();
+        debug!(
+            "Reconnecting to the MQTT broker for {} in {:?}",
+            device.name, delay
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Capped exponential backoff between reconnects of an event stream or MQTT
+/// subscription, so a flapping device doesn't cause a tight reconnect loop
+#[derive(Debug)]
+struct ReconnectBackoff {
+    delay: Duration,
+    connected_at: Option<Instant>,
+}
+
+impl Default for ReconnectBackoff {
+    fn default() -> Self {
+        Self {
+            delay: RECONNECT_MIN_DELAY,
+            connected_at: None,
+        }
+    }
+}
+
+impl ReconnectBackoff {
+    fn connected(&mut self) {
+        self.connected_at = Some(Instant::now());
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connected_at.is_some()
+    }
+
+    /// Delay before the next reconnect after the connection dropped or
+    /// couldn't be made
+    ///
+    /// Starts over once a connection lasted [`RECONNECT_STABLE_AFTER`], and is
+    /// jittered to between half and all of the current step so devices that
+    /// dropped together don't reconnect in lockstep.
+    fn next_delay(&mut self) -> Duration {
+        if self
+            .connected_at
+            .take()
+            .is_some_and(|at| at.elapsed() >= RECONNECT_STABLE_AFTER)
+        {
+            self.delay = RECONNECT_MIN_DELAY;
+        }
+
+        let delay = rand::random_range(self.delay / 2..=self.delay);
+        self.delay = (self.delay * 2).min(RECONNECT_MAX_DELAY);
+        delay
+    }
+}
+
+/// Periodically marks devices without recent successful updates as down, so
+/// a stuck or crashed device task can't leave a device reported up
+async fn watch_stale_devices(metrics: Arc<Metrics>, shared_metrics: SharedMetrics) {
+    let mut interval = interval(STALE_CHECK_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        if !metrics.mark_stale_devices(Instant::now()).is_empty() {
+            refresh_shared_metrics(&metrics, &shared_metrics).await;
+        }
+    }
+}
+
+/// Re-renders the metrics served on /metrics
+async fn refresh_shared_metrics(metrics: &Metrics, shared_metrics: &SharedMetrics) {
+    match metrics.render() {
+        // Swapped in whole, so /metrics never serves a partial update
+        Ok((text, openmetrics)) => {
+            let mut metrics_guard = shared_metrics.write().await;
+            *metrics_guard = RenderedMetrics { text, openmetrics };
+        }
+        Err(e) => {
+            error!("Failed to gather metrics: {}", e);
+        }
+    }
+}
+
+/// Pushes a device's series to the Pushgateway, if one is configured
+///
+/// Failures are logged and otherwise ignored so they never stop collection.
+async fn push_device_metrics(
+    pushgateway: Option<&Pushgateway>,
+    metrics: &Metrics,
+    device: &Device,
+) {
+    let Some(pushgateway) = pushgateway else {
+        return;
+    };
+
+    let body = match metrics.gather_device(&device.host) {
+        Ok(body) => body,
+        Err(e) => {
+            error!("Failed to gather metrics for {}: {}", device.name, e);
+            return;
+        }
+    };
+
+    if let Err(e) = pushgateway.push(&device.name, body).await {
+        warn!("Failed to push metrics for {}: {:#}", device.name, e);
+    }
+}
+
+/// Whether the scraper negotiated the OpenMetrics format
+fn wants_openmetrics(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| value.contains("application/openmetrics-text"))
+}
+
+/// Rejects requests without the configured `Authorization: Bearer` token
+async fn require_bearer_token(
+    State(token): State<Arc<str>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if has_bearer_token(request.headers(), &token) {
+        next.run(request).await
+    } else {
+        (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+            "Unauthorized\n",
+        )
+            .into_response()
+    }
+}
+
+/// Whether the request carries `Authorization: Bearer <token>`, compared in
+/// constant time
+fn has_bearer_token(headers: &HeaderMap, token: &str) -> bool {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|provided| bool::from(provided.as_bytes().ct_eq(token.as_bytes())))
+}
+
+async fn metrics_handler(State(metrics): State<SharedMetrics>, headers: HeaderMap) -> Response {
+    let metrics_guard = metrics.read().await;
+    if wants_openmetrics(&headers) {
+        (
+            [(header::CONTENT_TYPE, OPENMETRICS_FORMAT)],
+            metrics_guard.openmetrics.clone(),
+        )
+            .into_response()
+    } else {
+        (
+            [(header::CONTENT_TYPE, text_content_type())],
+            metrics_guard.text.clone(),
+        )
+            .into_response()
+    }
+}
+
+/// Content type of the Prometheus text format, including its version
+fn text_content_type() -> String {
+    format!("{}; charset=utf-8", TextEncoder::new().format_type())
+}
+
+/// Scrapes the device given by `?target=` and returns metrics for it alone
+///
+/// Each probe gathers into its own registry, so concurrent probes never see
+/// each other's series.
+async fn probe_handler(
+    State(probe): State<Arc<ProbeSettings>>,
+    Query(params): Query<ProbeParams>,
+    headers: HeaderMap,
+) -> Response {
+    let target = match probe_target(params) {
+        Ok(target) => target,
+        Err(rejection) => return rejection.into_response(),
+    };
+
+    let metrics = match Metrics::new(probe.metrics_options.clone()) {
+        Ok(metrics) => metrics,
+        Err(e) => {
+            error!("Failed to create metrics for probe: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let client = match ApolloClient::new(target.clone(), &probe.client_options) {
+        Ok(client) => client,
+        Err(e) => {
+            error!("Failed to create client for probe: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let device_name = extract_device_name(&target);
+    let started = Instant::now();
+    match client.get_status(&device_name).await {
+        Ok(status) => {
+            metrics.record_scrape_duration(&device_name, &target, started.elapsed().as_secs_f64());
+            if let Err(e) = metrics.update_device(&target, &status) {
+                error!("Failed to update metrics for {}: {}", target, e);
+            }
+        }
+        Err(e) => {
+            warn!("Probe of {} failed: {}", target, e);
+            metrics.record_scrape_error(&device_name, &target);
+            metrics.record_last_error(&device_name, &target, &apollo::failure_reason(&e));
+            metrics.mark_device_down(&device_name, &target);
+        }
+    }
+
+    if wants_openmetrics(&headers) {
+        return match metrics.encode(&OpenMetricsEncoder::new()) {
+            Ok(metrics_text) => {
+                ([(header::CONTENT_TYPE, OPENMETRICS_FORMAT)], metrics_text).into_response()
+            }
+            Err(e) => {
+                error!("Failed to gather probe metrics: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        };
+    }
+
+    match metrics.gather() {
+        Ok(metrics_text) => {
+            ([(header::CONTENT_TYPE, text_content_type())], metrics_text).into_response()
+        }
+        Err(e) => {
+            error!("Failed to gather probe metrics: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Checks that the device given by `?target=` answers, for verifying a URL
+/// and its credentials before adding it to the configuration
+async fn test_handler(
+    State(probe): State<Arc<ProbeSettings>>,
+    Query(params): Query<ProbeParams>,
+) -> Response {
+    let target = match probe_target(params) {
+        Ok(target) => target,
+        Err(rejection) => return rejection.into_response(),
+    };
 
-            drop(clients);
+    let client = match ApolloClient::new(target.clone(), &probe.client_options) {
+        Ok(client) => client,
+        Err(e) => {
+            error!("Failed to create client for connection test: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
 
-            // Gather all metrics
-            match poll_metrics.gather() {
-                Ok(metrics_text) => {
-                    let mut metrics_guard = poll_shared_metrics.write().await;
-                    *metrics_guard = metrics_text;
-                }
-                Err(e) => {
-                    error!("Failed to gather metrics: {}", e);
-                }
+    let reachable = client.test_connection().await.unwrap_or(false);
+    let sensors_found = if reachable {
+        match client.get_status(&extract_device_name(&target)).await {
+            Ok(status) => status.sensors.len(),
+            Err(e) => {
+                warn!("Failed to fetch sensors from {}: {}", target, e);
+                0
             }
         }
-    });
+    } else {
+        0
+    };
 
-    // Initialize HTTP server
-    let app = Router::new()
-        .route("/metrics", get(metrics_handler))
-        .route("/health", get(health_handler))
-        .route("/", get(root_handler))
-        .with_state(shared_metrics);
+    Json(ConnectionTest {
+        reachable,
+        sensors_found,
+    })
+    .into_response()
+}
 
-    let addr = config.metrics_bind_address();
-    info!("Starting metrics server on {}", &addr);
+/// Extracts the `?target=` device URL, rejecting it if missing or not an
+/// http(s) URL
+fn probe_target(params: ProbeParams) -> Result<String, (StatusCode, String)> {
+    let Some(target) = params.target.filter(|target| !target.is_empty()) else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Missing target parameter\n".to_string(),
+        ));
+    };
 
-    let listener = tokio::net::TcpListener::bind(&addr).await?;
-    axum::serve(listener, app).await?;
+    match reqwest::Url::parse(&target) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") && url.has_host() => Ok(target),
+        _ => Err((
+            StatusCode::BAD_REQUEST,
+            format!("Invalid target: {}\n", target),
+        )),
+    }
+}
 
-    Ok(())
+async fn health_handler() -> &'static str {
+    "OK"
 }
 
-async fn metrics_handler(
-    axum::extract::State(metrics): axum::extract::State<SharedMetrics>,
-) -> String {
-    let metrics_guard = metrics.read().await;
-    metrics_guard.clone()
+/// Latest status fetched from each device, keyed by host, for debugging
+/// sensor mapping
+async fn debug_devices_handler(
+    State(snapshots): State<DeviceSnapshots>,
+) -> Json<BTreeMap<String, ApolloStatus>> {
+    let snapshots = snapshots.read().await;
+    Json(
+        snapshots
+            .iter()
+            .map(|(host, snapshot)| (host.clone(), snapshot.status.clone()))
+            .collect(),
+    )
 }
 
-async fn health_handler() -> &'static str {
-    "OK"
+/// Latest readings as Home Assistant state objects keyed by entity id, for
+/// Home Assistant REST sensors
+async fn homeassistant_handler(
+    State(snapshots): State<DeviceSnapshots>,
+) -> Json<BTreeMap<String, homeassistant::HaState>> {
+    let snapshots = snapshots.read().await;
+    Json(homeassistant::states(
+        snapshots.values().map(|snapshot| &snapshot.status),
+    ))
+}
+
+/// Latest readings as a flat array of rows, for Grafana's Infinity datasource;
+/// see [`json_api`] for the schema
+async fn json_handler(State(snapshots): State<DeviceSnapshots>) -> Json<Vec<json_api::Reading>> {
+    let snapshots = snapshots.read().await;
+    Json(json_api::readings(snapshots.iter().map(
+        |(host, snapshot)| (host.as_str(), &snapshot.status, snapshot.updated_at),
+    )))
+}
+
+/// Effective configuration with secrets redacted, for checking how flags and
+/// environment variables were parsed
+async fn config_handler(State(config): State<EffectiveConfig>) -> Json<serde_json::Value> {
+    Json(config.read().await.clone())
+}
+
+/// Readiness check: succeeds once metrics from a successful scrape are being served
+async fn ready_handler(State(ready): State<Readiness>) -> (StatusCode, &'static str) {
+    if ready.load(Ordering::Relaxed) {
+        (StatusCode::OK, "OK")
+    } else {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Waiting for the first successful scrape",
+        )
+    }
 }
 
-async fn root_handler() -> &'static str {
-    "Apollo Air-1 Prometheus Exporter\n\nEndpoints:\n  /metrics - Prometheus metrics\n  /health  - Health check\n"
+/// Landing page: live device status followed by the available endpoints
+///
+/// The device status is only listed with the metrics token, if one is
+/// configured, as it exposes the same readings as /metrics.
+async fn root_handler(State(state): State<AppState>, headers: HeaderMap) -> String {
+    let mut text = String::from("Apollo Air-1 Prometheus Exporter\n\n");
+    match &state.metrics_token {
+        Some(token) if !has_bearer_token(&headers, token) => {
+            text.push_str("Device status requires the metrics token\n");
+        }
+        _ => {
+            let snapshots = state.snapshots.read().await;
+            let statuses = snapshots
+                .iter()
+                .map(|(host, snapshot)| (host.as_str(), &snapshot.status))
+                .collect();
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64();
+            text.push_str(&status_page::render(
+                &state.device_metrics.device_overview(),
+                &statuses,
+                now,
+            ));
+        }
+    }
+
+    let _ = write!(
+        text,
+        "\nEndpoints:\n  {} - Prometheus metrics\n  /probe   - Scrape a single device (?target=http://...)\n  /test    - Check the connection to a device (?target=http://...)\n  /health  - Health check\n  /ready   - Readiness check (after the first successful scrape)\n  /debug/devices - Latest fetched status per device (JSON)\n  /config  - Effective configuration, secrets redacted (JSON)\n",
+        state.metrics_path
+    );
+    if state.enable_ha_json {
+        text.push_str("  /homeassistant - Latest readings as Home Assistant states (JSON)\n");
+    }
+    if state.enable_json_api {
+        text.push_str("  /json    - Latest readings for Grafana's Infinity datasource (JSON)\n");
+    }
+    text
 }
 
 #[cfg(test)]
@@ -157,18 +1621,726 @@ mod tests {
     use std::sync::Arc;
     use tokio::sync::RwLock;
     use tower::ServiceExt;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
 
     fn create_test_app() -> Router {
-        let shared_metrics: SharedMetrics = Arc::new(RwLock::new(
-            "# HELP apollo_air1_device_up Whether device is up\n# TYPE apollo_air1_device_up gauge\napollo_air1_device_up{device=\"test\"} 1\n"
+        create_test_app_with_token(None)
+    }
+
+    fn create_test_app_with_token(metrics_token: Option<&str>) -> Router {
+        router(test_state(metrics_token))
+    }
+
+    fn test_state(metrics_token: Option<&str>) -> AppState {
+        let shared_metrics: SharedMetrics = Arc::new(RwLock::new(RenderedMetrics {
+            text: "# HELP apollo_air1_device_up Whether device is up\n# TYPE apollo_air1_device_up gauge\napollo_air1_device_up{device=\"test\"} 1\n"
+                .to_string(),
+            openmetrics: "# HELP apollo_air1_device_up Whether device is up\n# TYPE apollo_air1_device_up gauge\napollo_air1_device_up{device=\"test\"} 1\n# EOF\n"
                 .to_string(),
+        }));
+
+        AppState {
+            metrics: shared_metrics,
+            device_metrics: Arc::new(Metrics::new(MetricsOptions::default()).unwrap()),
+            ready: Arc::new(AtomicBool::new(true)),
+            snapshots: DeviceSnapshots::default(),
+            config: EffectiveConfig::default(),
+            probe: Arc::new(ProbeSettings {
+                client_options: ClientOptions::new(Duration::from_secs(5)),
+                metrics_options: MetricsOptions::default(),
+            }),
+            metrics_token: metrics_token.map(Arc::from),
+            metrics_path: Arc::from("/metrics"),
+            enable_ha_json: false,
+            enable_json_api: false,
+        }
+    }
+
+    async fn get_response(app: Router, uri: &str) -> (StatusCode, String) {
+        let response = app
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_metrics_handler_openmetrics() {
+        let app = create_test_app();
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/metrics")
+                    .header(
+                        header::ACCEPT,
+                        "application/openmetrics-text;version=1.0.0,text/plain;q=0.5",
+                    )
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], OPENMETRICS_FORMAT);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body_str = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body_str.ends_with("# EOF\n"));
+    }
+
+    #[tokio::test]
+    async fn test_metrics_handler_gzip() {
+        for accept in ["text/plain", OPENMETRICS_FORMAT] {
+            let response = create_test_app()
+                .oneshot(
+                    Request::builder()
+                        .uri("/metrics")
+                        .header(header::ACCEPT, accept)
+                        .header(header::ACCEPT_ENCODING, "gzip")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+            let content_type = response.headers()[header::CONTENT_TYPE].to_str().unwrap();
+            assert!(content_type.starts_with(accept), "{}", content_type);
+
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert_eq!(&body[..2], [0x1f, 0x8b]); // gzip magic bytes
+        }
+
+        // Other endpoints are not compressed
+        let response = create_test_app()
+            .oneshot(
+                Request::builder()
+                    .uri("/")
+                    .header(header::ACCEPT_ENCODING, "gzip")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+    }
+
+    #[tokio::test]
+    async fn test_metrics_token() {
+        let request = |authorization: Option<&str>, uri: &str| {
+            let mut builder = Request::builder().uri(uri);
+            if let Some(value) = authorization {
+                builder = builder.header(header::AUTHORIZATION, value);
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+
+        let app = create_test_app_with_token(Some("secret"));
+
+        let response = app
+            .clone()
+            .oneshot(request(None, "/metrics"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = app
+            .clone()
+            .oneshot(request(Some("Bearer wrong"), "/metrics"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = app
+            .clone()
+            .oneshot(request(Some("Bearer secret"), "/metrics"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Probes and connection tests are protected too, as they reach
+        // arbitrary targets
+        let response = app
+            .clone()
+            .oneshot(request(None, "/probe?target=http://192.168.1.100"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = app
+            .clone()
+            .oneshot(request(None, "/test?target=http://192.168.1.100"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        // Liveness checks stay open
+        let response = app.oneshot(request(None, "/health")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_probe_handler() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("GET"))
+            .and(path("/sensor"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_string(
+                    r#"[{"id": "sensor-co2", "value": 612.0, "state": "612 ppm"}]"#,
+                ),
+            )
+            .mount(&mock_server)
+            .await;
+
+        let target = mock_server.uri();
+        let (status, body) =
+            get_response(create_test_app(), &format!("/probe?target={}", target)).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("apollo_air1_co2_ppm"));
+        assert!(body.contains("612"));
+        assert!(body.contains(&format!(r#"host="{}""#, target)));
+        // Probes don't leak into the shared metrics
+        assert!(!body.contains(r#"device="test""#));
+    }
+
+    #[tokio::test]
+    async fn test_test_handler() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("GET"))
+            .and(path("/sensor/esp_temperature"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                r#"{"id": "sensor-esp_temperature", "value": 41.0, "state": "41.0 °C"}"#,
+            ))
+            .mount(&mock_server)
+            .await;
+
+        Mock::given(method("GET"))
+            .and(path("/sensor"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                r#"[
+                {"id": "sensor-esp_temperature", "value": 41.0, "state": "41.0 °C"},
+                {"id": "sensor-uptime", "value": 3600.0, "state": "3600 s"}
+            ]"#,
+            ))
+            .mount(&mock_server)
+            .await;
+
+        let (status, body) = get_response(
+            create_test_app(),
+            &format!("/test?target={}", mock_server.uri()),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["reachable"], true);
+        assert_eq!(json["sensors_found"], 2);
+
+        // Nothing listens on the discard port
+        let (status, body) =
+            get_response(create_test_app(), "/test?target=http://127.0.0.1:9").await;
+        assert_eq!(status, StatusCode::OK);
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["reachable"], false);
+        assert_eq!(json["sensors_found"], 0);
+
+        let (status, _) = get_response(create_test_app(), "/test?target=ftp://device").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    fn test_device_tasks(metrics: Arc<Metrics>) -> DeviceTasks {
+        DeviceTasks {
+            mode: Mode::Poll,
+            protocol: Protocol::Http,
+            api_password: None,
+            mqtt_broker: None,
+            samples_per_interval: 1,
+            poll_jitter: Duration::ZERO,
+            client_options: ClientOptions::new(Duration::from_secs(5)),
+            sinks: Sinks {
+                metrics: metrics.clone(),
+                shared_metrics: Arc::new(RwLock::new(RenderedMetrics::default())),
+                ready: Readiness::default(),
+                snapshots: DeviceSnapshots::default(),
+                pushgateway: None,
+            },
+            running: HashMap::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    fn test_device(host: String) -> Device {
+        Device {
+            host,
+            name: "Office".to_string(),
+            name_from_host: false,
+            location: String::new(),
+            poll_interval: Duration::from_secs(60),
+            http_timeout: Duration::from_secs(5),
+            stale_after: None,
+            auth: None,
+            headers: Vec::new(),
+            mqtt_topic_prefix: "Office".to_string(),
+        }
+    }
+
+    async fn mount_co2(mock_server: &MockServer) {
+        Mock::given(method("GET"))
+            .and(path("/sensor/co2"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(r#"{"id": "sensor-co2", "value": 450.0, "state": "450 ppm"}"#),
+            )
+            .mount(mock_server)
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_device_tasks_reconcile() {
+        let mock_server = MockServer::start().await;
+        mount_co2(&mock_server).await;
+
+        let metrics = Arc::new(Metrics::new(MetricsOptions::default()).unwrap());
+        let mut device_tasks = test_device_tasks(metrics.clone());
+        let device = test_device(mock_server.uri());
+
+        device_tasks.reconcile(vec![device.clone()]).await.unwrap();
+        assert!(device_tasks.running.contains_key(&device.host));
+
+        // Reloading the same device keeps its task running
+        let task_id = device_tasks.running[&device.host].1.id();
+        device_tasks.reconcile(vec![device.clone()]).await.unwrap();
+        assert_eq!(device_tasks.running[&device.host].1.id(), task_id);
+
+        metrics
+            .update_device(
+                &device.host,
+                &ApolloStatus {
+                    sensors: HashMap::new(),
+                    device_name: device.name.clone(),
+                    info: None,
+                },
+            )
+            .unwrap();
+        device_tasks.reconcile(Vec::new()).await.unwrap();
+        assert!(device_tasks.running.is_empty());
+        assert!(!metrics.gather().unwrap().contains("Office"));
+    }
+
+    #[tokio::test]
+    async fn test_device_tasks_friendly_name() {
+        let mock_server = MockServer::start().await;
+        mount_co2(&mock_server).await;
+        Mock::given(method("GET"))
+            .and(path("/"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("<title>Living Room</title>"))
+            .mount(&mock_server)
+            .await;
+
+        let metrics = Arc::new(Metrics::new(MetricsOptions::default()).unwrap());
+        let mut device_tasks = test_device_tasks(metrics.clone());
+        let device = Device {
+            name_from_host: true,
+            ..test_device(mock_server.uri())
+        };
+
+        device_tasks.reconcile(vec![device.clone()]).await.unwrap();
+        assert_eq!(device_tasks.running[&device.host].0.name, "Living Room");
+
+        // The renamed device still matches its configuration on reload
+        let task_id = device_tasks.running[&device.host].1.id();
+        device_tasks.reconcile(vec![device.clone()]).await.unwrap();
+        assert_eq!(device_tasks.running[&device.host].1.id(), task_id);
+    }
+
+    #[tokio::test]
+    async fn test_native_scrape_timeout() {
+        // Accepts the connection but never answers the handshake
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+            std::future::pending::<()>().await;
+        });
+
+        let metrics = Arc::new(Metrics::new(MetricsOptions::default()).unwrap());
+        let mut device_tasks = test_device_tasks(metrics.clone());
+        device_tasks.protocol = Protocol::Native;
+        device_tasks.client_options.scrape_timeout = Some(Duration::from_millis(200));
+        let device = test_device(format!("http://127.0.0.1:{}", port));
+
+        // Given up on after the scrape timeout, not the 5s HTTP timeout
+        let client =
+            tokio::time::timeout(Duration::from_secs(2), device_tasks.connect_native(&device))
+                .await
+                .unwrap()
+                .unwrap();
+        assert!(client.is_none());
+        assert!(metrics.gather().unwrap().contains(r#"reason="timeout""#));
+    }
+
+    #[tokio::test]
+    async fn test_friendly_name_keeps_saved_state() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("<title>Living Room</title>"))
+            .mount(&mock_server)
+            .await;
+
+        let options = MetricsOptions {
+            aqi_mode: config::AqiMode::NowCast,
+            ..Default::default()
+        };
+        let status = ApolloStatus {
+            sensors: HashMap::from([(
+                "pm__2_5_m_weight_concentration".to_string(),
+                apollo::SensorValue {
+                    value: 12.0,
+                    unit: "µg/m³".to_string(),
+                    name: "PM2.5".to_string(),
+                },
+            )]),
+            device_name: "Living Room".to_string(),
+            info: None,
+        };
+        let previous = Metrics::new(options.clone()).unwrap();
+        previous.update_device(&mock_server.uri(), &status).unwrap();
+        let path = std::env::temp_dir().join(format!(
+            "apollo-air1-exporter-friendly-name-{}.json",
+            std::process::id()
         ));
+        state::save(&path, previous.save_pm_history()).unwrap();
+
+        // Saved under the friendly name, restored for the host-derived one
+        let metrics = Arc::new(Metrics::new(options).unwrap());
+        let device = Device {
+            name_from_host: true,
+            ..test_device(mock_server.uri())
+        };
+        restore_state(&metrics, &path, std::slice::from_ref(&device));
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(metrics.save_pm_history()[0].device, "Office");
+
+        let device_tasks = test_device_tasks(metrics.clone());
+        let client = ApolloClient::new(
+            mock_server.uri(),
+            &ClientOptions::new(Duration::from_secs(5)),
+        )
+        .unwrap();
+        let device = device_tasks.with_friendly_name(device, &client).await;
+        assert_eq!(device.name, "Living Room");
+
+        let saved = metrics.save_pm_history();
+        assert_eq!(saved.len(), 1);
+        assert_eq!(saved[0].device, "Living Room");
+        assert_eq!(
+            serde_json::to_value(&saved[0]).unwrap(),
+            serde_json::to_value(&previous.save_pm_history()[0]).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_device_tasks_retry_pending() {
+        let mock_server = MockServer::start().await;
+
+        let metrics = Arc::new(Metrics::new(MetricsOptions::default()).unwrap());
+        let mut device_tasks = test_device_tasks(metrics.clone());
+        let device = test_device(mock_server.uri());
+
+        // Not responding yet: marked down and kept for retrying
+        device_tasks.reconcile(vec![device.clone()]).await.unwrap();
+        assert!(device_tasks.running.is_empty());
+        assert!(device_tasks.pending.contains_key(&device.host));
+        assert!(
+            metrics
+                .gather()
+                .unwrap()
+                .contains(r#"apollo_air1_device_up{device="Office""#)
+        );
+
+        device_tasks.retry_pending().await.unwrap();
+        assert!(device_tasks.running.is_empty());
+
+        // Picked up once it answers
+        mount_co2(&mock_server).await;
+        device_tasks.retry_pending().await.unwrap();
+        assert!(device_tasks.running.contains_key(&device.host));
+        assert!(device_tasks.pending.is_empty());
+
+        // A pending device dropped from the configuration loses its series
+        device_tasks.reconcile(Vec::new()).await.unwrap();
+        let other = test_device("http://127.0.0.1:9".to_string());
+        device_tasks.reconcile(vec![other.clone()]).await.unwrap();
+        assert!(device_tasks.pending.contains_key(&other.host));
+        device_tasks.reconcile(Vec::new()).await.unwrap();
+        assert!(device_tasks.pending.is_empty());
+        assert!(!metrics.gather().unwrap().contains("Office"));
+    }
+
+    #[test]
+    fn test_poll_offset() {
+        assert_eq!(poll_offset(Duration::ZERO), Duration::ZERO);
+        for _ in 0..100 {
+            assert!(poll_offset(Duration::from_secs(5)) <= Duration::from_secs(5));
+        }
+    }
+
+    #[test]
+    fn test_reconnect_backoff() {
+        let mut backoff = ReconnectBackoff::default();
+        let steps: Vec<_> = (0..8).map(|_| backoff.next_delay()).collect();
+        for (step, delay) in steps.iter().enumerate() {
+            let max = (RECONNECT_MIN_DELAY * 2u32.pow(step as u32)).min(RECONNECT_MAX_DELAY);
+            assert!(*delay >= max / 2 && *delay <= max, "{step}: {delay:?}");
+        }
+
+        // A short-lived connection keeps backing off
+        backoff.connected();
+        assert!(backoff.next_delay() >= RECONNECT_MAX_DELAY / 2);
+
+        // A connection that lasted starts over
+        backoff.connected_at = Instant::now().checked_sub(RECONNECT_STABLE_AFTER);
+        assert!(backoff.next_delay() <= RECONNECT_MIN_DELAY);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_samples() {
+        let mock_server = MockServer::start().await;
+
+        for value in [450.0, 520.0] {
+            Mock::given(method("GET"))
+                .and(path("/sensor/co2"))
+                .respond_with(ResponseTemplate::new(200).set_body_string(format!(
+                    r#"{{"id": "sensor-co2", "value": {value}, "state": "{value} ppm"}}"#
+                )))
+                .up_to_n_times(1)
+                .mount(&mock_server)
+                .await;
+        }
+
+        let client = ApolloClient::new(
+            mock_server.uri(),
+            &ClientOptions::new(Duration::from_secs(5)),
+        )
+        .unwrap();
+        let device = Device {
+            host: mock_server.uri(),
+            name: "Office".to_string(),
+            name_from_host: false,
+            location: String::new(),
+            poll_interval: Duration::from_millis(20),
+            http_timeout: Duration::from_secs(5),
+            stale_after: None,
+            auth: None,
+            headers: Vec::new(),
+            mqtt_topic_prefix: "Office".to_string(),
+        };
+
+        let sampled = fetch_samples(&DeviceClient::Http(client), &device, 3)
+            .await
+            .unwrap();
+        assert_eq!(sampled.readings["co2"], vec![450.0, 520.0]);
+        assert_eq!(sampled.latest.sensors["co2"].value, 520.0);
+    }
+
+    #[tokio::test]
+    async fn test_scrape_once() {
+        let mock_server = MockServer::start().await;
+        mount_co2(&mock_server).await;
+        let metrics = Metrics::new(MetricsOptions::default()).unwrap();
+        let options = ClientOptions::new(Duration::from_secs(5));
+
+        let devices = vec![
+            test_device(mock_server.uri()),
+            test_device("http://127.0.0.1:1".to_string()),
+        ];
+        let output = scrape_once(&devices, Protocol::Http, None, &options, &metrics)
+            .await
+            .unwrap();
+        assert!(output.contains("apollo_air1_co2_ppm{"));
+        assert!(output.contains("apollo_air1_devices_up 1\n"));
+
+        let devices = vec![test_device("http://127.0.0.1:1".to_string())];
+        let error = scrape_once(&devices, Protocol::Http, None, &options, &metrics)
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "No device responded");
+    }
+
+    #[tokio::test]
+    async fn test_probe_handler_rejects_bad_target() {
+        let (status, _) = get_response(create_test_app(), "/probe").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, _) = get_response(create_test_app(), "/probe?target=not-a-url").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, _) = get_response(create_test_app(), "/probe?target=ftp://device").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_debug_devices_handler() {
+        let state = test_state(None);
+        let mut sensors = HashMap::new();
+        sensors.insert(
+            "pm__10_m_weight_concentration".to_string(),
+            apollo::SensorValue {
+                value: 12.5,
+                unit: "µg/m³".to_string(),
+                name: "PM10".to_string(),
+            },
+        );
+        state.snapshots.write().await.insert(
+            "http://192.168.1.100".to_string(),
+            Snapshot::new(ApolloStatus {
+                sensors,
+                device_name: "Office".to_string(),
+                info: None,
+            }),
+        );
+
+        let (status, body) = get_response(router(state), "/debug/devices").await;
+        assert_eq!(status, StatusCode::OK);
+
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let device = &json["http://192.168.1.100"];
+        assert_eq!(device["device_name"], "Office");
+        let pm10 = &device["sensors"]["pm__10_m_weight_concentration"];
+        assert_eq!(pm10["value"], 12.5);
+        assert_eq!(pm10["unit"], "µg/m³");
+        assert_eq!(pm10["name"], "PM10");
+
+        // Protected by the metrics token like /metrics
+        let (status, _) =
+            get_response(create_test_app_with_token(Some("secret")), "/debug/devices").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_homeassistant_handler() {
+        let (status, _) = get_response(create_test_app(), "/homeassistant").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let mut state = test_state(None);
+        state.enable_ha_json = true;
+        let mut sensors = HashMap::new();
+        sensors.insert(
+            "co2".to_string(),
+            apollo::SensorValue {
+                value: 612.0,
+                unit: "ppm".to_string(),
+                name: "CO2".to_string(),
+            },
+        );
+        state.snapshots.write().await.insert(
+            "http://192.168.1.100".to_string(),
+            Snapshot::new(ApolloStatus {
+                sensors,
+                device_name: "Office".to_string(),
+                info: None,
+            }),
+        );
+
+        let (status, body) = get_response(router(state), "/homeassistant").await;
+        assert_eq!(status, StatusCode::OK);
+
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let co2 = &json["sensor.office_co2"];
+        assert_eq!(co2["state"], "612");
+        assert_eq!(co2["attributes"]["unit_of_measurement"], "ppm");
+        assert_eq!(co2["attributes"]["friendly_name"], "Office CO2");
+    }
+
+    #[tokio::test]
+    async fn test_json_handler() {
+        let (status, _) = get_response(create_test_app(), "/json").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let mut state = test_state(None);
+        state.enable_json_api = true;
+        let mut sensors = HashMap::new();
+        sensors.insert(
+            "co2".to_string(),
+            apollo::SensorValue {
+                value: 612.0,
+                unit: "ppm".to_string(),
+                name: "CO2".to_string(),
+            },
+        );
+        state.snapshots.write().await.insert(
+            "http://192.168.1.100".to_string(),
+            Snapshot::new(ApolloStatus {
+                sensors,
+                device_name: "Office".to_string(),
+                info: None,
+            }),
+        );
+
+        let (status, body) = get_response(router(state), "/json").await;
+        assert_eq!(status, StatusCode::OK);
+
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let rows = json.as_array().unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0]["device"], "Office");
+        assert_eq!(rows[0]["host"], "http://192.168.1.100");
+        assert_eq!(rows[0]["metric"], "co2");
+        assert_eq!(rows[0]["value"], 612.0);
+        assert!(rows[0]["timestamp"].as_u64().unwrap() > 0);
+
+        // Protected by the metrics token like /metrics
+        let mut state = test_state(Some("secret"));
+        state.enable_json_api = true;
+        let (status, _) = get_response(router(state), "/json").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_config_handler() {
+        let state = test_state(None);
+        *state.config.write().await = serde_json::json!({"config": {"poll_interval": 30}});
+
+        let (status, body) = get_response(router(state), "/config").await;
+        assert_eq!(status, StatusCode::OK);
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["config"]["poll_interval"], 30);
+
+        let (status, _) = get_response(create_test_app_with_token(Some("secret")), "/config").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_ready_handler() {
+        let (status, body) = get_response(create_test_app(), "/ready").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "OK");
+
+        let state = test_state(None);
+        state.ready.store(false, Ordering::Relaxed);
+        let (status, _) = get_response(router(state), "/ready").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
 
-        Router::new()
-            .route("/metrics", get(metrics_handler))
-            .route("/health", get(health_handler))
-            .route("/", get(root_handler))
-            .with_state(shared_metrics)
+        // Liveness doesn't depend on readiness
+        let state = test_state(None);
+        state.ready.store(false, Ordering::Relaxed);
+        let (status, _) = get_response(router(state), "/health").await;
+        assert_eq!(status, StatusCode::OK);
     }
 
     #[tokio::test]
@@ -213,6 +2385,67 @@ mod tests {
         assert!(body_str.contains("/health"));
     }
 
+    #[tokio::test]
+    async fn test_root_handler_device_status() {
+        let state = test_state(None);
+        let mut sensors = HashMap::new();
+        sensors.insert(
+            "co2".to_string(),
+            apollo::SensorValue {
+                value: 612.0,
+                unit: "ppm".to_string(),
+                name: "CO2".to_string(),
+            },
+        );
+        let status = ApolloStatus {
+            sensors,
+            device_name: "Office".to_string(),
+            info: None,
+        };
+        state
+            .device_metrics
+            .update_device("http://192.168.1.100", &status)
+            .unwrap();
+        state
+            .snapshots
+            .write()
+            .await
+            .insert("http://192.168.1.100".to_string(), Snapshot::new(status));
+
+        let (_, body) = get_response(router(state.clone()), "/").await;
+        assert!(body.contains("Office (http://192.168.1.100) - up, last scrape "));
+        assert!(body.contains("    CO2 612 ppm\n"));
+
+        // Readings are only shown with the metrics token, if one is set
+        let state = AppState {
+            metrics_token: Some(Arc::from("secret")),
+            ..state
+        };
+        let (status, body) = get_response(router(state.clone()), "/").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(!body.contains("Office"));
+        assert!(body.contains("Device status requires the metrics token"));
+
+        let response = router(state)
+            .oneshot(
+                Request::builder()
+                    .uri("/")
+                    .header(header::AUTHORIZATION, "Bearer secret")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(
+            String::from_utf8(body.to_vec())
+                .unwrap()
+                .contains("CO2 612 ppm")
+        );
+    }
+
     #[tokio::test]
     async fn test_metrics_handler() {
         let app = create_test_app();
@@ -228,6 +2461,10 @@ mod tests {
             .unwrap();
 
         assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/plain; version=0.0.4; charset=utf-8"
+        );
 
         let body = axum::body::to_bytes(response.into_body(), usize::MAX)
             .await
@@ -236,4 +2473,20 @@ mod tests {
         assert!(body_str.contains("apollo_air1_device_up"));
         assert!(body_str.contains("test"));
     }
+
+    #[tokio::test]
+    async fn test_custom_metrics_path() {
+        let mut state = test_state(None);
+        state.metrics_path = Arc::from("/apollo/metrics");
+
+        let (status, body) = get_response(router(state.clone()), "/apollo/metrics").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("apollo_air1_device_up"));
+
+        let (status, _) = get_response(router(state.clone()), "/metrics").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (_, body) = get_response(router(state), "/").await;
+        assert!(body.contains("/apollo/metrics - Prometheus metrics"));
+    }
 }
@@ -1,14 +1,25 @@
-use anyhow::Result;
+use anyhow::{Result, bail};
+use prometheus::core::Collector;
 use prometheus::{
-    Encoder, GaugeVec, IntGaugeVec, Registry, TextEncoder, register_gauge_vec,
-    register_int_gauge_vec,
+    Encoder, Gauge, GaugeVec, IntCounter, IntCounterVec, IntGaugeVec, Registry, TextEncoder,
+    register_gauge_vec_with_registry, register_gauge_with_registry,
+    register_int_counter_vec_with_registry, register_int_counter_with_registry,
+    register_int_gauge_vec_with_registry,
 };
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::sync::RwLock;
-use tracing::{debug, error};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug, error, warn};
 
-use crate::apollo::ApolloStatus;
-use crate::aqi::{self, AqiCategory};
+use crate::apollo::{ApolloStatus, CO2_SENSOR, DeviceInfo, PROBLEM_SENSOR, SensorValue};
+use crate::aqi::{
+    self, AqhiCategory, AqiCategory, CaqiCategory, ExponentialAverage, NowCastWindow,
+};
+use crate::config::{AirQualityStandard, AqiBreakpoints, AqiMode, TemperatureUnit};
+use crate::derived;
+use crate::openmetrics::OpenMetricsEncoder;
 
 /// Tracks previous AQI state for a device to enable cleanup of stale metrics
 #[derive(Clone, Debug)]
@@ -17,11 +28,215 @@ struct AqiState {
     primary_pollutant: String,
 }
 
+/// Settings that change which metrics are exported and how values are reported
+#[derive(Debug, Clone, Default)]
+pub struct MetricsOptions {
+    pub temperature_unit: TemperatureUnit,
+    pub aqi_mode: AqiMode,
+    pub aqi_primary_policy: aqi::PrimaryPolicy,
+    pub aqi_breakpoints: AqiBreakpoints,
+    pub air_quality_standard: AirQualityStandard,
+    /// Export the CO2-aware indoor air quality score
+    pub enable_iaq: bool,
+    /// Leave the air quality indices alone while PM2.5 is missing
+    pub aqi_require_pm25: bool,
+    /// Remove a device's sensor series while it is down
+    pub clear_on_down: bool,
+    /// Weight of the newest PM reading in the moving average the indices
+    /// are calculated from, if smoothing is enabled
+    pub aqi_smoothing: Option<f64>,
+    /// Maps device sensor ids to the canonical ids handled below
+    pub sensor_aliases: HashMap<String, String>,
+    /// Sensor ids the CO2 reading may be reported under, in order of preference
+    pub co2_sensor_ids: Vec<String>,
+    /// Metric names without the `apollo_air1_` prefix that are not exported
+    pub disabled_metrics: HashSet<String>,
+    /// Export the exporter's own `process_*` metrics (Linux only)
+    pub process_metrics: bool,
+    /// Decimal places sensor readings are rounded to, if rounding is enabled
+    pub round_decimals: Option<u32>,
+    /// Altitude of the devices, needed to reduce pressure to sea level
+    pub altitude_meters: Option<f64>,
+    /// How long a sensor may report the same value before it is flagged
+    /// stale, if watched
+    pub sensor_stale_after: Option<Duration>,
+}
+
+/// Prefix of every metric name, omitted when disabling metrics
+const METRIC_PREFIX: &str = "apollo_air1_";
+
+/// Registers metrics into the exported registry unless they are disabled,
+/// remembering every metric name so typos in the disabled list are caught
+struct MetricRegistry<'a> {
+    registry: Registry,
+    disabled: &'a HashSet<String>,
+    known: RefCell<HashSet<String>>,
+}
+
+impl<'a> MetricRegistry<'a> {
+    fn new(disabled: &'a HashSet<String>) -> Self {
+        Self {
+            registry: Registry::new(),
+            disabled,
+            known: RefCell::default(),
+        }
+    }
+
+    /// Called by the `register_*_with_registry!` macros
+    fn register(&self, collector: Box<dyn Collector>) -> prometheus::Result<()> {
+        if self.note_names(collector.as_ref()) {
+            return Ok(());
+        }
+        self.registry.register(collector)
+    }
+
+    /// Remembers the collector's metric names, returning whether any of
+    /// them is disabled
+    fn note_names(&self, collector: &dyn Collector) -> bool {
+        let names: Vec<String> = collector
+            .desc()
+            .iter()
+            .map(|desc| {
+                let name = desc.fq_name.as_str();
+                name.strip_prefix(METRIC_PREFIX).unwrap_or(name).to_string()
+            })
+            .collect();
+        let disabled = names.iter().any(|name| self.disabled.contains(name));
+        self.known.borrow_mut().extend(names);
+        disabled
+    }
+
+    /// Returns the registry, failing if a disabled name matched no metric
+    fn finish(self) -> Result<Registry> {
+        let known = self.known.into_inner();
+        let mut unknown: Vec<&str> = self
+            .disabled
+            .iter()
+            .filter(|name| !known.contains(*name))
+            .map(String::as_str)
+            .collect();
+
+        if !unknown.is_empty() {
+            unknown.sort_unstable();
+            let mut valid: Vec<String> = known.into_iter().collect();
+            valid.sort_unstable();
+            bail!(
+                "Unknown metrics to disable: {} (valid names: {})",
+                unknown.join(", "),
+                valid.join(", ")
+            );
+        }
+
+        Ok(self.registry)
+    }
+}
+
+/// PM history of one device, saved so the NowCast and smoothing continue
+/// across restarts
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SavedPmHistory {
+    pub device: String,
+    pub host: String,
+    #[serde(flatten)]
+    history: PmHistory,
+}
+
+/// Min, max and mean gauges of a sensor over the samples of a poll interval
+struct SampleGauges {
+    min: GaugeVec,
+    max: GaugeVec,
+    avg: GaugeVec,
+}
+
+impl SampleGauges {
+    fn register(registry: &MetricRegistry, name: &str, what: &str) -> Result<Self> {
+        let register = |suffix: &str, stat: &str| {
+            register_gauge_vec_with_registry!(
+                format!("{}_{}", name, suffix),
+                format!(
+                    "{} {} over the samples of the last poll interval",
+                    stat, what
+                ),
+                &["device", "host", "location"],
+                registry
+            )
+        };
+
+        Ok(Self {
+            min: register("min", "Minimum")?,
+            max: register("max", "Maximum")?,
+            avg: register("avg", "Average")?,
+        })
+    }
+
+    /// Sets the gauges from the finite values, if there are any
+    fn set(&self, device: &str, host: &str, location: &str, values: &[f64]) {
+        let values: Vec<f64> = values.iter().copied().filter(|v| v.is_finite()).collect();
+        if values.is_empty() {
+            return;
+        }
+
+        let labels = [device, host, location];
+        let min = values.iter().copied().fold(f64::INFINITY, f64::min);
+        let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        let avg = values.iter().sum::<f64>() / values.len() as f64;
+        self.min.with_label_values(&labels).set(min);
+        self.max.with_label_values(&labels).set(max);
+        self.avg.with_label_values(&labels).set(avg);
+    }
+
+    fn gauges(&self) -> [&GaugeVec; 3] {
+        [&self.min, &self.max, &self.avg]
+    }
+}
+
+/// A device's reachability as currently exported, for the status page
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceOverview {
+    pub device: String,
+    pub host: String,
+    pub up: bool,
+    /// Unix time of the last successful scrape, if there was one
+    pub last_scrape: Option<f64>,
+}
+
+/// Last value of a sensor watched for staleness
+#[derive(Debug)]
+struct SensorChange {
+    value: f64,
+    /// When the value last changed, or when watching started
+    changed_at: Instant,
+}
+
+/// Staleness tracking for a device watched by the watchdog
+#[derive(Debug)]
+struct Liveness {
+    /// Last successful update, or when watching started
+    last_success: Instant,
+    /// How long updates may be missing before the device is marked down
+    stale_after: Duration,
+    /// Whether the device has already been marked down as stale
+    marked_down: bool,
+}
+
+/// Rolling PM concentration history for the NowCast AQI and smoothing
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct PmHistory {
+    pm25: NowCastWindow,
+    pm10: NowCastWindow,
+    pm25_average: ExponentialAverage,
+    pm10_average: ExponentialAverage,
+}
+
 pub struct Metrics {
     registry: Registry,
+    options: MetricsOptions,
 
     // Device status
     device_up: IntGaugeVec,
+    sensors_found: IntGaugeVec,
+    device_connected: IntGaugeVec,
 
     // Air quality metrics
     co2_ppm: GaugeVec,
@@ -30,177 +245,608 @@ pub struct Metrics {
     pm10_0_ugm3: GaugeVec,
     voc_index: GaugeVec,
     nox_index: GaugeVec,
+    voc_raw: GaugeVec,
+    nox_raw: GaugeVec,
+
+    // Aggregates over several samples per poll interval
+    co2_ppm_samples: SampleGauges,
+    pm2_5_ugm3_samples: SampleGauges,
 
     // Environmental metrics
-    temperature_celsius: GaugeVec,
+    temperature: GaugeVec,
     humidity_percent: GaugeVec,
     pressure_hpa: GaugeVec,
     illuminance_lux: GaugeVec,
+    dew_point_celsius: GaugeVec,
+    absolute_humidity_gm3: GaugeVec,
+    sea_level_pressure_hpa: GaugeVec,
 
     // Device metrics
-    esp_temperature_celsius: GaugeVec,
+    esp_temperature: GaugeVec,
     wifi_rssi_dbm: IntGaugeVec,
+    uptime_seconds: GaugeVec,
+    device_reboots_total: IntCounterVec,
+    sensor_problem: IntGaugeVec,
+    co2_self_calibration: IntGaugeVec,
+
+    // Sensors whose value stopped changing, labeled by sensor id
+    sensor_stale: IntGaugeVec,
+
+    // Sensors without a dedicated metric, labeled by sensor id
+    other_sensor: GaugeVec,
+    sensor_unit_info: GaugeVec, // Info metric with the reported unit label
 
     // Air Quality Index - restructured for proper Prometheus semantics
-    aqi: GaugeVec,                    // Overall AQI value (device, host only)
-    aqi_pm25: GaugeVec,               // PM2.5 sub-AQI
-    aqi_pm10: GaugeVec,               // PM10 sub-AQI
-    aqi_info: GaugeVec,               // Info metric with category/pollutant labels
+    aqi: GaugeVec,             // Overall AQI value (device, host only)
+    aqi_pm25: GaugeVec,        // PM2.5 sub-AQI
+    aqi_pm10: GaugeVec,        // PM10 sub-AQI
+    aqi_info: GaugeVec,        // Info metric with category/pollutant labels
+    aqi_category: GaugeVec,    // Category as a number (0 = Good ... 5 = Hazardous)
+    aqi_advice_info: GaugeVec, // Info metric with the EPA health message label
+
+    // Canadian Air Quality Health Index
+    aqhi: GaugeVec,
+    aqhi_info: GaugeVec, // Info metric with category label
+
+    // European Common Air Quality Index
+    caqi: GaugeVec,
+    caqi_pm25: GaugeVec,
+    caqi_pm10: GaugeVec,
+    caqi_info: GaugeVec, // Info metric with category/pollutant labels
+
+    // Indoor air quality score combining CO2 and PM
+    iaq_score: GaugeVec,
+
+    // Firmware info metric
+    device_info: GaugeVec,
+
+    // Exporter metrics
+    scrape_duration_seconds: GaugeVec,
+    scrape_errors_total: IntCounterVec,
+    device_last_error: GaugeVec,
+    last_scrape_timestamp_seconds: GaugeVec,
+    poll_cycle_duration_seconds: GaugeVec,
+    poll_cycles_total: IntCounterVec,
+    devices_total: Gauge,
+    devices_up: Gauge,
+    poll_interval_seconds: Gauge,
+    scrape_generation_total: IntCounter,
 
     // State tracking for cleaning up stale AQI info metrics
     previous_aqi_state: RwLock<HashMap<(String, String), AqiState>>,
+    previous_aqhi_category: RwLock<HashMap<(String, String), AqhiCategory>>,
+    previous_caqi_state: RwLock<HashMap<(String, String), (CaqiCategory, String)>>,
+
+    // State tracking for cleaning up stale device info metrics
+    previous_device_info: RwLock<HashMap<(String, String), DeviceInfo>>,
+
+    // Last reported uptime per device, for detecting reboots
+    previous_uptime: RwLock<HashMap<(String, String), f64>>,
+
+    // Reason of the current failure per device, for replacing the last error series
+    last_error_reasons: RwLock<HashMap<(String, String), String>>,
+
+    // Per-device PM history for NowCast AQI
+    pm_history: RwLock<HashMap<(String, String), PmHistory>>,
+
+    // Ids exported through the generic sensor metric, per device
+    other_sensors: RwLock<HashMap<(String, String), HashSet<String>>>,
+
+    // Reported unit per sensor id, per device, for cleaning up stale unit labels
+    previous_sensor_units: RwLock<HashMap<(String, String), HashMap<String, String>>>,
+
+    // Devices watched for missing updates
+    liveness: RwLock<HashMap<(String, String), Liveness>>,
+
+    // Last value per sensor id, per device, and when it last changed
+    sensor_changes: RwLock<HashMap<(String, String), HashMap<String, SensorChange>>>,
+
+    // Static location label per host
+    device_locations: RwLock<HashMap<String, String>>,
 }
 
 impl Metrics {
-    pub fn new() -> Result<Self> {
-        let registry = Registry::new();
+    pub fn new(options: MetricsOptions) -> Result<Self> {
+        let registry = MetricRegistry::new(&options.disabled_metrics);
 
-        let device_up = register_int_gauge_vec!(
+        let device_up = register_int_gauge_vec_with_registry!(
             "apollo_air1_device_up",
             "Whether the Apollo Air-1 device is reachable (1) or not (0)",
-            &["device", "host"]
+            &["device", "host", "location"],
+            registry
+        )?;
+
+        let sensors_found = register_int_gauge_vec_with_registry!(
+            "apollo_air1_sensors_found",
+            "Number of sensors the device reported in its last update",
+            &["device", "host", "location"],
+            registry
+        )?;
+
+        let device_connected = register_int_gauge_vec_with_registry!(
+            "apollo_air1_device_connected",
+            "Whether the device's event stream or MQTT subscription is connected (1) or not (0)",
+            &["device", "host", "location"],
+            registry
         )?;
-        registry.register(Box::new(device_up.clone()))?;
 
         // Air Quality Metrics
-        let co2_ppm = register_gauge_vec!(
+        let co2_ppm = register_gauge_vec_with_registry!(
+            "apollo_air1_co2_ppm",
+            "CO2 concentration in parts per million",
+            &["device", "host", "location"],
+            registry
+        )?;
+
+        let co2_ppm_samples = SampleGauges::register(
+            &registry,
             "apollo_air1_co2_ppm",
             "CO2 concentration in parts per million",
-            &["device", "host"]
         )?;
-        registry.register(Box::new(co2_ppm.clone()))?;
 
-        let pm1_0_ugm3 = register_gauge_vec!(
+        let pm1_0_ugm3 = register_gauge_vec_with_registry!(
             "apollo_air1_pm1_0_ugm3",
             "PM1.0 particulate matter in micrograms per cubic meter",
-            &["device", "host"]
+            &["device", "host", "location"],
+            registry
+        )?;
+
+        let pm2_5_ugm3 = register_gauge_vec_with_registry!(
+            "apollo_air1_pm2_5_ugm3",
+            "PM2.5 particulate matter in micrograms per cubic meter",
+            &["device", "host", "location"],
+            registry
         )?;
-        registry.register(Box::new(pm1_0_ugm3.clone()))?;
 
-        let pm2_5_ugm3 = register_gauge_vec!(
+        let pm2_5_ugm3_samples = SampleGauges::register(
+            &registry,
             "apollo_air1_pm2_5_ugm3",
             "PM2.5 particulate matter in micrograms per cubic meter",
-            &["device", "host"]
         )?;
-        registry.register(Box::new(pm2_5_ugm3.clone()))?;
 
-        let pm10_0_ugm3 = register_gauge_vec!(
+        let pm10_0_ugm3 = register_gauge_vec_with_registry!(
             "apollo_air1_pm10_0_ugm3",
             "PM10 particulate matter in micrograms per cubic meter",
-            &["device", "host"]
+            &["device", "host", "location"],
+            registry
         )?;
-        registry.register(Box::new(pm10_0_ugm3.clone()))?;
 
-        let voc_index = register_gauge_vec!(
+        let voc_index = register_gauge_vec_with_registry!(
             "apollo_air1_voc_index",
             "Volatile Organic Compounds index",
-            &["device", "host"]
+            &["device", "host", "location"],
+            registry
         )?;
-        registry.register(Box::new(voc_index.clone()))?;
 
-        let nox_index = register_gauge_vec!(
+        let nox_index = register_gauge_vec_with_registry!(
             "apollo_air1_nox_index",
             "Nitrogen Oxides index",
-            &["device", "host"]
+            &["device", "host", "location"],
+            registry
+        )?;
+
+        let voc_raw = register_gauge_vec_with_registry!(
+            "apollo_air1_voc_raw",
+            "Raw VOC signal of the SEN55 gas sensor, before the index algorithm",
+            &["device", "host", "location"],
+            registry
+        )?;
+
+        let nox_raw = register_gauge_vec_with_registry!(
+            "apollo_air1_nox_raw",
+            "Raw NOx signal of the SEN55 gas sensor, before the index algorithm",
+            &["device", "host", "location"],
+            registry
         )?;
-        registry.register(Box::new(nox_index.clone()))?;
 
         // Environmental Metrics
-        let temperature_celsius = register_gauge_vec!(
-            "apollo_air1_temperature_celsius",
-            "Temperature in degrees Celsius",
-            &["device", "host"]
+        let unit = options.temperature_unit;
+        let temperature = register_gauge_vec_with_registry!(
+            format!("apollo_air1_temperature_{}", unit.metric_suffix()),
+            format!("Temperature in degrees {}", unit.display_name()),
+            &["device", "host", "location"],
+            registry
         )?;
-        registry.register(Box::new(temperature_celsius.clone()))?;
 
-        let humidity_percent = register_gauge_vec!(
+        let humidity_percent = register_gauge_vec_with_registry!(
             "apollo_air1_humidity_percent",
             "Relative humidity percentage",
-            &["device", "host"]
+            &["device", "host", "location"],
+            registry
         )?;
-        registry.register(Box::new(humidity_percent.clone()))?;
 
-        let pressure_hpa = register_gauge_vec!(
+        let pressure_hpa = register_gauge_vec_with_registry!(
             "apollo_air1_pressure_hpa",
             "Atmospheric pressure in hectopascals",
-            &["device", "host"]
+            &["device", "host", "location"],
+            registry
         )?;
-        registry.register(Box::new(pressure_hpa.clone()))?;
 
-        let illuminance_lux = register_gauge_vec!(
+        let illuminance_lux = register_gauge_vec_with_registry!(
             "apollo_air1_illuminance_lux",
             "Illuminance in lux",
-            &["device", "host"]
+            &["device", "host", "location"],
+            registry
+        )?;
+
+        let dew_point_celsius = register_gauge_vec_with_registry!(
+            "apollo_air1_dew_point_celsius",
+            "Dew point in degrees Celsius, derived from temperature and humidity",
+            &["device", "host", "location"],
+            registry
+        )?;
+
+        let absolute_humidity_gm3 = register_gauge_vec_with_registry!(
+            "apollo_air1_absolute_humidity_gm3",
+            "Absolute humidity in grams per cubic meter, derived from temperature and humidity",
+            &["device", "host", "location"],
+            registry
+        )?;
+
+        let sea_level_pressure_hpa = register_gauge_vec_with_registry!(
+            "apollo_air1_sea_level_pressure_hpa",
+            "Atmospheric pressure reduced to sea level in hectopascals, derived from pressure, temperature and the configured altitude",
+            &["device", "host", "location"],
+            registry
         )?;
-        registry.register(Box::new(illuminance_lux.clone()))?;
 
         // Device Metrics
-        let esp_temperature_celsius = register_gauge_vec!(
-            "apollo_air1_esp_temperature_celsius",
-            "ESP32 internal temperature in degrees Celsius",
-            &["device", "host"]
+        let esp_temperature = register_gauge_vec_with_registry!(
+            format!("apollo_air1_esp_temperature_{}", unit.metric_suffix()),
+            format!(
+                "ESP32 internal temperature in degrees {}",
+                unit.display_name()
+            ),
+            &["device", "host", "location"],
+            registry
         )?;
-        registry.register(Box::new(esp_temperature_celsius.clone()))?;
 
-        let wifi_rssi_dbm = register_int_gauge_vec!(
+        let wifi_rssi_dbm = register_int_gauge_vec_with_registry!(
             "apollo_air1_wifi_rssi_dbm",
             "WiFi signal strength in dBm",
-            &["device", "host"]
+            &["device", "host", "location"],
+            registry
+        )?;
+
+        let uptime_seconds = register_gauge_vec_with_registry!(
+            "apollo_air1_uptime_seconds",
+            "Time since the device last booted in seconds",
+            &["device", "host", "location"],
+            registry
+        )?;
+
+        let device_reboots_total = register_int_counter_vec_with_registry!(
+            "apollo_air1_device_reboots_total",
+            "Number of device reboots detected from its uptime going down",
+            &["device", "host", "location"],
+            registry
+        )?;
+
+        let sensor_problem = register_int_gauge_vec_with_registry!(
+            "apollo_air1_sensor_problem",
+            "Whether the device reports a sensor problem (1 = problem, 0 = ok)",
+            &["device", "host", "location"],
+            registry
+        )?;
+
+        let co2_self_calibration = register_int_gauge_vec_with_registry!(
+            "apollo_air1_co2_self_calibration_enabled",
+            "Whether the CO2 sensor's automatic self-calibration is on (1 = on, 0 = off)",
+            &["device", "host", "location"],
+            registry
+        )?;
+
+        let sensor_stale = register_int_gauge_vec_with_registry!(
+            "apollo_air1_sensor_stale",
+            "Whether a sensor has reported the same value for longer than the configured limit (1 = stale)",
+            &["device", "host", "location", "sensor"],
+            registry
+        )?;
+
+        let other_sensor = register_gauge_vec_with_registry!(
+            "apollo_air1_sensor",
+            "Reading of a sensor without a dedicated metric",
+            &["device", "host", "location", "id"],
+            registry
+        )?;
+
+        let sensor_unit_info = register_gauge_vec_with_registry!(
+            "apollo_air1_sensor_unit_info",
+            "Unit reported by the device for a sensor (value always 1, use labels for unit)",
+            &["device", "host", "location", "sensor", "unit"],
+            registry
         )?;
-        registry.register(Box::new(wifi_rssi_dbm.clone()))?;
 
         // Air Quality Index - Overall value
-        let aqi = register_gauge_vec!(
+        let aqi = register_gauge_vec_with_registry!(
             "apollo_air1_aqi",
             "Air Quality Index based on PM2.5 and PM10",
-            &["device", "host"]
+            &["device", "host", "location"],
+            registry
         )?;
-        registry.register(Box::new(aqi.clone()))?;
 
         // Air Quality Index - PM2.5 sub-index
-        let aqi_pm25 = register_gauge_vec!(
+        let aqi_pm25 = register_gauge_vec_with_registry!(
             "apollo_air1_aqi_pm25",
             "Air Quality Index for PM2.5",
-            &["device", "host"]
+            &["device", "host", "location"],
+            registry
         )?;
-        registry.register(Box::new(aqi_pm25.clone()))?;
 
         // Air Quality Index - PM10 sub-index
-        let aqi_pm10 = register_gauge_vec!(
+        let aqi_pm10 = register_gauge_vec_with_registry!(
             "apollo_air1_aqi_pm10",
             "Air Quality Index for PM10",
-            &["device", "host"]
+            &["device", "host", "location"],
+            registry
         )?;
-        registry.register(Box::new(aqi_pm10.clone()))?;
 
         // Air Quality Index - Info metric with category labels
-        let aqi_info = register_gauge_vec!(
+        let aqi_info = register_gauge_vec_with_registry!(
             "apollo_air1_aqi_info",
             "AQI category information (value always 1, use labels for category)",
-            &["device", "host", "category", "primary_pollutant"]
+            &[
+                "device",
+                "host",
+                "location",
+                "category",
+                "primary_pollutant",
+                "color"
+            ],
+            registry
+        )?;
+
+        // Air Quality Index - Category as a stable numeric series
+        let aqi_category = register_gauge_vec_with_registry!(
+            "apollo_air1_aqi_category",
+            "AQI category (0=Good, 1=Moderate, 2=Unhealthy for Sensitive Groups, 3=Unhealthy, 4=Very Unhealthy, 5=Hazardous)",
+            &["device", "host", "location"],
+            registry
+        )?;
+
+        // Air Quality Index - Info metric with the health message for the category
+        let aqi_advice_info = register_gauge_vec_with_registry!(
+            "apollo_air1_aqi_advice_info",
+            "EPA health message for the AQI category (value always 1, use labels for message)",
+            &["device", "host", "location", "category", "message"],
+            registry
+        )?;
+
+        let aqhi = register_gauge_vec_with_registry!(
+            "apollo_air1_aqhi",
+            "Canadian Air Quality Health Index (PM2.5 only)",
+            &["device", "host", "location"],
+            registry
+        )?;
+
+        let aqhi_info = register_gauge_vec_with_registry!(
+            "apollo_air1_aqhi_info",
+            "AQHI risk category information (value always 1, use labels for category)",
+            &["device", "host", "location", "category"],
+            registry
+        )?;
+
+        let caqi = register_gauge_vec_with_registry!(
+            "apollo_air1_caqi",
+            "European Common Air Quality Index (hourly) based on PM2.5 and PM10",
+            &["device", "host", "location"],
+            registry
+        )?;
+
+        let caqi_pm25 = register_gauge_vec_with_registry!(
+            "apollo_air1_caqi_pm25",
+            "European Common Air Quality Index for PM2.5",
+            &["device", "host", "location"],
+            registry
+        )?;
+
+        let caqi_pm10 = register_gauge_vec_with_registry!(
+            "apollo_air1_caqi_pm10",
+            "European Common Air Quality Index for PM10",
+            &["device", "host", "location"],
+            registry
+        )?;
+
+        let caqi_info = register_gauge_vec_with_registry!(
+            "apollo_air1_caqi_info",
+            "CAQI category information (value always 1, use labels for category)",
+            &[
+                "device",
+                "host",
+                "location",
+                "category",
+                "primary_pollutant"
+            ],
+            registry
+        )?;
+
+        let iaq_score = register_gauge_vec_with_registry!(
+            "apollo_air1_iaq_score",
+            "Indoor air quality score on the AQI scale, the worst of CO2 and PM sub-indices",
+            &["device", "host", "location"],
+            registry
+        )?;
+
+        // Device info metric with firmware labels
+        let device_info = register_gauge_vec_with_registry!(
+            "apollo_air1_device_info",
+            "Device firmware information (value always 1, use labels for versions and IP address)",
+            &[
+                "device",
+                "host",
+                "location",
+                "esphome_version",
+                "project_name",
+                "project_version",
+                "ip_address"
+            ],
+            registry
+        )?;
+
+        // Exporter Metrics
+        let scrape_duration_seconds = register_gauge_vec_with_registry!(
+            "apollo_air1_scrape_duration_seconds",
+            "Duration of the last status fetch from the device in seconds",
+            &["device", "host", "location"],
+            registry
+        )?;
+
+        let scrape_errors_total = register_int_counter_vec_with_registry!(
+            "apollo_air1_scrape_errors_total",
+            "Total number of failed status fetches from the device",
+            &["device", "host", "location"],
+            registry
+        )?;
+
+        let device_last_error = register_gauge_vec_with_registry!(
+            "apollo_air1_device_last_error",
+            "Why the last status fetch from a failing device failed (always 1, the reason is in the label)",
+            &["device", "host", "location", "reason"],
+            registry
+        )?;
+
+        let last_scrape_timestamp_seconds = register_gauge_vec_with_registry!(
+            "apollo_air1_last_scrape_timestamp_seconds",
+            "Unix timestamp of the last successful scrape of the device",
+            &["device", "host", "location"],
+            registry
+        )?;
+
+        let poll_cycle_duration_seconds = register_gauge_vec_with_registry!(
+            "apollo_air1_poll_cycle_duration_seconds",
+            "Duration of the last full poll cycle of the device (fetch, update, render and push) in seconds",
+            &["device", "host", "location"],
+            registry
+        )?;
+
+        let poll_cycles_total = register_int_counter_vec_with_registry!(
+            "apollo_air1_poll_cycles_total",
+            "Total number of completed poll cycles of the device",
+            &["device", "host", "location"],
+            registry
+        )?;
+
+        let devices_total = register_gauge_with_registry!(
+            "apollo_air1_devices_total",
+            "Number of configured devices",
+            registry
         )?;
-        registry.register(Box::new(aqi_info.clone()))?;
+
+        let devices_up = register_gauge_with_registry!(
+            "apollo_air1_devices_up",
+            "Number of devices whose last scrape succeeded",
+            registry
+        )?;
+
+        let poll_interval_seconds = register_gauge_with_registry!(
+            "apollo_air1_poll_interval_seconds",
+            "Configured default poll interval in seconds",
+            registry
+        )?;
+
+        let scrape_generation_total = register_int_counter_with_registry!(
+            "apollo_air1_scrape_generation_total",
+            "Number of times the metrics served on /metrics have been refreshed",
+            registry
+        )?;
+
+        // Static series describing this build, set once
+        let build_info = register_gauge_vec_with_registry!(
+            "apollo_air1_build_info",
+            "Exporter build information (value always 1, use labels for details)",
+            &["version", "rustc", "git_sha"],
+            registry
+        )?;
+        build_info
+            .with_label_values(&[
+                env!("CARGO_PKG_VERSION"),
+                env!("APOLLO_RUSTC_VERSION"),
+                env!("APOLLO_GIT_SHA"),
+            ])
+            .set(1.0);
+
+        // Resident memory, CPU time, open file descriptors and the like.
+        // Known even when not exported, so disabling them is never a typo.
+        #[cfg(target_os = "linux")]
+        {
+            let collector = prometheus::process_collector::ProcessCollector::for_self();
+            if options.process_metrics {
+                registry.register(Box::new(collector))?;
+            } else {
+                registry.note_names(&collector);
+            }
+        }
+
+        let registry = registry.finish()?;
 
         Ok(Self {
             registry,
+            options,
             device_up,
+            sensors_found,
+            device_connected,
             co2_ppm,
             pm1_0_ugm3,
             pm2_5_ugm3,
             pm10_0_ugm3,
             voc_index,
             nox_index,
-            temperature_celsius,
+            voc_raw,
+            nox_raw,
+            co2_ppm_samples,
+            pm2_5_ugm3_samples,
+            temperature,
             humidity_percent,
             pressure_hpa,
             illuminance_lux,
-            esp_temperature_celsius,
+            dew_point_celsius,
+            absolute_humidity_gm3,
+            sea_level_pressure_hpa,
+            esp_temperature,
             wifi_rssi_dbm,
+            uptime_seconds,
+            device_reboots_total,
+            sensor_problem,
+            co2_self_calibration,
+            sensor_stale,
+            other_sensor,
+            sensor_unit_info,
             aqi,
             aqi_pm25,
             aqi_pm10,
             aqi_info,
+            aqi_category,
+            aqi_advice_info,
+            aqhi,
+            aqhi_info,
+            caqi,
+            caqi_pm25,
+            caqi_pm10,
+            caqi_info,
+            iaq_score,
+            device_info,
+            scrape_duration_seconds,
+            scrape_errors_total,
+            device_last_error,
+            last_scrape_timestamp_seconds,
+            poll_cycle_duration_seconds,
+            poll_cycles_total,
+            devices_total,
+            devices_up,
+            poll_interval_seconds,
+            scrape_generation_total,
             previous_aqi_state: RwLock::new(HashMap::new()),
+            previous_aqhi_category: RwLock::new(HashMap::new()),
+            previous_caqi_state: RwLock::new(HashMap::new()),
+            previous_device_info: RwLock::new(HashMap::new()),
+            previous_uptime: RwLock::new(HashMap::new()),
+            last_error_reasons: RwLock::new(HashMap::new()),
+            pm_history: RwLock::new(HashMap::new()),
+            other_sensors: RwLock::new(HashMap::new()),
+            previous_sensor_units: RwLock::new(HashMap::new()),
+            liveness: RwLock::new(HashMap::new()),
+            sensor_changes: RwLock::new(HashMap::new()),
+            device_locations: RwLock::new(HashMap::new()),
         })
     }
 
@@ -210,96 +856,577 @@ impl Metrics {
             status.device_name, host
         );
 
+        let location = self.location(host);
+        let labels = [status.device_name.as_str(), host, location.as_str()];
+
         // Device is up
-        self.device_up
-            .with_label_values(&[status.device_name.as_str(), host])
-            .set(1);
+        self.device_up.with_label_values(&labels).set(1);
+        self.refresh_devices_up();
+        self.sensors_found
+            .with_label_values(&labels)
+            .set(status.sensors.len() as i64);
+        self.clear_last_error(&status.device_name, host, &location);
+        if let Some(liveness) = self
+            .liveness
+            .write()
+            .unwrap()
+            .get_mut(&(status.device_name.clone(), host.to_string()))
+        {
+            liveness.last_success = Instant::now();
+            liveness.marked_down = false;
+        }
 
         // Collect PM values for AQI calculation
         let mut pm25_value: Option<f64> = None;
         let mut pm10_value: Option<f64> = None;
 
+        let mut co2_value: Option<f64> = None;
+
+        // Collect climate values for derived metrics
+        let mut temperature_value: Option<f64> = None;
+        let mut humidity_value: Option<f64> = None;
+        let mut pressure_value: Option<f64> = None;
+
         // Update each available sensor
-        for (sensor_id, sensor_value) in &status.sensors {
-            match sensor_id.as_str() {
+        for (sensor_id, sensor_value) in self.resolve_sensors(&status.device_name, &status.sensors)
+        {
+            self.update_sensor_unit(
+                &status.device_name,
+                host,
+                &location,
+                sensor_id,
+                &sensor_value.unit,
+            );
+            self.update_sensor_staleness(
+                &status.device_name,
+                host,
+                &location,
+                sensor_id,
+                sensor_value.value,
+                Instant::now(),
+            );
+
+            match sensor_id {
                 "co2" => {
                     self.co2_ppm
-                        .with_label_values(&[status.device_name.as_str(), host])
-                        .set(sensor_value.value);
+                        .with_label_values(&labels)
+                        .set(self.round(sensor_value.value));
+                    co2_value = Some(sensor_value.value);
                 }
                 "pm__1_m_weight_concentration" => {
                     self.pm1_0_ugm3
-                        .with_label_values(&[status.device_name.as_str(), host])
-                        .set(sensor_value.value);
+                        .with_label_values(&labels)
+                        .set(self.round(sensor_value.value));
                 }
                 "pm__2_5_m_weight_concentration" => {
                     self.pm2_5_ugm3
-                        .with_label_values(&[status.device_name.as_str(), host])
-                        .set(sensor_value.value);
+                        .with_label_values(&labels)
+                        .set(self.round(sensor_value.value));
                     pm25_value = Some(sensor_value.value);
                 }
                 "pm__10_m_weight_concentration" => {
                     self.pm10_0_ugm3
-                        .with_label_values(&[status.device_name.as_str(), host])
-                        .set(sensor_value.value);
+                        .with_label_values(&labels)
+                        .set(self.round(sensor_value.value));
                     pm10_value = Some(sensor_value.value);
                 }
                 "sen55_voc" => {
                     self.voc_index
-                        .with_label_values(&[status.device_name.as_str(), host])
-                        .set(sensor_value.value);
+                        .with_label_values(&labels)
+                        .set(self.round(sensor_value.value));
                 }
                 "sen55_nox" => {
                     self.nox_index
-                        .with_label_values(&[status.device_name.as_str(), host])
-                        .set(sensor_value.value);
+                        .with_label_values(&labels)
+                        .set(self.round(sensor_value.value));
+                }
+                "sen55_voc_raw" => {
+                    self.voc_raw
+                        .with_label_values(&labels)
+                        .set(self.round(sensor_value.value));
+                }
+                "sen55_nox_raw" => {
+                    self.nox_raw
+                        .with_label_values(&labels)
+                        .set(self.round(sensor_value.value));
                 }
                 "sen55_temperature" => {
-                    self.temperature_celsius
-                        .with_label_values(&[status.device_name.as_str(), host])
-                        .set(sensor_value.value);
+                    self.temperature.with_label_values(&labels).set(
+                        self.round(
+                            self.options
+                                .temperature_unit
+                                .convert_celsius(sensor_value.value),
+                        ),
+                    );
+                    temperature_value = Some(sensor_value.value);
                 }
                 "sen55_humidity" => {
                     self.humidity_percent
-                        .with_label_values(&[status.device_name.as_str(), host])
-                        .set(sensor_value.value);
+                        .with_label_values(&labels)
+                        .set(self.round(sensor_value.value));
+                    humidity_value = Some(sensor_value.value);
                 }
                 "dps310_pressure" => {
+                    let pressure = pressure_hpa(sensor_value.value, &sensor_value.unit);
                     self.pressure_hpa
-                        .with_label_values(&[status.device_name.as_str(), host])
-                        .set(sensor_value.value);
+                        .with_label_values(&labels)
+                        .set(self.round(pressure));
+                    pressure_value = Some(pressure);
                 }
                 "illuminance" => {
                     self.illuminance_lux
-                        .with_label_values(&[status.device_name.as_str(), host])
-                        .set(sensor_value.value);
+                        .with_label_values(&labels)
+                        .set(self.round(sensor_value.value));
                 }
                 "esp_temperature" => {
-                    self.esp_temperature_celsius
-                        .with_label_values(&[status.device_name.as_str(), host])
-                        .set(sensor_value.value);
+                    self.esp_temperature.with_label_values(&labels).set(
+                        self.round(
+                            self.options
+                                .temperature_unit
+                                .convert_celsius(sensor_value.value),
+                        ),
+                    );
+                }
+                "uptime" => {
+                    self.uptime_seconds
+                        .with_label_values(&labels)
+                        .set(self.round(sensor_value.value));
+                    self.update_reboots(&status.device_name, host, &labels, sensor_value.value);
                 }
                 "rssi" => {
                     self.wifi_rssi_dbm
-                        .with_label_values(&[status.device_name.as_str(), host])
+                        .with_label_values(&labels)
+                        .set(sensor_value.value as i64);
+                }
+                "co2_self_calibration" => {
+                    self.co2_self_calibration
+                        .with_label_values(&labels)
+                        .set(sensor_value.value as i64);
+                }
+                PROBLEM_SENSOR => {
+                    self.sensor_problem
+                        .with_label_values(&labels)
                         .set(sensor_value.value as i64);
                 }
                 _ => {
-                    debug!("Unknown sensor: {} = {}", sensor_id, sensor_value.value);
+                    debug!("Other sensor: {} = {}", sensor_id, sensor_value.value);
+                    self.other_sensor
+                        .with_label_values(&[
+                            status.device_name.as_str(),
+                            host,
+                            &location,
+                            sensor_id,
+                        ])
+                        .set(self.round(sensor_value.value));
+                    self.other_sensors
+                        .write()
+                        .unwrap()
+                        .entry((status.device_name.clone(), host.to_string()))
+                        .or_default()
+                        .insert(sensor_id.to_string());
                 }
             }
         }
 
+        // Calculate climate-derived metrics only when both inputs are available
+        if let (Some(temperature), Some(humidity)) = (temperature_value, humidity_value) {
+            if let Some(dew_point) = derived::dew_point(temperature, humidity) {
+                self.dew_point_celsius
+                    .with_label_values(&labels)
+                    .set(self.round(dew_point));
+            }
+
+            self.absolute_humidity_gm3
+                .with_label_values(&labels)
+                .set(self.round(derived::absolute_humidity(temperature, humidity)));
+        }
+
+        if let (Some(pressure), Some(altitude)) = (pressure_value, self.options.altitude_meters) {
+            self.sea_level_pressure_hpa
+                .with_label_values(&labels)
+                .set(self.round(derived::sea_level_pressure(
+                    pressure,
+                    temperature_value,
+                    altitude,
+                )));
+        }
+
+        // Keep the indices from flipping to PM10 alone while PM2.5 is missing
+        let skip_indices = self.options.aqi_require_pm25 && pm25_value.is_none();
+        if skip_indices {
+            debug!(
+                "No PM2.5 reading from {}, not updating the air quality index",
+                status.device_name
+            );
+        }
+
+        // Damp jumpy PM readings with a moving average if enabled
+        if let Some(alpha) = self.options.aqi_smoothing {
+            (pm25_value, pm10_value) = self.smoothed_concentrations(
+                &status.device_name,
+                host,
+                alpha,
+                pm25_value,
+                pm10_value,
+            );
+        }
+
+        // Smooth PM readings with the NowCast if enabled
+        if self.options.aqi_mode == AqiMode::NowCast {
+            (pm25_value, pm10_value) =
+                self.nowcast_concentrations(&status.device_name, host, pm25_value, pm10_value)?;
+        }
+
         // Calculate and update AQI if PM data is available
-        if let Some(aqi_result) = aqi::calculate_aqi(pm25_value, pm10_value) {
-            self.update_aqi(&status.device_name, host, &aqi_result);
+        let aqi_result = if skip_indices {
+            None
+        } else {
+            aqi::calculate_aqi(
+                pm25_value,
+                pm10_value,
+                self.options.aqi_breakpoints.pm25_table(),
+                self.options.aqi_primary_policy,
+            )
+        };
+        match self.options.air_quality_standard {
+            _ if skip_indices => {}
+            AirQualityStandard::UsEpa => {
+                if let Some(aqi_result) = &aqi_result {
+                    self.update_aqi(&status.device_name, host, &location, aqi_result);
+                }
+            }
+            AirQualityStandard::CanadaAqhi => {
+                if let Some(aqhi_result) = aqi::calculate_aqhi(pm25_value) {
+                    self.update_aqhi(&status.device_name, host, &location, &aqhi_result);
+                }
+            }
+            AirQualityStandard::EuCaqi => {
+                if let Some(caqi_result) = aqi::calculate_caqi(pm25_value, pm10_value) {
+                    self.update_caqi(&status.device_name, host, &location, &caqi_result);
+                }
+            }
+        }
+
+        if self.options.enable_iaq
+            && let Some(score) = aqi::calculate_iaq(co2_value, aqi_result.as_ref())
+        {
+            self.iaq_score.with_label_values(&labels).set(score);
+        }
+
+        if let Some(info) = &status.info {
+            self.update_device_info(&status.device_name, host, &location, info);
         }
 
+        // Record when this device was last successfully scraped
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        self.last_scrape_timestamp_seconds
+            .with_label_values(&labels)
+            .set(now.as_secs_f64());
+
         Ok(())
     }
 
+    /// Sets the min/max/avg gauges of the sampled sensors from every
+    /// reading of the last poll interval, keyed by device sensor id
+    pub fn update_sample_stats(
+        &self,
+        device_name: &str,
+        host: &str,
+        readings: &HashMap<String, Vec<f64>>,
+    ) {
+        let location = self.location(host);
+        for (sensor_id, values) in readings {
+            let gauges = match self.canonical_sensor(sensor_id) {
+                "co2" => &self.co2_ppm_samples,
+                "pm__2_5_m_weight_concentration" => &self.pm2_5_ugm3_samples,
+                _ => continue,
+            };
+            gauges.set(device_name, host, &location, values);
+        }
+    }
+
+    /// Maps a device sensor id to the id handled below: its configured alias,
+    /// `co2` for a CO2 candidate, or the id itself
+    fn canonical_sensor<'a>(&'a self, source_id: &'a str) -> &'a str {
+        match self.options.sensor_aliases.get(source_id) {
+            Some(sensor_id) => sensor_id,
+            None if self.co2_candidate_rank(source_id).is_some() => CO2_SENSOR,
+            None => source_id,
+        }
+    }
+
+    fn co2_candidate_rank(&self, source_id: &str) -> Option<usize> {
+        self.options
+            .co2_sensor_ids
+            .iter()
+            .position(|id| id == source_id)
+    }
+
+    /// Counts a reboot when the device's uptime is lower than the last one
+    /// seen; the counter starts at 0 with the first uptime reading
+    fn update_reboots(&self, device_name: &str, host: &str, labels: &[&str], uptime: f64) {
+        let counter = self.device_reboots_total.with_label_values(labels);
+        let key = (device_name.to_string(), host.to_string());
+        if let Some(previous) = self.previous_uptime.write().unwrap().insert(key, uptime)
+            && uptime < previous
+        {
+            warn!(
+                "Detected a reboot of {} ({}): uptime went from {}s to {}s",
+                device_name, host, previous, uptime
+            );
+            counter.inc();
+        }
+    }
+
+    /// Applies sensor aliases and picks one reading per sensor id
+    ///
+    /// When several sensors map to the same id, e.g. `co2` and a `scd4x_co2`
+    /// aliased to it, the one reporting under the id itself is the primary and
+    /// the aliased ones are considered in id order, with CO2 candidates last
+    /// in order of preference, so the exported value doesn't depend on map
+    /// iteration order. Non-finite readings are skipped, letting a duplicate
+    /// stand in while the primary warms up.
+    fn resolve_sensors<'a>(
+        &'a self,
+        device: &str,
+        sensors: &'a HashMap<String, SensorValue>,
+    ) -> Vec<(&'a str, &'a SensorValue)> {
+        let mut candidates: Vec<_> = sensors
+            .iter()
+            .map(|(source_id, value)| {
+                let sensor_id = self.canonical_sensor(source_id);
+                (
+                    sensor_id != source_id,
+                    self.co2_candidate_rank(source_id),
+                    source_id.as_str(),
+                    sensor_id,
+                    value,
+                )
+            })
+            .collect();
+        candidates.sort_by(|a, b| (a.0, a.1, a.2).cmp(&(b.0, b.1, b.2)));
+
+        let mut resolved: Vec<(&str, &SensorValue)> = Vec::new();
+        let mut sources: HashMap<&str, &str> = HashMap::new();
+        for (_, _, source_id, sensor_id, value) in candidates {
+            // Warming up or unavailable sensors report NaN, which some scrapers reject
+            if !value.value.is_finite() {
+                debug!(
+                    "Skipping non-finite value for {} on {}: {}",
+                    source_id, device, value.value
+                );
+                continue;
+            }
+
+            if let Some(kept) = sources.get(sensor_id) {
+                debug!(
+                    "Ignoring {} on {}: {} already provides {}",
+                    source_id, device, kept, sensor_id
+                );
+                continue;
+            }
+
+            sources.insert(sensor_id, source_id);
+            resolved.push((sensor_id, value));
+        }
+        resolved
+    }
+
+    /// Updates the device info metric, removing the previous label set if the firmware changed
+    fn update_device_info(&self, device: &str, host: &str, location: &str, info: &DeviceInfo) {
+        let key = (device.to_string(), host.to_string());
+
+        let mut info_guard = self.previous_device_info.write().unwrap();
+        if let Some(prev) = info_guard.get(&key)
+            && prev != info
+        {
+            let _ = self
+                .device_info
+                .remove_label_values(&device_info_labels(device, host, location, prev));
+            debug!("Removed stale device info metric for {}", device);
+        }
+
+        self.device_info
+            .with_label_values(&device_info_labels(device, host, location, info))
+            .set(1.0);
+        info_guard.insert(key, info.clone());
+    }
+
+    /// Records the latest PM readings and returns the NowCast concentrations,
+    /// falling back to the instantaneous readings until enough history exists
+    fn smoothed_concentrations(
+        &self,
+        device: &str,
+        host: &str,
+        alpha: f64,
+        pm25: Option<f64>,
+        pm10: Option<f64>,
+    ) -> (Option<f64>, Option<f64>) {
+        let mut history_guard = self.pm_history.write().unwrap();
+        let history = history_guard
+            .entry((device.to_string(), host.to_string()))
+            .or_default();
+
+        (
+            pm25.map(|value| history.pm25_average.update(alpha, value)),
+            pm10.map(|value| history.pm10_average.update(alpha, value)),
+        )
+    }
+
+    /// Returns the PM history of every device, for saving
+    pub fn save_pm_history(&self) -> Vec<SavedPmHistory> {
+        let history = self.pm_history.read().unwrap();
+        history
+            .iter()
+            .map(|((device, host), history)| SavedPmHistory {
+                device: device.clone(),
+                host: host.clone(),
+                history: history.clone(),
+            })
+            .collect()
+    }
+
+    /// Moves a device's PM history to a new device name, so a renamed device
+    /// keeps its NowCast and smoothing state
+    pub fn rename_pm_history(&self, host: &str, from: &str, to: &str) {
+        let mut history = self.pm_history.write().unwrap();
+        if let Some(entry) = history.remove(&(from.to_string(), host.to_string())) {
+            history.insert((to.to_string(), host.to_string()), entry);
+        }
+    }
+
+    /// Restores saved PM history, replacing any the devices already have
+    pub fn restore_pm_history(&self, saved: Vec<SavedPmHistory>) {
+        let mut history = self.pm_history.write().unwrap();
+        for entry in saved {
+            history.insert((entry.device, entry.host), entry.history);
+        }
+    }
+
+    fn nowcast_concentrations(
+        &self,
+        device: &str,
+        host: &str,
+        pm25: Option<f64>,
+        pm10: Option<f64>,
+    ) -> Result<(Option<f64>, Option<f64>)> {
+        let hour = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() / 3600;
+
+        let mut history_guard = self.pm_history.write().unwrap();
+        let history = history_guard
+            .entry((device.to_string(), host.to_string()))
+            .or_default();
+
+        if let Some(value) = pm25 {
+            history.pm25.add_sample(hour, value);
+        }
+        if let Some(value) = pm10 {
+            history.pm10.add_sample(hour, value);
+        }
+
+        Ok((
+            pm25.map(|value| history.pm25.nowcast(hour).unwrap_or(value)),
+            pm10.map(|value| history.pm10.nowcast(hour).unwrap_or(value)),
+        ))
+    }
+
+    /// Rounds a reading for export if `round_decimals` is set; indices are
+    /// still calculated from the unrounded readings
+    fn round(&self, value: f64) -> f64 {
+        match self.options.round_decimals {
+            Some(decimals) => {
+                let scale = 10f64.powi(decimals as i32);
+                (value * scale).round() / scale
+            }
+            None => value,
+        }
+    }
+
+    /// Updates the unit info series of a sensor, removing the old one if the
+    /// reported unit changed
+    fn update_sensor_unit(
+        &self,
+        device: &str,
+        host: &str,
+        location: &str,
+        sensor: &str,
+        unit: &str,
+    ) {
+        let mut units_guard = self.previous_sensor_units.write().unwrap();
+        let units = units_guard
+            .entry((device.to_string(), host.to_string()))
+            .or_default();
+
+        if let Some(prev) = units.get(sensor) {
+            if prev == unit {
+                return;
+            }
+            let _ = self.sensor_unit_info.remove_label_values(&[
+                device,
+                host,
+                location,
+                sensor,
+                prev.as_str(),
+            ]);
+            debug!(
+                "Removed stale unit info metric for {} {} (was {})",
+                device, sensor, prev
+            );
+            units.remove(sensor);
+        }
+
+        if !unit.is_empty() {
+            self.sensor_unit_info
+                .with_label_values(&[device, host, location, sensor, unit])
+                .set(1.0);
+            units.insert(sensor.to_string(), unit.to_string());
+        }
+    }
+
+    /// Flags a sensor stale once its value hasn't changed for
+    /// `sensor_stale_after`, e.g. after an I2C hiccup while the device keeps
+    /// serving the last reading
+    ///
+    /// States rather than readings, like the problem sensor, are expected to
+    /// stay the same and are never flagged.
+    fn update_sensor_staleness(
+        &self,
+        device: &str,
+        host: &str,
+        location: &str,
+        sensor: &str,
+        value: f64,
+        now: Instant,
+    ) {
+        let Some(stale_after) = self.options.sensor_stale_after else {
+            return;
+        };
+        if matches!(sensor, PROBLEM_SENSOR | "co2_self_calibration") {
+            return;
+        }
+
+        let mut changes_guard = self.sensor_changes.write().unwrap();
+        let change = changes_guard
+            .entry((device.to_string(), host.to_string()))
+            .or_default()
+            .entry(sensor.to_string())
+            .or_insert(SensorChange {
+                value,
+                changed_at: now,
+            });
+        if change.value != value {
+            change.value = value;
+            change.changed_at = now;
+        }
+
+        let stale = now.saturating_duration_since(change.changed_at) > stale_after;
+        self.sensor_stale
+            .with_label_values(&[device, host, location, sensor])
+            .set(stale as i64);
+    }
+
     /// Updates AQI metrics with proper cleanup of stale info labels
-    fn update_aqi(&self, device: &str, host: &str, result: &aqi::AqiResult) {
+    fn update_aqi(&self, device: &str, host: &str, location: &str, result: &aqi::AqiResult) {
         let key = (device.to_string(), host.to_string());
 
         // Remove previous info metric if category or pollutant changed
@@ -313,8 +1440,17 @@ impl Metrics {
                 let _ = self.aqi_info.remove_label_values(&[
                     device,
                     host,
+                    location,
                     prev.category.as_str(),
                     &prev.primary_pollutant,
+                    prev.category.color(),
+                ]);
+                let _ = self.aqi_advice_info.remove_label_values(&[
+                    device,
+                    host,
+                    location,
+                    prev.category.as_str(),
+                    prev.category.health_message(),
                 ]);
                 debug!(
                     "Removed stale AQI info metric for {} (was {:?}/{})",
@@ -324,19 +1460,45 @@ impl Metrics {
         }
 
         // Set overall AQI value
-        self.aqi.with_label_values(&[device, host]).set(result.aqi);
+        self.aqi
+            .with_label_values(&[device, host, location])
+            .set(result.aqi);
 
         // Set per-pollutant sub-AQIs
         if let Some(pm25_aqi) = result.pm25_aqi {
-            self.aqi_pm25.with_label_values(&[device, host]).set(pm25_aqi);
+            self.aqi_pm25
+                .with_label_values(&[device, host, location])
+                .set(pm25_aqi);
         }
         if let Some(pm10_aqi) = result.pm10_aqi {
-            self.aqi_pm10.with_label_values(&[device, host]).set(pm10_aqi);
+            self.aqi_pm10
+                .with_label_values(&[device, host, location])
+                .set(pm10_aqi);
         }
 
+        self.aqi_category
+            .with_label_values(&[device, host, location])
+            .set(result.category.level() as f64);
+
         // Set info metric (always value 1)
         self.aqi_info
-            .with_label_values(&[device, host, result.category.as_str(), &result.primary_pollutant])
+            .with_label_values(&[
+                device,
+                host,
+                location,
+                result.category.as_str(),
+                &result.primary_pollutant,
+                result.category.color(),
+            ])
+            .set(1.0);
+        self.aqi_advice_info
+            .with_label_values(&[
+                device,
+                host,
+                location,
+                result.category.as_str(),
+                result.category.health_message(),
+            ])
             .set(1.0);
 
         // Update tracked state
@@ -352,15 +1514,447 @@ impl Metrics {
         }
     }
 
+    /// Updates the AQHI metrics, replacing the info series when the category changes
+    fn update_aqhi(&self, device: &str, host: &str, location: &str, result: &aqi::AqhiResult) {
+        let key = (device.to_string(), host.to_string());
+
+        let mut category_guard = self.previous_aqhi_category.write().unwrap();
+        if let Some(prev) = category_guard.get(&key)
+            && *prev != result.category
+        {
+            let _ = self
+                .aqhi_info
+                .remove_label_values(&[device, host, location, prev.as_str()]);
+        }
+
+        self.aqhi
+            .with_label_values(&[device, host, location])
+            .set(result.aqhi);
+        self.aqhi_info
+            .with_label_values(&[device, host, location, result.category.as_str()])
+            .set(1.0);
+        category_guard.insert(key, result.category.clone());
+    }
+
+    /// Updates the CAQI metrics, replacing the info series when the category or
+    /// primary pollutant changes
+    fn update_caqi(&self, device: &str, host: &str, location: &str, result: &aqi::CaqiResult) {
+        let key = (device.to_string(), host.to_string());
+
+        let mut state_guard = self.previous_caqi_state.write().unwrap();
+        if let Some((category, pollutant)) = state_guard.get(&key)
+            && (*category != result.category || *pollutant != result.primary_pollutant)
+        {
+            let _ = self.caqi_info.remove_label_values(&[
+                device,
+                host,
+                location,
+                category.as_str(),
+                pollutant,
+            ]);
+        }
+
+        self.caqi
+            .with_label_values(&[device, host, location])
+            .set(result.caqi);
+        if let Some(pm25_caqi) = result.pm25_caqi {
+            self.caqi_pm25
+                .with_label_values(&[device, host, location])
+                .set(pm25_caqi);
+        }
+        if let Some(pm10_caqi) = result.pm10_caqi {
+            self.caqi_pm10
+                .with_label_values(&[device, host, location])
+                .set(pm10_caqi);
+        }
+        self.caqi_info
+            .with_label_values(&[
+                device,
+                host,
+                location,
+                result.category.as_str(),
+                &result.primary_pollutant,
+            ])
+            .set(1.0);
+        state_guard.insert(
+            key,
+            (result.category.clone(), result.primary_pollutant.clone()),
+        );
+    }
+
+    /// Records how long the last successful status fetch took
+    pub fn record_scrape_duration(&self, device_name: &str, host: &str, seconds: f64) {
+        let location = self.location(host);
+        self.scrape_duration_seconds
+            .with_label_values(&[device_name, host, &location])
+            .set(seconds);
+    }
+
+    /// Records a completed poll cycle of the device
+    pub fn record_poll_cycle(&self, device_name: &str, host: &str, seconds: f64) {
+        let location = self.location(host);
+        self.poll_cycle_duration_seconds
+            .with_label_values(&[device_name, host, &location])
+            .set(seconds);
+        self.poll_cycles_total
+            .with_label_values(&[device_name, host, &location])
+            .inc();
+    }
+
+    /// Counts a failed status fetch for the device
+    pub fn record_scrape_error(&self, device_name: &str, host: &str) {
+        let location = self.location(host);
+        self.scrape_errors_total
+            .with_label_values(&[device_name, host, &location])
+            .inc();
+    }
+
+    /// Records why a status fetch from the device failed, replacing the
+    /// previous reason
+    pub fn record_last_error(&self, device_name: &str, host: &str, reason: &str) {
+        let location = self.location(host);
+        self.clear_last_error(device_name, host, &location);
+        self.device_last_error
+            .with_label_values(&[device_name, host, &location, reason])
+            .set(1.0);
+        self.last_error_reasons.write().unwrap().insert(
+            (device_name.to_string(), host.to_string()),
+            reason.to_string(),
+        );
+    }
+
+    /// Drops the last error series once the device answers again
+    fn clear_last_error(&self, device_name: &str, host: &str, location: &str) {
+        let key = (device_name.to_string(), host.to_string());
+        if let Some(reason) = self.last_error_reasons.write().unwrap().remove(&key) {
+            let _ =
+                self.device_last_error
+                    .remove_label_values(&[device_name, host, location, &reason]);
+        }
+    }
+
+    /// Removes every series of a device, e.g. once it is dropped from the configuration
+    pub fn remove_device(&self, device_name: &str, host: &str) {
+        let location = self.location(host);
+        let labels = [device_name, host, location.as_str()];
+
+        let _ = self.device_up.remove_label_values(&labels);
+        self.refresh_devices_up();
+        let _ = self.sensors_found.remove_label_values(&labels);
+        let _ = self.device_connected.remove_label_values(&labels);
+        let _ = self.scrape_duration_seconds.remove_label_values(&labels);
+        let _ = self.scrape_errors_total.remove_label_values(&labels);
+        self.clear_last_error(device_name, host, &location);
+        let _ = self
+            .last_scrape_timestamp_seconds
+            .remove_label_values(&labels);
+        let _ = self
+            .poll_cycle_duration_seconds
+            .remove_label_values(&labels);
+        let _ = self.poll_cycles_total.remove_label_values(&labels);
+        self.remove_sensor_metrics(device_name, host, &location);
+
+        let key = (device_name.to_string(), host.to_string());
+        self.liveness.write().unwrap().remove(&key);
+        self.previous_uptime.write().unwrap().remove(&key);
+        let _ = self.device_reboots_total.remove_label_values(&labels);
+        self.pm_history.write().unwrap().remove(&key);
+        if let Some(prev) = self.previous_device_info.write().unwrap().remove(&key) {
+            let _ = self.device_info.remove_label_values(&device_info_labels(
+                device_name,
+                host,
+                &location,
+                &prev,
+            ));
+        }
+
+        self.device_locations.write().unwrap().remove(host);
+
+        debug!("Removed all metrics for {} ({})", device_name, host);
+    }
+
+    /// Removes the sensor, derived and AQI series of a device
+    fn remove_sensor_metrics(&self, device_name: &str, host: &str, location: &str) {
+        let labels = [device_name, host, location];
+
+        for gauge in self.sensor_gauges() {
+            let _ = gauge.remove_label_values(&labels);
+        }
+        let _ = self.wifi_rssi_dbm.remove_label_values(&labels);
+        let _ = self.sensor_problem.remove_label_values(&labels);
+        let _ = self.co2_self_calibration.remove_label_values(&labels);
+
+        let key = (device_name.to_string(), host.to_string());
+        if let Some(ids) = self.other_sensors.write().unwrap().remove(&key) {
+            for id in ids {
+                let _ = self.other_sensor.remove_label_values(&[
+                    device_name,
+                    host,
+                    location,
+                    id.as_str(),
+                ]);
+            }
+        }
+        if let Some(changes) = self.sensor_changes.write().unwrap().remove(&key) {
+            for sensor in changes.keys() {
+                let _ = self.sensor_stale.remove_label_values(&[
+                    device_name,
+                    host,
+                    location,
+                    sensor.as_str(),
+                ]);
+            }
+        }
+        if let Some(units) = self.previous_sensor_units.write().unwrap().remove(&key) {
+            for (sensor, unit) in units {
+                let _ = self.sensor_unit_info.remove_label_values(&[
+                    device_name,
+                    host,
+                    location,
+                    sensor.as_str(),
+                    unit.as_str(),
+                ]);
+            }
+        }
+        if let Some(prev) = self.previous_aqi_state.write().unwrap().remove(&key) {
+            let _ = self.aqi_info.remove_label_values(&[
+                device_name,
+                host,
+                location,
+                prev.category.as_str(),
+                &prev.primary_pollutant,
+                prev.category.color(),
+            ]);
+            let _ = self.aqi_advice_info.remove_label_values(&[
+                device_name,
+                host,
+                location,
+                prev.category.as_str(),
+                prev.category.health_message(),
+            ]);
+        }
+        if let Some(prev) = self.previous_aqhi_category.write().unwrap().remove(&key) {
+            let _ =
+                self.aqhi_info
+                    .remove_label_values(&[device_name, host, location, prev.as_str()]);
+        }
+        if let Some((category, pollutant)) = self.previous_caqi_state.write().unwrap().remove(&key)
+        {
+            let _ = self.caqi_info.remove_label_values(&[
+                device_name,
+                host,
+                location,
+                category.as_str(),
+                &pollutant,
+            ]);
+        }
+    }
+
+    /// All float gauges labeled only by device and host that carry sensor data
+    fn sensor_gauges(&self) -> Vec<&GaugeVec> {
+        let mut gauges = vec![
+            &self.co2_ppm,
+            &self.pm1_0_ugm3,
+            &self.pm2_5_ugm3,
+            &self.pm10_0_ugm3,
+            &self.voc_index,
+            &self.nox_index,
+            &self.voc_raw,
+            &self.nox_raw,
+            &self.temperature,
+            &self.humidity_percent,
+            &self.pressure_hpa,
+            &self.illuminance_lux,
+            &self.dew_point_celsius,
+            &self.absolute_humidity_gm3,
+            &self.sea_level_pressure_hpa,
+            &self.esp_temperature,
+            &self.uptime_seconds,
+            &self.aqi,
+            &self.aqi_pm25,
+            &self.aqi_pm10,
+            &self.aqi_category,
+            &self.aqhi,
+            &self.caqi,
+            &self.caqi_pm25,
+            &self.caqi_pm10,
+            &self.iaq_score,
+        ];
+        gauges.extend(self.co2_ppm_samples.gauges());
+        gauges.extend(self.pm2_5_ugm3_samples.gauges());
+        gauges
+    }
+
+    pub fn set_devices_total(&self, count: usize) {
+        self.devices_total.set(count as f64);
+    }
+
+    pub fn set_poll_interval(&self, interval: Duration) {
+        self.poll_interval_seconds.set(interval.as_secs_f64());
+    }
+
+    /// Recounts the devices currently reporting `device_up` 1
+    fn refresh_devices_up(&self) {
+        let up = self
+            .device_up
+            .collect()
+            .iter()
+            .flat_map(|family| family.get_metric())
+            .filter(|metric| metric.get_gauge().value() == 1.0)
+            .count();
+        self.devices_up.set(up as f64);
+    }
+
+    /// Lists every device with a `device_up` series, sorted by name and host
+    pub fn device_overview(&self) -> Vec<DeviceOverview> {
+        let last_scrapes: HashMap<_, _> = self
+            .last_scrape_timestamp_seconds
+            .collect()
+            .iter()
+            .flat_map(|family| family.get_metric())
+            .map(|metric| (device_labels(metric), metric.get_gauge().value()))
+            .collect();
+
+        let mut devices: Vec<_> = self
+            .device_up
+            .collect()
+            .iter()
+            .flat_map(|family| family.get_metric())
+            .map(|metric| {
+                let (device, host) = device_labels(metric);
+                DeviceOverview {
+                    last_scrape: last_scrapes.get(&(device.clone(), host.clone())).copied(),
+                    up: metric.get_gauge().value() == 1.0,
+                    device,
+                    host,
+                }
+            })
+            .collect();
+        devices.sort_by(|a, b| (&a.device, &a.host).cmp(&(&b.device, &b.host)));
+        devices
+    }
+
     pub fn mark_device_down(&self, device_name: &str, host: &str) {
+        let location = self.location(host);
         error!("Marking device {} as down", device_name);
         self.device_up
-            .with_label_values(&[device_name, host])
+            .with_label_values(&[device_name, host, &location])
             .set(0);
+        self.refresh_devices_up();
+
+        // Clear the last duration so a failed scrape doesn't report a stale value
+        let _ = self
+            .scrape_duration_seconds
+            .remove_label_values(&[device_name, host, &location]);
+
+        // Stop reporting the last known readings so absent() alerts fire
+        if self.options.clear_on_down {
+            self.remove_sensor_metrics(device_name, host, &location);
+        }
+    }
+
+    /// Records whether an event-driven device's stream or subscription is
+    /// currently connected
+    pub fn set_device_connected(&self, device_name: &str, host: &str, connected: bool) {
+        let location = self.location(host);
+        self.device_connected
+            .with_label_values(&[device_name, host, &location])
+            .set(i64::from(connected));
+    }
+
+    /// Sets the static `location` label of a device's series; devices
+    /// without one are labeled with an empty location
+    pub fn set_location(&self, host: &str, location: &str) {
+        self.device_locations
+            .write()
+            .unwrap()
+            .insert(host.to_string(), location.to_string());
+    }
+
+    fn location(&self, host: &str) -> String {
+        self.device_locations
+            .read()
+            .unwrap()
+            .get(host)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Starts watching a device, so it is marked down by
+    /// [`Metrics::mark_stale_devices`] after `stale_after` without a
+    /// successful update
+    pub fn watch_device(&self, device_name: &str, host: &str, stale_after: Duration) {
+        self.liveness.write().unwrap().insert(
+            (device_name.to_string(), host.to_string()),
+            Liveness {
+                last_success: Instant::now(),
+                stale_after,
+                marked_down: false,
+            },
+        );
+    }
+
+    /// Marks watched devices without a successful update within their
+    /// staleness limit as down, independent of the scrape error path;
+    /// returns the names of the devices newly marked down
+    pub fn mark_stale_devices(&self, now: Instant) -> Vec<String> {
+        let mut stale = Vec::new();
+        for ((device_name, host), liveness) in self.liveness.write().unwrap().iter_mut() {
+            if liveness.marked_down
+                || now.saturating_duration_since(liveness.last_success) <= liveness.stale_after
+            {
+                continue;
+            }
+
+            warn!(
+                "No successful update from {} ({}) in {:?}",
+                device_name, host, liveness.stale_after
+            );
+            self.mark_device_down(device_name, host);
+            liveness.marked_down = true;
+            stale.push(device_name.clone());
+        }
+        stale
     }
 
     pub fn gather(&self) -> Result<String> {
-        let encoder = TextEncoder::new();
+        self.encode(&TextEncoder::new())
+    }
+
+    /// Renders only the series of one device, in the Prometheus text format
+    pub fn gather_device(&self, host: &str) -> Result<String> {
+        let mut metric_families = self.registry.gather();
+        for family in &mut metric_families {
+            family.mut_metric().retain(|metric| {
+                metric
+                    .get_label()
+                    .iter()
+                    .any(|label| label.name() == "host" && label.value() == host)
+            });
+        }
+        metric_families.retain(|family| !family.get_metric().is_empty());
+
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&metric_families, &mut buffer)?;
+        String::from_utf8(buffer).map_err(|e| e.into())
+    }
+
+    /// Renders the Prometheus text and OpenMetrics formats from one gather,
+    /// so both describe the same snapshot, and counts the refresh
+    pub fn render(&self) -> Result<(String, String)> {
+        self.scrape_generation_total.inc();
+        let metric_families = self.registry.gather();
+
+        let mut text = Vec::new();
+        TextEncoder::new().encode(&metric_families, &mut text)?;
+        let mut openmetrics = Vec::new();
+        OpenMetricsEncoder::new().encode(&metric_families, &mut openmetrics)?;
+
+        Ok((String::from_utf8(text)?, String::from_utf8(openmetrics)?))
+    }
+
+    /// Renders all metrics with the given exposition format encoder
+    pub fn encode(&self, encoder: &impl Encoder) -> Result<String> {
         let metric_families = self.registry.gather();
         let mut buffer = Vec::new();
         encoder.encode(&metric_families, &mut buffer)?;
@@ -368,6 +1962,51 @@ impl Metrics {
     }
 }
 
+/// Pressure readings without a known unit above this are taken as Pascals;
+/// surface pressure stays well below it in hPa and well above it in Pa
+const PASCAL_THRESHOLD: f64 = 2000.0;
+
+/// Converts a pressure reading to hPa, for firmwares that report Pascals or
+/// kilopascals instead
+fn pressure_hpa(value: f64, unit: &str) -> f64 {
+    match unit.trim() {
+        "Pa" => value / 100.0,
+        "kPa" => value * 10.0,
+        "hPa" | "mbar" => value,
+        _ if value > PASCAL_THRESHOLD => value / 100.0,
+        _ => value,
+    }
+}
+
+fn device_info_labels<'a>(
+    device: &'a str,
+    host: &'a str,
+    location: &'a str,
+    info: &'a DeviceInfo,
+) -> [&'a str; 7] {
+    [
+        device,
+        host,
+        location,
+        &info.esphome_version,
+        &info.project_name,
+        &info.project_version,
+        &info.ip_address,
+    ]
+}
+
+/// The `device` and `host` labels of a gathered series
+fn device_labels(metric: &prometheus::proto::Metric) -> (String, String) {
+    let label = |name: &str| {
+        metric
+            .get_label()
+            .iter()
+            .find(|label| label.name() == name)
+            .map_or_else(String::new, |label| label.value().to_string())
+    };
+    (label("device"), label("host"))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -376,7 +2015,7 @@ mod tests {
 
     #[test]
     fn test_metrics_update() {
-        let metrics = Metrics::new().unwrap();
+        let metrics = Metrics::new(MetricsOptions::default()).unwrap();
 
         let mut sensors = HashMap::new();
         sensors.insert(
@@ -411,16 +2050,53 @@ mod tests {
                 name: "PM2.5".to_string(),
             },
         );
+        sensors.insert(
+            "uptime".to_string(),
+            SensorValue {
+                value: 3600.5,
+                unit: "s".to_string(),
+                name: "Uptime".to_string(),
+            },
+        );
+        sensors.insert(
+            "co2_self_calibration".to_string(),
+            SensorValue {
+                value: 1.0,
+                unit: String::new(),
+                name: "CO2 Self-Calibration".to_string(),
+            },
+        );
+        sensors.insert(
+            PROBLEM_SENSOR.to_string(),
+            SensorValue {
+                value: 1.0,
+                unit: String::new(),
+                name: "Sensor Problem".to_string(),
+            },
+        );
 
         let status = ApolloStatus {
             sensors,
             device_name: "Test Device".to_string(),
+            info: None,
         };
 
         metrics.update_device("192.168.1.100", &status).unwrap();
 
         let output = metrics.gather().unwrap();
         assert!(output.contains("apollo_air1_device_up"));
+        assert!(output.contains(
+            r#"apollo_air1_sensors_found{device="Test Device",host="192.168.1.100",location=""} 7"#
+        ));
+        assert!(output.contains(
+            r#"apollo_air1_uptime_seconds{device="Test Device",host="192.168.1.100",location=""} 3600.5"#
+        ));
+        assert!(output.contains(
+            r#"apollo_air1_sensor_problem{device="Test Device",host="192.168.1.100",location=""} 1"#
+        ));
+        assert!(output.contains(
+            r#"apollo_air1_co2_self_calibration_enabled{device="Test Device",host="192.168.1.100",location=""} 1"#
+        ));
         assert!(output.contains("apollo_air1_co2_ppm"));
         assert!(output.contains("apollo_air1_temperature_celsius"));
         assert!(output.contains("apollo_air1_humidity_percent"));
@@ -430,72 +2106,1295 @@ mod tests {
         assert!(output.contains("22.5")); // Temperature value
         assert!(output.contains("45")); // Humidity value
         assert!(output.contains("12.5")); // PM2.5 value
+        assert!(output.contains("apollo_air1_last_scrape_timestamp_seconds{"));
+        assert!(output.contains("apollo_air1_dew_point_celsius{"));
+        assert!(output.contains("apollo_air1_absolute_humidity_gm3{"));
     }
 
     #[test]
-    #[ignore = "Metrics registry conflict in tests"]
-    fn test_device_down_marking() {
-        let metrics = Metrics::new().unwrap();
+    fn test_derived_climate_metrics_require_humidity() {
+        let metrics = Metrics::new(MetricsOptions::default()).unwrap();
 
-        metrics.mark_device_down("Test Device", "192.168.1.100");
+        let mut sensors = HashMap::new();
+        sensors.insert(
+            "sen55_temperature".to_string(),
+            SensorValue {
+                value: 22.5,
+                unit: "°C".to_string(),
+                name: "Temperature".to_string(),
+            },
+        );
+
+        let status = ApolloStatus {
+            sensors,
+            device_name: "Test Device".to_string(),
+            info: None,
+        };
+
+        metrics.update_device("192.168.1.100", &status).unwrap();
 
         let output = metrics.gather().unwrap();
-        assert!(output.contains("apollo_air1_device_up"));
-        assert!(output.contains(r#"device="Test Device""#));
-        assert!(output.contains("} 0"));
+        assert!(!output.contains("apollo_air1_dew_point_celsius{"));
+        assert!(!output.contains("apollo_air1_absolute_humidity_gm3{"));
     }
 
     #[test]
-    #[ignore = "Metrics registry conflict in tests"]
-    fn test_aqi_calculation_integration() {
-        let metrics = Metrics::new().unwrap();
+    fn test_sea_level_pressure() {
+        let pressure = |options: MetricsOptions| {
+            let metrics = Metrics::new(options).unwrap();
+            let status = ApolloStatus {
+                sensors: HashMap::from([(
+                    "dps310_pressure".to_string(),
+                    SensorValue {
+                        value: 954.6,
+                        unit: "hPa".to_string(),
+                        name: "DPS310 Pressure".to_string(),
+                    },
+                )]),
+                device_name: "Test Device".to_string(),
+                info: None,
+            };
+            metrics.update_device("192.168.1.100", &status).unwrap();
+            metrics.gather().unwrap()
+        };
+
+        let output = pressure(MetricsOptions::default());
+        assert!(!output.contains("apollo_air1_sea_level_pressure_hpa{"));
+
+        let output = pressure(MetricsOptions {
+            altitude_meters: Some(500.0),
+            round_decimals: Some(1),
+            ..Default::default()
+        });
+        assert!(output.contains(
+            r#"apollo_air1_sea_level_pressure_hpa{device="Test Device",host="192.168.1.100",location=""} 1013.3"#
+        ));
+    }
+
+    #[test]
+    fn test_pressure_in_pascals() {
+        let metrics = Metrics::new(MetricsOptions::default()).unwrap();
+        let status = ApolloStatus {
+            sensors: HashMap::from([(
+                "dps310_pressure".to_string(),
+                SensorValue {
+                    value: 101325.0,
+                    unit: "Pa".to_string(),
+                    name: "DPS310 Pressure".to_string(),
+                },
+            )]),
+            device_name: "Test Device".to_string(),
+            info: None,
+        };
+        metrics.update_device("192.168.1.100", &status).unwrap();
+        assert!(metrics.gather().unwrap().contains(
+            r#"apollo_air1_pressure_hpa{device="Test Device",host="192.168.1.100",location=""} 1013.25"#
+        ));
+
+        assert_eq!(pressure_hpa(1013.25, "hPa"), 1013.25);
+        assert_eq!(pressure_hpa(101.325, "kPa"), 1013.25);
+        // Without a unit, the magnitude tells Pascals apart
+        assert_eq!(pressure_hpa(101325.0, ""), 1013.25);
+        assert_eq!(pressure_hpa(1013.25, ""), 1013.25);
+    }
+
+    #[test]
+    fn test_fahrenheit_temperature() {
+        let metrics = Metrics::new(MetricsOptions {
+            temperature_unit: TemperatureUnit::Fahrenheit,
+            ..Default::default()
+        })
+        .unwrap();
 
         let mut sensors = HashMap::new();
-        // Add PM2.5 data that should result in Moderate AQI (~68)
         sensors.insert(
-            "pm__2_5_m_weight_concentration".to_string(),
+            "sen55_temperature".to_string(),
             SensorValue {
                 value: 20.0,
-                unit: "µg/m³".to_string(),
-                name: "PM2.5".to_string(),
+                unit: "°C".to_string(),
+                name: "Temperature".to_string(),
             },
         );
-        // Add PM10 data that should result in lower AQI (~28)
         sensors.insert(
-            "pm__10_m_weight_concentration".to_string(),
+            "esp_temperature".to_string(),
             SensorValue {
-                value: 30.0,
-                unit: "µg/m³".to_string(),
-                name: "PM10".to_string(),
+                value: 45.0,
+                unit: "°C".to_string(),
+                name: "ESP Temperature".to_string(),
             },
         );
 
         let status = ApolloStatus {
             sensors,
             device_name: "Test Device".to_string(),
+            info: None,
         };
 
         metrics.update_device("192.168.1.100", &status).unwrap();
 
         let output = metrics.gather().unwrap();
-        // Check overall AQI metric (71 with 2024 EPA breakpoints)
-        assert!(output.contains("apollo_air1_aqi{"));
-        assert!(output.contains("71")); // Expected AQI value with 2024 breakpoints
+        assert!(!output.contains("celsius"));
+        assert!(output.contains(
+            r#"apollo_air1_temperature_fahrenheit{device="Test Device",host="192.168.1.100",location=""} 68"#
+        ));
+        assert!(output.contains(
+            r#"apollo_air1_esp_temperature_fahrenheit{device="Test Device",host="192.168.1.100",location=""} 113"#
+        ));
+    }
 
-        // Check per-pollutant sub-AQI metrics
-        assert!(output.contains("apollo_air1_aqi_pm25{"));
-        assert!(output.contains("apollo_air1_aqi_pm10{"));
+    #[test]
+    fn test_device_info_cleanup() {
+        let metrics = Metrics::new(MetricsOptions::default()).unwrap();
 
-        // Check info metric with category labels
-        assert!(output.contains("apollo_air1_aqi_info{"));
-        assert!(output.contains("category=\"Moderate\""));
-        assert!(output.contains("primary_pollutant=\"PM2.5\""));
+        let mut sensors = HashMap::new();
+        sensors.insert(
+            "co2".to_string(),
+            SensorValue {
+                value: 450.0,
+                unit: "ppm".to_string(),
+                name: "CO2".to_string(),
+            },
+        );
+
+        let mut status = ApolloStatus {
+            sensors,
+            device_name: "Test Device".to_string(),
+            info: Some(DeviceInfo {
+                esphome_version: "2024.6.0".to_string(),
+                project_name: "ApolloAutomation.AIR-1".to_string(),
+                project_version: "24.6.1.1".to_string(),
+                ip_address: "192.168.1.100".to_string(),
+            }),
+        };
+
+        metrics.update_device("192.168.1.100", &status).unwrap();
+        let output = metrics.gather().unwrap();
+        assert!(output.contains(
+            r#"apollo_air1_device_info{device="Test Device",esphome_version="2024.6.0",host="192.168.1.100",ip_address="192.168.1.100",location="",project_name="ApolloAutomation.AIR-1",project_version="24.6.1.1"} 1"#
+        ));
+
+        // Firmware upgrade replaces the old label set
+        status.info.as_mut().unwrap().esphome_version = "2024.7.0".to_string();
+        metrics.update_device("192.168.1.100", &status).unwrap();
+        let output = metrics.gather().unwrap();
+        assert!(output.contains(r#"esphome_version="2024.7.0""#));
+        assert!(!output.contains(r#"esphome_version="2024.6.0""#));
+    }
+
+    #[test]
+    fn test_sensor_aliases() {
+        let mut options = MetricsOptions::default();
+        options
+            .sensor_aliases
+            .insert("scd40_co2".to_string(), "co2".to_string());
+        let metrics = Metrics::new(options).unwrap();
+
+        let mut sensors = HashMap::new();
+        sensors.insert(
+            "scd40_co2".to_string(),
+            SensorValue {
+                value: 530.0,
+                unit: "ppm".to_string(),
+                name: "scd40_co2".to_string(),
+            },
+        );
+        let status = ApolloStatus {
+            sensors,
+            device_name: "Test Device".to_string(),
+            info: None,
+        };
+
+        metrics.update_device("192.168.1.100", &status).unwrap();
+        let output = metrics.gather().unwrap();
+        assert!(output.contains(
+            r#"apollo_air1_co2_ppm{device="Test Device",host="192.168.1.100",location=""} 530"#
+        ));
+        assert!(!output.contains("scd40_co2"));
+    }
+
+    #[test]
+    fn test_round_decimals() {
+        let pm25 = |value: f64| ApolloStatus {
+            sensors: HashMap::from([(
+                "pm__2_5_m_weight_concentration".to_string(),
+                SensorValue {
+                    value,
+                    unit: "µg/m³".to_string(),
+                    name: "PM2.5".to_string(),
+                },
+            )]),
+            device_name: "Test Device".to_string(),
+            info: None,
+        };
+
+        let metrics = Metrics::new(MetricsOptions::default()).unwrap();
+        metrics
+            .update_device("192.168.1.100", &pm25(12.299999999999999))
+            .unwrap();
+        assert!(metrics.gather().unwrap().contains("} 12.299999999999999\n"));
+
+        let options = MetricsOptions {
+            round_decimals: Some(2),
+            ..Default::default()
+        };
+        let metrics = Metrics::new(options).unwrap();
+        metrics
+            .update_device("192.168.1.100", &pm25(12.299999999999999))
+            .unwrap();
+        let output = metrics.gather().unwrap();
+        assert!(output.contains(
+            r#"apollo_air1_pm2_5_ugm3{device="Test Device",host="192.168.1.100",location=""} 12.3"#
+        ));
+        assert!(!output.contains("12.299999999999999"));
+    }
+
+    #[test]
+    fn test_duplicate_sensors() {
+        let mut options = MetricsOptions::default();
+        for alias in ["scd4x_co2", "scd40_co2"] {
+            options
+                .sensor_aliases
+                .insert(alias.to_string(), "co2".to_string());
+        }
+        let metrics = Metrics::new(options).unwrap();
+
+        let co2 = |value: f64| SensorValue {
+            value,
+            unit: "ppm".to_string(),
+            name: "CO2".to_string(),
+        };
+        let mut status = ApolloStatus {
+            sensors: HashMap::from([
+                ("co2".to_string(), co2(500.0)),
+                ("scd4x_co2".to_string(), co2(510.0)),
+                ("scd40_co2".to_string(), co2(520.0)),
+            ]),
+            device_name: "Test Device".to_string(),
+            info: None,
+        };
+
+        // The sensor reporting under the metric's own id wins
+        metrics.update_device("192.168.1.100", &status).unwrap();
+        assert!(metrics.gather().unwrap().contains(
+            r#"apollo_air1_co2_ppm{device="Test Device",host="192.168.1.100",location=""} 500"#
+        ));
+
+        // Without a usable primary, aliases are picked in id order
+        status.sensors.insert("co2".to_string(), co2(f64::NAN));
+        metrics.update_device("192.168.1.100", &status).unwrap();
+        assert!(metrics.gather().unwrap().contains(
+            r#"apollo_air1_co2_ppm{device="Test Device",host="192.168.1.100",location=""} 520"#
+        ));
+    }
+
+    #[test]
+    fn test_co2_sensor_candidates() {
+        let metrics = Metrics::new(MetricsOptions {
+            co2_sensor_ids: ["co2", "scd4x_co2", "scd30_co2"].map(String::from).to_vec(),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let co2 = |value: f64| SensorValue {
+            value,
+            unit: "ppm".to_string(),
+            name: "CO2".to_string(),
+        };
+        let status = ApolloStatus {
+            sensors: HashMap::from([
+                ("scd30_co2".to_string(), co2(480.0)),
+                ("scd4x_co2".to_string(), co2(510.0)),
+            ]),
+            device_name: "Test Device".to_string(),
+            info: None,
+        };
+
+        // Candidates are picked in the configured order, not by id
+        metrics.update_device("192.168.1.100", &status).unwrap();
+        let output = metrics.gather().unwrap();
+        assert!(output.contains(
+            r#"apollo_air1_co2_ppm{device="Test Device",host="192.168.1.100",location=""} 510"#
+        ));
+        assert!(!output.contains(r#"id="scd4x_co2""#));
+    }
+
+    #[test]
+    fn test_other_sensors() {
+        let metrics = Metrics::new(MetricsOptions::default()).unwrap();
+
+        let mut sensors = HashMap::new();
+        sensors.insert(
+            "probe_temperature".to_string(),
+            SensorValue {
+                value: 19.5,
+                unit: "°C".to_string(),
+                name: "probe_temperature".to_string(),
+            },
+        );
+        let status = ApolloStatus {
+            sensors,
+            device_name: "Test Device".to_string(),
+            info: None,
+        };
+
+        metrics.update_device("192.168.1.100", &status).unwrap();
+        let output = metrics.gather().unwrap();
+        assert!(output.contains(
+            r#"apollo_air1_sensor{device="Test Device",host="192.168.1.100",id="probe_temperature",location=""} 19.5"#
+        ));
+
+        metrics.remove_device("Test Device", "192.168.1.100");
+        let output = metrics.gather().unwrap();
+        assert!(!output.contains("probe_temperature"));
+    }
+
+    #[test]
+    fn test_non_finite_values_skipped() {
+        let metrics = Metrics::new(MetricsOptions::default()).unwrap();
+
+        let mut sensors = HashMap::new();
+        for (id, value) in [
+            ("co2", f64::NAN),
+            ("pm__2_5_m_weight_concentration", f64::INFINITY),
+            ("probe_temperature", f64::NAN),
+            ("sen55_humidity", 45.0),
+        ] {
+            sensors.insert(
+                id.to_string(),
+                SensorValue {
+                    value,
+                    unit: String::new(),
+                    name: id.to_string(),
+                },
+            );
+        }
+        let status = ApolloStatus {
+            sensors,
+            device_name: "Test Device".to_string(),
+            info: None,
+        };
+
+        metrics.update_device("192.168.1.100", &status).unwrap();
+        let output = metrics.gather().unwrap();
+        assert!(!output.contains("NaN"));
+        assert!(!output.contains("Inf"));
+        assert!(!output.contains("apollo_air1_co2_ppm{"));
+        assert!(!output.contains("apollo_air1_aqi{"));
+        assert!(output.contains("apollo_air1_humidity_percent{"));
+    }
+
+    #[test]
+    fn test_sensor_unit_info() {
+        let metrics = Metrics::new(MetricsOptions::default()).unwrap();
+
+        let mut sensors = HashMap::new();
+        sensors.insert(
+            "co2".to_string(),
+            SensorValue {
+                value: 450.0,
+                unit: "ppm".to_string(),
+                name: "CO2".to_string(),
+            },
+        );
+        let mut status = ApolloStatus {
+            sensors,
+            device_name: "Test Device".to_string(),
+            info: None,
+        };
+
+        metrics.update_device("192.168.1.100", &status).unwrap();
+        let output = metrics.gather().unwrap();
+        assert!(output.contains(
+            r#"apollo_air1_sensor_unit_info{device="Test Device",host="192.168.1.100",location="",sensor="co2",unit="ppm"} 1"#
+        ));
+
+        // A changed unit replaces the old series
+        status.sensors.get_mut("co2").unwrap().unit = "mg/m³".to_string();
+        metrics.update_device("192.168.1.100", &status).unwrap();
+        let output = metrics.gather().unwrap();
+        assert!(output.contains(r#"unit="mg/m³""#));
+        assert!(!output.contains(r#"unit="ppm""#));
+
+        metrics.remove_device("Test Device", "192.168.1.100");
+        let output = metrics.gather().unwrap();
+        assert!(!output.contains("apollo_air1_sensor_unit_info{"));
+    }
+
+    #[test]
+    fn test_remove_device() {
+        let metrics = Metrics::new(MetricsOptions::default()).unwrap();
+
+        let mut sensors = HashMap::new();
+        sensors.insert(
+            "co2".to_string(),
+            SensorValue {
+                value: 450.0,
+                unit: "ppm".to_string(),
+                name: "CO2".to_string(),
+            },
+        );
+        sensors.insert(
+            "pm__2_5_m_weight_concentration".to_string(),
+            SensorValue {
+                value: 12.5,
+                unit: "µg/m³".to_string(),
+                name: "PM2.5".to_string(),
+            },
+        );
+
+        let removed = ApolloStatus {
+            sensors: sensors.clone(),
+            device_name: "Old Device".to_string(),
+            info: None,
+        };
+        let kept = ApolloStatus {
+            sensors,
+            device_name: "Test Device".to_string(),
+            info: None,
+        };
+
+        metrics.update_device("192.168.1.100", &removed).unwrap();
+        metrics.update_device("192.168.1.101", &kept).unwrap();
+        metrics.record_scrape_error("Old Device", "192.168.1.100");
+
+        metrics.remove_device("Old Device", "192.168.1.100");
+
+        let output = metrics.gather().unwrap();
+        assert!(!output.contains("Old Device"));
+        assert!(output.contains(r#"device="Test Device""#));
+        assert!(output.contains("apollo_air1_aqi_info{"));
+    }
+
+    #[test]
+    fn test_sensor_staleness() {
+        let options = MetricsOptions {
+            sensor_stale_after: Some(Duration::from_secs(300)),
+            ..Default::default()
+        };
+        let metrics = Metrics::new(options).unwrap();
+        let start = Instant::now();
+        let update = |sensor: &str, value: f64, elapsed: u64| {
+            metrics.update_sensor_staleness(
+                "Test Device",
+                "192.168.1.100",
+                "",
+                sensor,
+                value,
+                start + Duration::from_secs(elapsed),
+            )
+        };
+        let stale = |sensor: &str| {
+            metrics
+                .sensor_stale
+                .with_label_values(&["Test Device", "192.168.1.100", "", sensor])
+                .get()
+        };
+
+        update("co2", 450.0, 0);
+        update("sen55_temperature", 21.0, 0);
+        assert_eq!(stale("co2"), 0);
+
+        // The temperature keeps changing while CO2 is stuck
+        update("co2", 450.0, 200);
+        update("sen55_temperature", 21.5, 200);
+        update("co2", 450.0, 400);
+        update("sen55_temperature", 21.0, 400);
+        assert_eq!(stale("co2"), 1);
+        assert_eq!(stale("sen55_temperature"), 0);
+
+        // A new value clears the flag
+        update("co2", 455.0, 500);
+        assert_eq!(stale("co2"), 0);
+
+        // States are expected to stay the same
+        update(PROBLEM_SENSOR, 0.0, 0);
+        update(PROBLEM_SENSOR, 0.0, 1000);
+        assert!(
+            !metrics
+                .gather()
+                .unwrap()
+                .contains(r#"sensor="sensor_problem""#)
+        );
+
+        metrics.remove_device("Test Device", "192.168.1.100");
+        assert!(
+            !metrics
+                .gather()
+                .unwrap()
+                .contains("apollo_air1_sensor_stale{")
+        );
+    }
+
+    #[test]
+    fn test_device_location() {
+        let metrics = Metrics::new(MetricsOptions::default()).unwrap();
+        metrics.set_location("192.168.1.100", "Building A");
+
+        let status = ApolloStatus {
+            sensors: HashMap::from([(
+                "co2".to_string(),
+                SensorValue {
+                    value: 450.0,
+                    unit: "ppm".to_string(),
+                    name: "CO2".to_string(),
+                },
+            )]),
+            device_name: "Test Device".to_string(),
+            info: None,
+        };
+        metrics.update_device("192.168.1.100", &status).unwrap();
+        metrics.record_scrape_error("Test Device", "192.168.1.100");
+
+        let output = metrics.gather().unwrap();
+        assert!(output.contains(
+            r#"apollo_air1_co2_ppm{device="Test Device",host="192.168.1.100",location="Building A"} 450"#
+        ));
+        assert!(output.contains(
+            r#"apollo_air1_scrape_errors_total{device="Test Device",host="192.168.1.100",location="Building A"} 1"#
+        ));
+
+        metrics.remove_device("Test Device", "192.168.1.100");
+        assert!(!metrics.gather().unwrap().contains("Building A"));
+    }
+
+    #[test]
+    fn test_device_down_marking() {
+        let metrics = Metrics::new(MetricsOptions::default()).unwrap();
+
+        metrics.mark_device_down("Test Device", "192.168.1.100");
+
+        let output = metrics.gather().unwrap();
+        assert!(output.contains("apollo_air1_device_up"));
+        assert!(output.contains(r#"device="Test Device""#));
+        assert!(output.contains("} 0"));
+    }
+
+    #[test]
+    fn test_scrape_duration_cleared_on_down() {
+        let metrics = Metrics::new(MetricsOptions::default()).unwrap();
+
+        metrics.record_scrape_duration("Test Device", "192.168.1.100", 0.25);
+        let output = metrics.gather().unwrap();
+        assert!(output.contains(
+            r#"apollo_air1_scrape_duration_seconds{device="Test Device",host="192.168.1.100",location=""} 0.25"#
+        ));
+
+        metrics.mark_device_down("Test Device", "192.168.1.100");
+        let output = metrics.gather().unwrap();
+        assert!(!output.contains("apollo_air1_scrape_duration_seconds{"));
+    }
+
+    #[test]
+    fn test_clear_on_down() {
+        let mut sensors = HashMap::new();
+        sensors.insert(
+            "co2".to_string(),
+            SensorValue {
+                value: 450.0,
+                unit: "ppm".to_string(),
+                name: "CO2".to_string(),
+            },
+        );
+        let status = ApolloStatus {
+            sensors,
+            device_name: "Test Device".to_string(),
+            info: None,
+        };
+
+        // Default: last readings are kept
+        let metrics = Metrics::new(MetricsOptions::default()).unwrap();
+        metrics.update_device("192.168.1.100", &status).unwrap();
+        metrics.mark_device_down("Test Device", "192.168.1.100");
+        assert!(metrics.gather().unwrap().contains("apollo_air1_co2_ppm{"));
+
+        let metrics = Metrics::new(MetricsOptions {
+            clear_on_down: true,
+            ..Default::default()
+        })
+        .unwrap();
+        metrics.update_device("192.168.1.100", &status).unwrap();
+        metrics.mark_device_down("Test Device", "192.168.1.100");
+
+        let output = metrics.gather().unwrap();
+        assert!(!output.contains("apollo_air1_co2_ppm{"));
+        assert!(output.contains(
+            r#"apollo_air1_device_up{device="Test Device",host="192.168.1.100",location=""} 0"#
+        ));
+    }
+
+    #[test]
+    fn test_last_scrape_timestamp_untouched_on_down() {
+        let metrics = Metrics::new(MetricsOptions::default()).unwrap();
+
+        metrics.mark_device_down("Test Device", "192.168.1.100");
+
+        let output = metrics.gather().unwrap();
+        assert!(!output.contains("apollo_air1_last_scrape_timestamp_seconds{"));
+    }
+
+    #[test]
+    fn test_disabled_metrics() {
+        let options = MetricsOptions {
+            disabled_metrics: HashSet::from([
+                "illuminance_lux".to_string(),
+                "esp_temperature_celsius".to_string(),
+            ]),
+            ..Default::default()
+        };
+        let metrics = Metrics::new(options).unwrap();
+
+        let sensors = [
+            ("co2", 450.0),
+            ("illuminance", 120.0),
+            ("esp_temperature", 41.0),
+        ]
+        .into_iter()
+        .map(|(id, value)| {
+            let sensor = SensorValue {
+                value,
+                unit: String::new(),
+                name: id.to_string(),
+            };
+            (id.to_string(), sensor)
+        })
+        .collect();
+        let status = ApolloStatus {
+            sensors,
+            device_name: "Test Device".to_string(),
+            info: None,
+        };
+        metrics.update_device("192.168.1.100", &status).unwrap();
+
+        let output = metrics.gather().unwrap();
+        assert!(!output.contains("apollo_air1_illuminance_lux"));
+        assert!(!output.contains("apollo_air1_esp_temperature_celsius"));
+        assert!(output.contains("apollo_air1_co2_ppm{"));
+
+        let options = MetricsOptions {
+            disabled_metrics: HashSet::from(["iluminance_lux".to_string()]),
+            ..Default::default()
+        };
+        let err = Metrics::new(options).err().unwrap().to_string();
+        assert!(err.contains("iluminance_lux"), "{}", err);
+        assert!(err.contains("illuminance_lux"), "{}", err);
+    }
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new(MetricsOptions::default()).unwrap();
+
+        let (text, openmetrics) = metrics.render().unwrap();
+        assert!(text.contains("apollo_air1_scrape_generation_total 1\n"));
+        assert!(openmetrics.contains("apollo_air1_scrape_generation_total 1\n"));
+        assert!(openmetrics.ends_with("# EOF\n"));
+
+        let (text, _) = metrics.render().unwrap();
+        assert!(text.contains("apollo_air1_scrape_generation_total 2\n"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_process_metrics() {
+        let options = MetricsOptions {
+            process_metrics: true,
+            ..Default::default()
+        };
+        let output = Metrics::new(options).unwrap().gather().unwrap();
+        assert!(output.contains("process_resident_memory_bytes"));
+        assert!(output.contains("process_cpu_seconds_total"));
+        assert!(output.contains("process_open_fds"));
+
+        let options = MetricsOptions {
+            disabled_metrics: HashSet::from(["process_open_fds".to_string()]),
+            ..Default::default()
+        };
+        let output = Metrics::new(options).unwrap().gather().unwrap();
+        assert!(!output.contains("process_"));
+    }
+
+    #[test]
+    fn test_build_info() {
+        let metrics = Metrics::new(MetricsOptions::default()).unwrap();
+        let output = metrics.gather().unwrap();
+        assert!(output.contains(&format!(
+            r#"apollo_air1_build_info{{git_sha="{}",rustc="{}",version="{}"}} 1"#,
+            env!("APOLLO_GIT_SHA"),
+            env!("APOLLO_RUSTC_VERSION"),
+            env!("CARGO_PKG_VERSION")
+        )));
+    }
+
+    #[test]
+    fn test_devices_up() {
+        let metrics = Metrics::new(MetricsOptions::default()).unwrap();
+        metrics.set_devices_total(3);
+        metrics.set_poll_interval(Duration::from_secs(30));
+
+        for host in ["192.168.1.100", "192.168.1.101"] {
+            let status = ApolloStatus {
+                sensors: HashMap::new(),
+                device_name: host.to_string(),
+                info: None,
+            };
+            metrics.update_device(host, &status).unwrap();
+        }
+        metrics.mark_device_down("192.168.1.101", "192.168.1.101");
+
+        let output = metrics.gather().unwrap();
+        assert!(output.contains("apollo_air1_devices_total 3\n"));
+        assert!(output.contains("apollo_air1_devices_up 1\n"));
+        assert!(output.contains("apollo_air1_poll_interval_seconds 30\n"));
+
+        metrics.remove_device("192.168.1.100", "192.168.1.100");
+        let output = metrics.gather().unwrap();
+        assert!(output.contains("apollo_air1_devices_up 0\n"));
+    }
+
+    #[test]
+    fn test_sample_stats() {
+        let mut options = MetricsOptions::default();
+        options
+            .sensor_aliases
+            .insert("scd40_co2".to_string(), "co2".to_string());
+        let metrics = Metrics::new(options).unwrap();
+
+        let readings = HashMap::from([
+            ("scd40_co2".to_string(), vec![600.0, 640.0, 620.0]),
+            (
+                "pm__2_5_m_weight_concentration".to_string(),
+                vec![3.0, f64::NAN, 9.0],
+            ),
+            ("sen55_voc".to_string(), vec![100.0, 120.0]),
+        ]);
+        metrics.update_sample_stats("office", "192.168.1.100", &readings);
+
+        let output = metrics.gather().unwrap();
+        let labels = r#"{device="office",host="192.168.1.100",location=""}"#;
+        assert!(output.contains(&format!("apollo_air1_co2_ppm_min{labels} 600\n")));
+        assert!(output.contains(&format!("apollo_air1_co2_ppm_max{labels} 640\n")));
+        assert!(output.contains(&format!("apollo_air1_co2_ppm_avg{labels} 620\n")));
+        assert!(output.contains(&format!("apollo_air1_pm2_5_ugm3_min{labels} 3\n")));
+        assert!(output.contains(&format!("apollo_air1_pm2_5_ugm3_max{labels} 9\n")));
+        assert!(output.contains(&format!("apollo_air1_pm2_5_ugm3_avg{labels} 6\n")));
+        assert!(!output.contains("apollo_air1_voc_index_min"));
+
+        metrics.remove_device("office", "192.168.1.100");
+        assert!(
+            !metrics
+                .gather()
+                .unwrap()
+                .contains("apollo_air1_co2_ppm_min{")
+        );
+    }
+
+    #[test]
+    fn test_stale_devices() {
+        let metrics = Metrics::new(MetricsOptions::default()).unwrap();
+        metrics.watch_device("office", "192.168.1.100", Duration::from_secs(90));
+        let status = ApolloStatus {
+            sensors: HashMap::new(),
+            device_name: "office".to_string(),
+            info: None,
+        };
+        metrics.update_device("192.168.1.100", &status).unwrap();
+
+        assert!(metrics.mark_stale_devices(Instant::now()).is_empty());
+
+        let later = Instant::now() + Duration::from_secs(91);
+        assert_eq!(
+            metrics.mark_stale_devices(later),
+            vec!["office".to_string()]
+        );
+        let output = metrics.gather().unwrap();
+        assert!(output.contains(
+            r#"apollo_air1_device_up{device="office",host="192.168.1.100",location=""} 0"#
+        ));
+
+        // Only marked down once until the device updates again
+        assert!(metrics.mark_stale_devices(later).is_empty());
+        metrics.update_device("192.168.1.100", &status).unwrap();
+        let output = metrics.gather().unwrap();
+        assert!(output.contains(
+            r#"apollo_air1_device_up{device="office",host="192.168.1.100",location=""} 1"#
+        ));
+    }
+
+    #[test]
+    fn test_scrape_errors_counter() {
+        let metrics = Metrics::new(MetricsOptions::default()).unwrap();
+
+        metrics.record_scrape_error("Test Device", "192.168.1.100");
+        metrics.record_scrape_error("Test Device", "192.168.1.100");
+
+        let output = metrics.gather().unwrap();
+        assert!(output.contains("# TYPE apollo_air1_scrape_errors_total counter"));
+        assert!(output.contains(
+            r#"apollo_air1_scrape_errors_total{device="Test Device",host="192.168.1.100",location=""} 2"#
+        ));
+    }
+
+    #[test]
+    fn test_device_last_error() {
+        let metrics = Metrics::new(MetricsOptions::default()).unwrap();
+
+        metrics.record_last_error("Test Device", "192.168.1.100", "timeout");
+        metrics.record_last_error("Test Device", "192.168.1.100", "http_401");
+
+        let output = metrics.gather().unwrap();
+        assert!(output.contains(
+            r#"apollo_air1_device_last_error{device="Test Device",host="192.168.1.100",location="",reason="http_401"} 1"#
+        ));
+        assert!(!output.contains(r#"reason="timeout""#));
+
+        let status = ApolloStatus {
+            sensors: HashMap::from([(
+                "co2".to_string(),
+                SensorValue {
+                    value: 450.0,
+                    unit: "ppm".to_string(),
+                    name: "CO2".to_string(),
+                },
+            )]),
+            device_name: "Test Device".to_string(),
+            info: None,
+        };
+        metrics.update_device("192.168.1.100", &status).unwrap();
+        let output = metrics.gather().unwrap();
+        assert!(!output.contains("apollo_air1_device_last_error{"));
+    }
+
+    #[test]
+    fn test_device_overview() {
+        let metrics = Metrics::new(MetricsOptions::default()).unwrap();
+        assert!(metrics.device_overview().is_empty());
+
+        let status = ApolloStatus {
+            sensors: HashMap::from([(
+                "co2".to_string(),
+                SensorValue {
+                    value: 450.0,
+                    unit: "ppm".to_string(),
+                    name: "CO2".to_string(),
+                },
+            )]),
+            device_name: "Office".to_string(),
+            info: None,
+        };
+        metrics.update_device("192.168.1.100", &status).unwrap();
+        metrics.mark_device_down("Bedroom", "192.168.1.101");
+
+        let devices = metrics.device_overview();
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].device, "Bedroom");
+        assert!(!devices[0].up);
+        assert_eq!(devices[0].last_scrape, None);
+        assert_eq!(devices[1].device, "Office");
+        assert_eq!(devices[1].host, "192.168.1.100");
+        assert!(devices[1].up);
+        assert!(devices[1].last_scrape.unwrap() > 0.0);
+    }
+
+    #[test]
+    fn test_device_reboots() {
+        let metrics = Metrics::new(MetricsOptions::default()).unwrap();
+        let update = |uptime: f64| {
+            let status = ApolloStatus {
+                sensors: HashMap::from([(
+                    "uptime".to_string(),
+                    SensorValue {
+                        value: uptime,
+                        unit: "s".to_string(),
+                        name: "Uptime".to_string(),
+                    },
+                )]),
+                device_name: "Test Device".to_string(),
+                info: None,
+            };
+            metrics.update_device("192.168.1.100", &status).unwrap();
+        };
+        let reboots = |count: u64| {
+            format!(
+                r#"apollo_air1_device_reboots_total{{device="Test Device",host="192.168.1.100",location=""}} {count}"#
+            )
+        };
+
+        update(3600.0);
+        assert!(metrics.gather().unwrap().contains(&reboots(0)));
+
+        update(3660.0);
+        update(12.0);
+        update(72.0);
+        assert!(metrics.gather().unwrap().contains(&reboots(1)));
+
+        update(5.0);
+        assert!(metrics.gather().unwrap().contains(&reboots(2)));
+
+        metrics.remove_device("Test Device", "192.168.1.100");
+        assert!(
+            !metrics
+                .gather()
+                .unwrap()
+                .contains("apollo_air1_device_reboots_total{")
+        );
+    }
+
+    #[test]
+    fn test_device_connected() {
+        let metrics = Metrics::new(MetricsOptions::default()).unwrap();
+
+        metrics.set_device_connected("Test Device", "192.168.1.100", true);
+        let output = metrics.gather().unwrap();
+        assert!(output.contains(
+            r#"apollo_air1_device_connected{device="Test Device",host="192.168.1.100",location=""} 1"#
+        ));
+
+        metrics.set_device_connected("Test Device", "192.168.1.100", false);
+        let output = metrics.gather().unwrap();
+        assert!(output.contains(
+            r#"apollo_air1_device_connected{device="Test Device",host="192.168.1.100",location=""} 0"#
+        ));
+
+        metrics.remove_device("Test Device", "192.168.1.100");
+        let output = metrics.gather().unwrap();
+        assert!(!output.contains("apollo_air1_device_connected{"));
+    }
+
+    #[test]
+    fn test_poll_cycles() {
+        let metrics = Metrics::new(MetricsOptions::default()).unwrap();
+
+        metrics.record_poll_cycle("Test Device", "192.168.1.100", 0.5);
+        metrics.record_poll_cycle("Test Device", "192.168.1.100", 0.25);
+
+        let output = metrics.gather().unwrap();
+        assert!(output.contains(
+            r#"apollo_air1_poll_cycle_duration_seconds{device="Test Device",host="192.168.1.100",location=""} 0.25"#
+        ));
+        assert!(output.contains(
+            r#"apollo_air1_poll_cycles_total{device="Test Device",host="192.168.1.100",location=""} 2"#
+        ));
+
+        metrics.remove_device("Test Device", "192.168.1.100");
+        assert!(!metrics.gather().unwrap().contains("apollo_air1_poll_cycle"));
+    }
+
+    #[test]
+    fn test_gather_device() {
+        let metrics = Metrics::new(MetricsOptions::default()).unwrap();
+
+        metrics.record_scrape_error("Office", "192.168.1.100");
+        metrics.record_scrape_error("Bedroom", "192.168.1.101");
+        metrics.record_scrape_duration("Office", "192.168.1.100", 0.25);
+
+        let output = metrics.gather_device("192.168.1.100").unwrap();
+        assert!(output.contains(r#"host="192.168.1.100""#));
+        assert!(output.contains("apollo_air1_scrape_duration_seconds"));
+        assert!(!output.contains("192.168.1.101"));
+    }
+
+    #[test]
+    fn test_aqhi() {
+        let metrics = Metrics::new(MetricsOptions {
+            air_quality_standard: AirQualityStandard::CanadaAqhi,
+            ..Default::default()
+        })
+        .unwrap();
+
+        let status = |pm25: f64| {
+            let mut sensors = HashMap::new();
+            sensors.insert(
+                "pm__2_5_m_weight_concentration".to_string(),
+                SensorValue {
+                    value: pm25,
+                    unit: "µg/m³".to_string(),
+                    name: "PM2.5".to_string(),
+                },
+            );
+            ApolloStatus {
+                sensors,
+                device_name: "Test Device".to_string(),
+                info: None,
+            }
+        };
+
+        metrics
+            .update_device("192.168.1.100", &status(100.0))
+            .unwrap();
+        let output = metrics.gather().unwrap();
+        assert!(output.contains(
+            r#"apollo_air1_aqhi{device="Test Device",host="192.168.1.100",location=""} 5"#
+        ));
+        assert!(output.contains(r#"category="Moderate Risk""#));
+        // The US AQI isn't exported under another standard
+        assert!(!output.contains("apollo_air1_aqi{"));
+
+        // A category change replaces the info series
+        metrics
+            .update_device("192.168.1.100", &status(200.0))
+            .unwrap();
+        let output = metrics.gather().unwrap();
+        assert!(output.contains(r#"category="High Risk""#));
+        assert!(!output.contains(r#"category="Moderate Risk""#));
+
+        metrics.remove_device("Test Device", "192.168.1.100");
+        assert!(!metrics.gather().unwrap().contains("Test Device"));
+    }
+
+    #[test]
+    fn test_caqi() {
+        let metrics = Metrics::new(MetricsOptions {
+            air_quality_standard: AirQualityStandard::EuCaqi,
+            ..Default::default()
+        })
+        .unwrap();
+
+        let mut sensors = HashMap::new();
+        sensors.insert(
+            "pm__2_5_m_weight_concentration".to_string(),
+            SensorValue {
+                value: 20.0,
+                unit: "µg/m³".to_string(),
+                name: "PM2.5".to_string(),
+            },
+        );
+        sensors.insert(
+            "pm__10_m_weight_concentration".to_string(),
+            SensorValue {
+                value: 30.0,
+                unit: "µg/m³".to_string(),
+                name: "PM10".to_string(),
+            },
+        );
+        let status = ApolloStatus {
+            sensors,
+            device_name: "Test Device".to_string(),
+            info: None,
+        };
+
+        metrics.update_device("192.168.1.100", &status).unwrap();
+        let output = metrics.gather().unwrap();
+        assert!(output.contains(
+            r#"apollo_air1_caqi{device="Test Device",host="192.168.1.100",location=""} 33"#
+        ));
+        assert!(output.contains(
+            r#"apollo_air1_caqi_info{category="Low",device="Test Device",host="192.168.1.100",location="",primary_pollutant="PM2.5"} 1"#
+        ));
+        assert!(output.contains(
+            r#"apollo_air1_caqi_pm10{device="Test Device",host="192.168.1.100",location=""} 30"#
+        ));
+        assert!(!output.contains("apollo_air1_aqi{"));
+
+        metrics.remove_device("Test Device", "192.168.1.100");
+        assert!(!metrics.gather().unwrap().contains("Test Device"));
+    }
+
+    #[test]
+    fn test_iaq_score() {
+        let mut sensors = HashMap::new();
+        sensors.insert(
+            "co2".to_string(),
+            SensorValue {
+                value: 1500.0,
+                unit: "ppm".to_string(),
+                name: "CO2".to_string(),
+            },
+        );
+        let status = ApolloStatus {
+            sensors,
+            device_name: "Test Device".to_string(),
+            info: None,
+        };
+
+        // Off by default
+        let metrics = Metrics::new(MetricsOptions::default()).unwrap();
+        metrics.update_device("192.168.1.100", &status).unwrap();
+        assert!(!metrics.gather().unwrap().contains("apollo_air1_iaq_score{"));
+
+        let metrics = Metrics::new(MetricsOptions {
+            enable_iaq: true,
+            ..Default::default()
+        })
+        .unwrap();
+        metrics.update_device("192.168.1.100", &status).unwrap();
+        assert!(metrics.gather().unwrap().contains(
+            r#"apollo_air1_iaq_score{device="Test Device",host="192.168.1.100",location=""} 75"#
+        ));
+    }
+
+    #[test]
+    fn test_aqi_smoothing() {
+        let options = MetricsOptions {
+            aqi_smoothing: Some(0.5),
+            ..Default::default()
+        };
+        let metrics = Metrics::new(options).unwrap();
+
+        for value in [5.0, 45.0] {
+            let mut sensors = HashMap::new();
+            sensors.insert(
+                "pm__2_5_m_weight_concentration".to_string(),
+                SensorValue {
+                    value,
+                    unit: "µg/m³".to_string(),
+                    name: "PM2.5".to_string(),
+                },
+            );
+            let status = ApolloStatus {
+                sensors,
+                device_name: "Test Device".to_string(),
+                info: None,
+            };
+            metrics.update_device("192.168.1.100", &status).unwrap();
+        }
+
+        // The raw reading is exported as-is, the AQI uses the average of 25
+        let smoothed = aqi::calculate_aqi(
+            Some(25.0),
+            None,
+            &aqi::PM25_BREAKPOINTS,
+            aqi::PrimaryPolicy::Max,
+        )
+        .unwrap()
+        .aqi;
+        let output = metrics.gather().unwrap();
+        let labels = r#"{device="Test Device",host="192.168.1.100",location=""}"#;
+        assert!(output.contains(&format!("apollo_air1_pm2_5_ugm3{labels} 45\n")));
+        assert!(output.contains(&format!("apollo_air1_aqi_pm25{labels} {smoothed}\n")));
+    }
+
+    #[test]
+    fn test_aqi_calculation_integration() {
+        let metrics = Metrics::new(MetricsOptions::default()).unwrap();
+
+        let mut sensors = HashMap::new();
+        // Add PM2.5 data that should result in Moderate AQI (~68)
+        sensors.insert(
+            "pm__2_5_m_weight_concentration".to_string(),
+            SensorValue {
+                value: 20.0,
+                unit: "µg/m³".to_string(),
+                name: "PM2.5".to_string(),
+            },
+        );
+        // Add PM10 data that should result in lower AQI (~28)
+        sensors.insert(
+            "pm__10_m_weight_concentration".to_string(),
+            SensorValue {
+                value: 30.0,
+                unit: "µg/m³".to_string(),
+                name: "PM10".to_string(),
+            },
+        );
+
+        let status = ApolloStatus {
+            sensors,
+            device_name: "Test Device".to_string(),
+            info: None,
+        };
+
+        metrics.update_device("192.168.1.100", &status).unwrap();
+
+        let output = metrics.gather().unwrap();
+        // Check overall AQI metric (71 with 2024 EPA breakpoints)
+        assert!(output.contains("apollo_air1_aqi{"));
+        assert!(output.contains("71")); // Expected AQI value with 2024 breakpoints
+
+        // Check per-pollutant sub-AQI metrics
+        assert!(output.contains("apollo_air1_aqi_pm25{"));
+        assert!(output.contains("apollo_air1_aqi_pm10{"));
+
+        // Check info metric with category labels
+        assert!(output.contains("apollo_air1_aqi_info{"));
+        assert!(output.contains("category=\"Moderate\""));
+        assert!(output.contains("primary_pollutant=\"PM2.5\""));
+        assert!(output.contains("color=\"#FFFF00\""));
+        assert!(output.contains(
+            r#"message="Air quality is acceptable. However, there may be a risk for some people"#
+        ));
+
+        // Check numeric category (1 = Moderate)
+        assert!(output.contains(
+            r#"apollo_air1_aqi_category{device="Test Device",host="192.168.1.100",location=""} 1"#
+        ));
+    }
+
+    #[test]
+    fn test_voc_nox_raw() {
+        let metrics = Metrics::new(MetricsOptions::default()).unwrap();
+
+        let mut sensors = HashMap::new();
+        for (id, value) in [("sen55_voc", 100.0), ("sen55_voc_raw", 31250.0)] {
+            sensors.insert(
+                id.to_string(),
+                SensorValue {
+                    value,
+                    unit: String::new(),
+                    name: id.to_string(),
+                },
+            );
+        }
+        let status = ApolloStatus {
+            sensors,
+            device_name: "Test Device".to_string(),
+            info: None,
+        };
+        metrics.update_device("192.168.1.100", &status).unwrap();
+
+        let output = metrics.gather().unwrap();
+        assert!(output.contains(
+            r#"apollo_air1_voc_raw{device="Test Device",host="192.168.1.100",location=""} 31250"#
+        ));
+        // Firmwares without the raw NOx signal get no series for it
+        assert!(!output.contains("apollo_air1_nox_raw{"));
+        assert!(!output.contains("sensor=\"sen55_voc_raw\""));
+    }
+
+    #[test]
+    fn test_aqi_require_pm25() {
+        let metrics = Metrics::new(MetricsOptions {
+            aqi_require_pm25: true,
+            ..Default::default()
+        })
+        .unwrap();
+
+        let pm10_only = ApolloStatus {
+            sensors: HashMap::from([(
+                "pm__10_m_weight_concentration".to_string(),
+                SensorValue {
+                    value: 30.0,
+                    unit: "µg/m³".to_string(),
+                    name: "PM10".to_string(),
+                },
+            )]),
+            device_name: "Test Device".to_string(),
+            info: None,
+        };
+        metrics.update_device("192.168.1.100", &pm10_only).unwrap();
+        assert!(!metrics.gather().unwrap().contains("apollo_air1_aqi{"));
+
+        let mut status = pm10_only.clone();
+        status.sensors.insert(
+            "pm__2_5_m_weight_concentration".to_string(),
+            SensorValue {
+                value: 20.0,
+                unit: "µg/m³".to_string(),
+                name: "PM2.5".to_string(),
+            },
+        );
+        metrics.update_device("192.168.1.100", &status).unwrap();
+        let aqi = r#"apollo_air1_aqi{device="Test Device",host="192.168.1.100",location=""} 71"#;
+        assert!(metrics.gather().unwrap().contains(aqi));
+
+        // A PM10-only reading leaves the PM2.5-driven index in place
+        metrics.update_device("192.168.1.100", &pm10_only).unwrap();
+        let output = metrics.gather().unwrap();
+        assert!(output.contains(aqi));
+        assert!(output.contains(r#"primary_pollutant="PM2.5""#));
     }
 
     #[test]
-    #[ignore = "Metrics registry conflict in tests"]
     fn test_aqi_state_cleanup() {
-        let metrics = Metrics::new().unwrap();
+        let metrics = Metrics::new(MetricsOptions::default()).unwrap();
 
         // First update with Good AQI
         let mut sensors = HashMap::new();
@@ -511,6 +3410,7 @@ mod tests {
         let status = ApolloStatus {
             sensors: sensors.clone(),
             device_name: "Test Device".to_string(),
+            info: None,
         };
 
         metrics.update_device("192.168.1.100", &status).unwrap();
@@ -531,6 +3431,7 @@ mod tests {
         let status = ApolloStatus {
             sensors,
             device_name: "Test Device".to_string(),
+            info: None,
         };
 
         metrics.update_device("192.168.1.100", &status).unwrap();
@@ -539,5 +3440,6 @@ mod tests {
         // Should have Moderate, should NOT have Good anymore
         assert!(output.contains("category=\"Moderate\""));
         assert!(!output.contains("category=\"Good\""));
+        assert!(!output.contains(AqiCategory::Good.health_message()));
     }
 }
@@ -0,0 +1,209 @@
+//! Subscribes to the state topics ESPHome's MQTT component publishes, as an
+//! event-driven alternative to polling the web server.
+//!
+//! Only plaintext `mqtt://` brokers are supported.
+
+use anyhow::{Context, Result, anyhow, bail};
+use reqwest::Url;
+use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Packet, QoS};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::debug;
+
+use crate::apollo::{self, BasicAuth, SensorValue};
+
+/// Port used when the broker URL doesn't set one
+const DEFAULT_PORT: u16 = 1883;
+
+/// Interval of MQTT keep-alive pings, which also detect a dead connection
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+
+/// Requests queued between the client and its event loop
+const REQUEST_CAPACITY: usize = 10;
+
+/// Connection settings for an MQTT broker
+#[derive(Debug, Clone)]
+pub struct MqttBroker {
+    host: String,
+    port: u16,
+    credentials: Option<BasicAuth>,
+}
+
+impl MqttBroker {
+    /// Parses `mqtt://host[:port]`; the scheme may be omitted
+    pub fn new(url: &str, credentials: Option<BasicAuth>) -> Result<Self> {
+        let url = url.trim();
+        let full_url = if url.contains("://") {
+            url.to_string()
+        } else {
+            format!("mqtt://{}", url)
+        };
+
+        let parsed =
+            Url::parse(&full_url).with_context(|| format!("Invalid MQTT broker URL {:?}", url))?;
+        if parsed.scheme() != "mqtt" {
+            bail!(
+                "Invalid MQTT broker URL {:?}: scheme must be mqtt, not {}",
+                url,
+                parsed.scheme()
+            );
+        }
+        let host = parsed
+            .host_str()
+            .filter(|host| !host.is_empty())
+            .ok_or_else(|| anyhow!("Invalid MQTT broker URL {:?}: missing hostname", url))?;
+
+        Ok(Self {
+            host: host.trim_matches(['[', ']']).to_string(),
+            port: parsed.port().unwrap_or(DEFAULT_PORT),
+            credentials,
+        })
+    }
+
+    /// Subscribes to the sensor state topics under `topic_prefix`
+    ///
+    /// The connection is only established once updates are read.
+    pub async fn subscribe(&self, topic_prefix: &str) -> Result<StateUpdates> {
+        let mut options = MqttOptions::new(client_id(), self.host.as_str(), self.port);
+        options.set_keep_alive(KEEP_ALIVE);
+        if let Some(auth) = &self.credentials {
+            options.set_credentials(
+                auth.username.as_str(),
+                auth.password.as_deref().unwrap_or_default(),
+            );
+        }
+
+        let (client, events) = AsyncClient::new(options, REQUEST_CAPACITY);
+        let topic = format!("{}/sensor/+/state", topic_prefix);
+        client
+            .subscribe(topic.as_str(), QoS::AtMostOnce)
+            .await
+            .with_context(|| format!("Failed to subscribe to {}", topic))?;
+
+        Ok(StateUpdates {
+            _client: client,
+            events,
+            topic_prefix: topic_prefix.to_string(),
+        })
+    }
+
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+}
+
+/// Sensor updates received from a device's MQTT state topics
+pub struct StateUpdates {
+    /// Kept alive so the event loop's request channel stays open
+    _client: AsyncClient,
+    events: EventLoop,
+    topic_prefix: String,
+}
+
+impl StateUpdates {
+    /// Waits for the next state of a known sensor
+    ///
+    /// Fails when the broker connection drops; the caller is expected to
+    /// subscribe again.
+    pub async fn next_update(&mut self) -> Result<(String, SensorValue)> {
+        loop {
+            let event = self.events.poll().await.context("MQTT connection failed")?;
+
+            if let Event::Incoming(Packet::Publish(publish)) = event {
+                if let Some(update) =
+                    parse_state_message(&self.topic_prefix, &publish.topic, &publish.payload)
+                {
+                    return Ok(update);
+                }
+                debug!("Ignoring MQTT message on {}", publish.topic);
+            }
+        }
+    }
+}
+
+/// A client id unique to this connection, as brokers drop an existing
+/// session when another client connects with the same id
+fn client_id() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.subsec_nanos())
+        .unwrap_or_default();
+    format!("apollo-air1-exporter-{}-{:08x}", std::process::id(), nanos)
+}
+
+/// Maps a `<prefix>/sensor/<object_id>/state` message to a sensor update
+///
+/// ESPHome publishes bare numbers without a unit, so the unit is left empty.
+fn parse_state_message(
+    topic_prefix: &str,
+    topic: &str,
+    payload: &[u8],
+) -> Option<(String, SensorValue)> {
+    let object_id = topic
+        .strip_prefix(topic_prefix)?
+        .strip_prefix("/sensor/")?
+        .strip_suffix("/state")?;
+    if object_id.contains('/') {
+        return None;
+    }
+
+    let (id, name) = apollo::sensor_entity(&format!("sensor-{object_id}"))?;
+    let value = std::str::from_utf8(payload).ok()?.trim().parse().ok()?;
+
+    Some((
+        id,
+        SensorValue {
+            value,
+            unit: String::new(),
+            name,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_broker_url() {
+        let broker = MqttBroker::new("mqtt://192.168.1.10:1884", None).unwrap();
+        assert_eq!(broker.address(), "192.168.1.10:1884");
+
+        let broker = MqttBroker::new("broker.local", None).unwrap();
+        assert_eq!(broker.address(), "broker.local:1883");
+
+        assert!(MqttBroker::new("mqtts://broker.local", None).is_err());
+        assert!(MqttBroker::new("mqtt://", None).is_err());
+    }
+
+    #[test]
+    fn test_parse_state_message() {
+        let (id, sensor) =
+            parse_state_message("apollo-air-1", "apollo-air-1/sensor/co2/state", b"612").unwrap();
+        assert_eq!(id, "co2");
+        assert_eq!(sensor.name, "CO2");
+        assert_eq!(sensor.value, 612.0);
+
+        let (id, sensor) = parse_state_message(
+            "home/air",
+            "home/air/sensor/pm__2_5_m_weight_concentration/state",
+            b"3.5",
+        )
+        .unwrap();
+        assert_eq!(id, "pm__2_5_m_weight_concentration");
+        assert_eq!(sensor.value, 3.5);
+
+        // Sensors without a reading publish "nan"
+        let (_, sensor) =
+            parse_state_message("apollo-air-1", "apollo-air-1/sensor/co2/state", b"nan").unwrap();
+        assert!(sensor.value.is_nan());
+
+        assert!(parse_state_message("apollo-air-1", "other/sensor/co2/state", b"612").is_none());
+        assert!(
+            parse_state_message("apollo-air-1", "apollo-air-1/sensor/co2/state", b"on").is_none()
+        );
+        assert!(
+            parse_state_message("apollo-air-1", "apollo-air-1/binary_sensor/x/state", b"1")
+                .is_none()
+        );
+    }
+}
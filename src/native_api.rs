@@ -0,0 +1,513 @@
+//! Client for the ESPHome native API, for devices without the `web_server`
+//! component.
+//!
+//! Only the plaintext protocol is supported: each poll connects, lists the
+//! sensor entities, subscribes to states and disconnects once every sensor
+//! has reported. Devices with API encryption enabled are rejected with a
+//! clear error.
+
+use anyhow::{Context, Result, anyhow, bail};
+use reqwest::Url;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tracing::debug;
+
+use crate::apollo::{self, ApolloStatus, DeviceInfo, FailureReason, RequestError, SensorValue};
+
+/// Port the native API listens on unless the host URL names another
+pub const DEFAULT_PORT: u16 = 6053;
+
+/// Protocol version announced in the hello handshake
+const API_VERSION_MAJOR: u64 = 1;
+const API_VERSION_MINOR: u64 = 10;
+
+// Message types used by the exporter
+const HELLO_REQUEST: u64 = 1;
+const HELLO_RESPONSE: u64 = 2;
+const CONNECT_REQUEST: u64 = 3;
+const CONNECT_RESPONSE: u64 = 4;
+const DISCONNECT_REQUEST: u64 = 5;
+const PING_REQUEST: u64 = 7;
+const PING_RESPONSE: u64 = 8;
+const DEVICE_INFO_REQUEST: u64 = 9;
+const DEVICE_INFO_RESPONSE: u64 = 10;
+const LIST_ENTITIES_REQUEST: u64 = 11;
+const LIST_ENTITIES_SENSOR_RESPONSE: u64 = 16;
+const LIST_ENTITIES_DONE_RESPONSE: u64 = 19;
+const SUBSCRIBE_STATES_REQUEST: u64 = 20;
+const SENSOR_STATE_RESPONSE: u64 = 25;
+
+/// Frame preambles: plaintext frames start with 0, Noise-encrypted ones with 1
+const PLAINTEXT_PREAMBLE: u8 = 0x00;
+const ENCRYPTED_PREAMBLE: u8 = 0x01;
+
+/// Largest frame accepted from a device
+const MAX_FRAME_LEN: u64 = 64 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct NativeApiClient {
+    host: String,
+    port: u16,
+    password: Option<String>,
+    timeout: Duration,
+}
+
+/// A sensor entity announced by the device
+#[derive(Debug)]
+struct SensorEntity {
+    object_id: String,
+    unit: String,
+}
+
+impl NativeApiClient {
+    /// Creates a client for a device host such as `http://192.168.1.100`;
+    /// the port defaults to 6053
+    pub fn new(host: &str, password: Option<String>, timeout: Duration) -> Result<Self> {
+        let url = Url::parse(host).with_context(|| format!("Invalid host {}", host))?;
+        let hostname = url
+            .host_str()
+            .ok_or_else(|| anyhow!("Invalid host {}: missing hostname", host))?;
+
+        Ok(Self {
+            host: hostname.trim_matches(['[', ']']).to_string(),
+            port: url.port().unwrap_or(DEFAULT_PORT),
+            password,
+            timeout,
+        })
+    }
+
+    /// Connects, collects the current state of every sensor and disconnects
+    pub async fn get_status(&self, device_name: &str) -> Result<ApolloStatus> {
+        tokio::time::timeout(self.timeout, self.fetch_status(device_name))
+            .await
+            .map_err(|_| {
+                anyhow::Error::new(RequestError::new(
+                    FailureReason::Timeout,
+                    format!(
+                        "Timed out reading the native API of {}:{}",
+                        self.host, self.port
+                    ),
+                ))
+            })?
+    }
+
+    async fn fetch_status(&self, device_name: &str) -> Result<ApolloStatus> {
+        debug!(
+            "Fetching status via native API from {}:{}",
+            self.host, self.port
+        );
+
+        let stream = TcpStream::connect((self.host.as_str(), self.port))
+            .await
+            .with_context(|| format!("Failed to connect to {}:{}", self.host, self.port))?;
+        let mut conn = Connection {
+            stream: BufReader::new(stream),
+        };
+
+        conn.send(
+            HELLO_REQUEST,
+            &Message::new()
+                .string(1, "apollo-air1-exporter")
+                .varint(2, API_VERSION_MAJOR)
+                .varint(3, API_VERSION_MINOR),
+        )
+        .await?;
+        conn.expect(HELLO_RESPONSE).await?;
+
+        conn.send(
+            CONNECT_REQUEST,
+            &Message::new().string(1, self.password.as_deref().unwrap_or("")),
+        )
+        .await?;
+        let response = conn.expect(CONNECT_RESPONSE).await?;
+        if decode_fields(&response)?.varint(1) == Some(1) {
+            bail!("Native API password rejected by {}", self.host);
+        }
+
+        conn.send(DEVICE_INFO_REQUEST, &Message::new()).await?;
+        let response = conn.expect(DEVICE_INFO_RESPONSE).await?;
+        let info = decode_fields(&response)?;
+        let info = DeviceInfo {
+            esphome_version: info.string(4).unwrap_or_default(),
+            project_name: info.string(8).unwrap_or_default(),
+            project_version: info.string(9).unwrap_or_default(),
+            ..Default::default()
+        };
+
+        // Entities arrive one message each, terminated by a done message
+        conn.send(LIST_ENTITIES_REQUEST, &Message::new()).await?;
+        let mut entities = HashMap::new();
+        loop {
+            let (message_type, payload) = conn.receive().await?;
+            match message_type {
+                LIST_ENTITIES_SENSOR_RESPONSE => {
+                    let fields = decode_fields(&payload)?;
+                    if let (Some(object_id), Some(key)) = (fields.string(1), fields.fixed32(2)) {
+                        let unit = fields.string(6).unwrap_or_default();
+                        entities.insert(key, SensorEntity { object_id, unit });
+                    }
+                }
+                LIST_ENTITIES_DONE_RESPONSE => break,
+                _ => {}
+            }
+        }
+        if entities.is_empty() {
+            bail!("No sensors found on device");
+        }
+
+        // The device sends the current state of every entity on subscribe
+        conn.send(SUBSCRIBE_STATES_REQUEST, &Message::new()).await?;
+        let mut sensors = HashMap::new();
+        let mut pending = entities.len();
+        while pending > 0 {
+            let (message_type, payload) = conn.receive().await?;
+            if message_type != SENSOR_STATE_RESPONSE {
+                continue;
+            }
+
+            let fields = decode_fields(&payload)?;
+            let Some(entity) = fields.fixed32(1).and_then(|key| entities.remove(&key)) else {
+                continue;
+            };
+            pending -= 1;
+
+            if fields.varint(3) == Some(1) {
+                debug!("Sensor {} has no state yet", entity.object_id);
+                continue;
+            }
+            let Some(value) = fields.fixed32(2).map(f32::from_bits) else {
+                continue;
+            };
+
+            let entity_id = format!("sensor-{}", entity.object_id);
+            if let Some((id, name)) = apollo::sensor_entity(&entity_id) {
+                debug!("Got {}: {} {}", name, value, entity.unit);
+                sensors.insert(
+                    id,
+                    SensorValue {
+                        value: value as f64,
+                        unit: entity.unit,
+                        name,
+                    },
+                );
+            }
+        }
+
+        // Best effort; the connection is dropped either way
+        let _ = conn.send(DISCONNECT_REQUEST, &Message::new()).await;
+
+        Ok(ApolloStatus {
+            sensors,
+            device_name: device_name.to_string(),
+            info: Some(info),
+        })
+    }
+}
+
+struct Connection {
+    stream: BufReader<TcpStream>,
+}
+
+impl Connection {
+    async fn send(&mut self, message_type: u64, message: &Message) -> Result<()> {
+        let mut frame = vec![PLAINTEXT_PREAMBLE];
+        put_varint(&mut frame, message.0.len() as u64);
+        put_varint(&mut frame, message_type);
+        frame.extend_from_slice(&message.0);
+
+        self.stream.get_mut().write_all(&frame).await?;
+        Ok(())
+    }
+
+    /// Reads the next frame, answering keepalive pings along the way
+    async fn receive(&mut self) -> Result<(u64, Vec<u8>)> {
+        loop {
+            match self.stream.read_u8().await? {
+                PLAINTEXT_PREAMBLE => {}
+                ENCRYPTED_PREAMBLE => {
+                    bail!("Device requires native API encryption, which is not supported")
+                }
+                other => bail!("Invalid native API frame preamble {:#04x}", other),
+            }
+
+            let len = self.read_varint().await?;
+            if len > MAX_FRAME_LEN {
+                bail!("Native API frame of {} bytes is too large", len);
+            }
+            let message_type = self.read_varint().await?;
+            let mut payload = vec![0; len as usize];
+            self.stream.read_exact(&mut payload).await?;
+
+            if message_type == PING_REQUEST {
+                self.send(PING_RESPONSE, &Message::new()).await?;
+                continue;
+            }
+            return Ok((message_type, payload));
+        }
+    }
+
+    /// Reads frames until one of the given type arrives
+    async fn expect(&mut self, expected: u64) -> Result<Vec<u8>> {
+        loop {
+            let (message_type, payload) = self.receive().await?;
+            if message_type == expected {
+                return Ok(payload);
+            }
+            debug!("Ignoring native API message {}", message_type);
+        }
+    }
+
+    async fn read_varint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.stream.read_u8().await?;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        bail!("Invalid varint in native API frame")
+    }
+}
+
+/// Protobuf message encoder for the few field types requests use
+struct Message(Vec<u8>);
+
+impl Message {
+    fn new() -> Self {
+        Message(Vec::new())
+    }
+
+    fn varint(mut self, field: u32, value: u64) -> Self {
+        put_varint(&mut self.0, u64::from(field << 3));
+        put_varint(&mut self.0, value);
+        self
+    }
+
+    fn string(mut self, field: u32, value: &str) -> Self {
+        put_varint(&mut self.0, u64::from(field << 3 | 2));
+        put_varint(&mut self.0, value.len() as u64);
+        self.0.extend_from_slice(value.as_bytes());
+        self
+    }
+}
+
+fn put_varint(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+/// A decoded protobuf field value
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed32(u32),
+}
+
+/// Fields of a decoded message; later occurrences of a field win
+struct Fields<'a>(HashMap<u32, Field<'a>>);
+
+impl Fields<'_> {
+    fn varint(&self, field: u32) -> Option<u64> {
+        match self.0.get(&field)? {
+            Field::Varint(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    fn fixed32(&self, field: u32) -> Option<u32> {
+        match self.0.get(&field)? {
+            Field::Fixed32(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    fn string(&self, field: u32) -> Option<String> {
+        match self.0.get(&field)? {
+            Field::Bytes(bytes) => Some(String::from_utf8_lossy(bytes).into_owned()),
+            _ => None,
+        }
+    }
+}
+
+fn decode_fields(mut payload: &[u8]) -> Result<Fields<'_>> {
+    let mut fields = HashMap::new();
+
+    while !payload.is_empty() {
+        let tag = take_varint(&mut payload)?;
+        let field = (tag >> 3) as u32;
+        let value = match tag & 0x7 {
+            0 => Field::Varint(take_varint(&mut payload)?),
+            // 64-bit fields aren't used by any message read here
+            1 => {
+                take_bytes(&mut payload, 8)?;
+                continue;
+            }
+            2 => {
+                let len = take_varint(&mut payload)? as usize;
+                Field::Bytes(take_bytes(&mut payload, len)?)
+            }
+            5 => Field::Fixed32(u32::from_le_bytes(take_bytes(&mut payload, 4)?.try_into()?)),
+            other => bail!("Unsupported protobuf wire type {}", other),
+        };
+        fields.insert(field, value);
+    }
+
+    Ok(Fields(fields))
+}
+
+fn take_varint(payload: &mut &[u8]) -> Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = payload
+            .split_first()
+            .ok_or_else(|| anyhow!("Truncated protobuf varint"))?;
+        *payload = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    bail!("Invalid protobuf varint")
+}
+
+fn take_bytes<'a>(payload: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if payload.len() < len {
+        bail!("Truncated protobuf field");
+    }
+    let (bytes, rest) = payload.split_at(len);
+    *payload = rest;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn fixed32(field: u32, value: u32) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        put_varint(&mut buffer, u64::from(field << 3 | 5));
+        buffer.extend_from_slice(&value.to_le_bytes());
+        buffer
+    }
+
+    /// Serves one plaintext native API session with a CO2 and a temperature sensor
+    async fn fake_device(listener: TcpListener) {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut conn = Connection {
+            stream: BufReader::new(stream),
+        };
+
+        conn.expect(HELLO_REQUEST).await.unwrap();
+        conn.send(
+            HELLO_RESPONSE,
+            &Message::new()
+                .varint(1, 1)
+                .varint(2, 10)
+                .string(4, "apollo"),
+        )
+        .await
+        .unwrap();
+
+        let connect = conn.expect(CONNECT_REQUEST).await.unwrap();
+        assert_eq!(
+            decode_fields(&connect).unwrap().string(1).unwrap(),
+            "secret"
+        );
+        conn.send(CONNECT_RESPONSE, &Message::new()).await.unwrap();
+
+        conn.expect(DEVICE_INFO_REQUEST).await.unwrap();
+        conn.send(
+            DEVICE_INFO_RESPONSE,
+            &Message::new()
+                .string(4, "2025.5.0")
+                .string(8, "ApolloAutomation.AIR-1")
+                .string(9, "25.5.1.1"),
+        )
+        .await
+        .unwrap();
+
+        conn.expect(LIST_ENTITIES_REQUEST).await.unwrap();
+        for (object_id, key, unit) in [("co2", 1u32, "ppm"), ("sen55_temperature", 2, "°C")] {
+            let mut entity = Message::new().string(1, object_id).0;
+            entity.extend(fixed32(2, key));
+            entity.extend(Message::new().string(3, object_id).string(6, unit).0);
+            conn.send(LIST_ENTITIES_SENSOR_RESPONSE, &Message(entity))
+                .await
+                .unwrap();
+        }
+        conn.send(LIST_ENTITIES_DONE_RESPONSE, &Message::new())
+            .await
+            .unwrap();
+
+        conn.expect(SUBSCRIBE_STATES_REQUEST).await.unwrap();
+        for (key, value) in [(1u32, 612.0f32), (2, 21.5)] {
+            let mut state = fixed32(1, key);
+            state.extend(fixed32(2, value.to_bits()));
+            conn.send(SENSOR_STATE_RESPONSE, &Message(state))
+                .await
+                .unwrap();
+        }
+
+        let _ = conn.expect(DISCONNECT_REQUEST).await;
+    }
+
+    #[tokio::test]
+    async fn test_get_status() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let device = tokio::spawn(fake_device(listener));
+
+        let client = NativeApiClient::new(
+            &format!("http://127.0.0.1:{}", port),
+            Some("secret".to_string()),
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        let status = client.get_status("Office").await.unwrap();
+        device.await.unwrap();
+
+        assert_eq!(status.device_name, "Office");
+        assert_eq!(status.sensors["co2"].value, 612.0);
+        assert_eq!(status.sensors["co2"].unit, "ppm");
+        assert_eq!(status.sensors["co2"].name, "CO2");
+        assert_eq!(status.sensors["sen55_temperature"].value, 21.5);
+        let info = status.info.unwrap();
+        assert_eq!(info.esphome_version, "2025.5.0");
+        assert_eq!(info.project_version, "25.5.1.1");
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_device_rejected() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(&[ENCRYPTED_PREAMBLE, 0, 0]).await.unwrap();
+        });
+
+        let client = NativeApiClient::new(
+            &format!("http://127.0.0.1:{}", port),
+            None,
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        let error = client.get_status("Office").await.unwrap_err();
+        assert!(error.to_string().contains("encryption"));
+    }
+
+    #[test]
+    fn test_default_port() {
+        let client =
+            NativeApiClient::new("http://192.168.1.100", None, Duration::from_secs(5)).unwrap();
+        assert_eq!(client.port, DEFAULT_PORT);
+        assert_eq!(client.host, "192.168.1.100");
+    }
+}
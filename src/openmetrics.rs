@@ -0,0 +1,168 @@
+//! OpenMetrics text exposition, for scrapers that negotiate it via `Accept`.
+//!
+//! The `prometheus` crate only ships the classic text format. OpenMetrics
+//! differs mostly in naming counter families without their `_total` suffix
+//! and terminating the exposition with `# EOF`.
+
+use prometheus::proto::{LabelPair, Metric, MetricFamily, MetricType};
+use prometheus::{Encoder, Error, Result};
+use std::io::Write;
+
+/// Content type of the OpenMetrics text format
+pub const OPENMETRICS_FORMAT: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Encodes metric families in the OpenMetrics text format
+#[derive(Debug, Default)]
+pub struct OpenMetricsEncoder;
+
+impl OpenMetricsEncoder {
+    pub fn new() -> Self {
+        OpenMetricsEncoder
+    }
+}
+
+impl Encoder for OpenMetricsEncoder {
+    fn encode<W: Write>(&self, metric_families: &[MetricFamily], writer: &mut W) -> Result<()> {
+        for mf in metric_families {
+            let name = mf.name();
+            let metric_type = mf.get_field_type();
+
+            // Counter families are named without the sample suffix
+            let family = match metric_type {
+                MetricType::COUNTER => name.strip_suffix("_total").unwrap_or(name),
+                _ => name,
+            };
+            let type_name = match metric_type {
+                MetricType::COUNTER => "counter",
+                MetricType::GAUGE => "gauge",
+                MetricType::UNTYPED => "unknown",
+                other => {
+                    return Err(Error::Msg(format!(
+                        "unsupported metric type {:?} for {}",
+                        other, name
+                    )));
+                }
+            };
+
+            if !mf.help().is_empty() {
+                writeln!(writer, "# HELP {} {}", family, escape(mf.help(), false))?;
+            }
+            writeln!(writer, "# TYPE {} {}", family, type_name)?;
+
+            for m in mf.get_metric() {
+                match metric_type {
+                    MetricType::COUNTER => {
+                        let sample = format!("{}_total", family);
+                        write_sample(writer, &sample, m, m.get_counter().value())?;
+                    }
+                    MetricType::GAUGE => {
+                        write_sample(writer, name, m, m.get_gauge().value())?;
+                    }
+                    _ => {
+                        write_sample(writer, name, m, m.untyped.value())?;
+                    }
+                }
+            }
+        }
+
+        writeln!(writer, "# EOF")?;
+        Ok(())
+    }
+
+    fn format_type(&self) -> &str {
+        OPENMETRICS_FORMAT
+    }
+}
+
+fn write_sample<W: Write>(writer: &mut W, name: &str, m: &Metric, value: f64) -> Result<()> {
+    write!(writer, "{}", name)?;
+    write_labels(writer, m.get_label())?;
+    write!(writer, " {}", format_value(value))?;
+
+    // OpenMetrics timestamps are in seconds
+    let timestamp_ms = m.timestamp_ms();
+    if timestamp_ms != 0 {
+        write!(writer, " {}", timestamp_ms as f64 / 1000.0)?;
+    }
+
+    writeln!(writer)?;
+    Ok(())
+}
+
+fn write_labels<W: Write>(writer: &mut W, labels: &[LabelPair]) -> Result<()> {
+    if labels.is_empty() {
+        return Ok(());
+    }
+
+    let pairs: Vec<String> = labels
+        .iter()
+        .map(|label| format!("{}=\"{}\"", label.name(), escape(label.value(), true)))
+        .collect();
+    write!(writer, "{{{}}}", pairs.join(","))?;
+    Ok(())
+}
+
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+Inf" } else { "-Inf" }.to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+fn escape(value: &str, include_quote: bool) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '"' if include_quote => escaped.push_str("\\\""),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::{
+        Registry, register_gauge_vec_with_registry, register_int_counter_vec_with_registry,
+    };
+
+    #[test]
+    fn test_encode_openmetrics() {
+        let registry = Registry::new();
+        let gauge = register_gauge_vec_with_registry!(
+            "apollo_air1_co2_ppm",
+            "CO2 concentration in ppm",
+            &["device"],
+            registry
+        )
+        .unwrap();
+        gauge.with_label_values(&["Living \"Room\""]).set(450.0);
+
+        let counter = register_int_counter_vec_with_registry!(
+            "apollo_air1_scrape_errors_total",
+            "Total scrape errors",
+            &["device"],
+            registry
+        )
+        .unwrap();
+        counter.with_label_values(&["Office"]).inc();
+
+        let mut buffer = Vec::new();
+        OpenMetricsEncoder::new()
+            .encode(&registry.gather(), &mut buffer)
+            .unwrap();
+        let output = String::from_utf8(buffer).unwrap();
+
+        assert!(output.contains("# TYPE apollo_air1_co2_ppm gauge\n"));
+        assert!(output.contains(r#"apollo_air1_co2_ppm{device="Living \"Room\""} 450"#));
+        assert!(output.contains("# TYPE apollo_air1_scrape_errors counter\n"));
+        assert!(output.contains(r#"apollo_air1_scrape_errors_total{device="Office"} 1"#));
+        assert!(output.ends_with("# EOF\n"));
+    }
+}
@@ -0,0 +1,106 @@
+//! Persists rolling AQI state (NowCast windows and moving averages) in a JSON
+//! file, so the AQI doesn't start over after every restart.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::metrics::SavedPmHistory;
+
+/// Layout of the state file
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StateFile {
+    #[serde(default)]
+    pm_history: Vec<SavedPmHistory>,
+}
+
+/// Loads the saved PM history; a missing file means there is none yet
+pub fn load(path: &Path) -> Result<Vec<SavedPmHistory>> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to read state file {}", path.display()));
+        }
+    };
+
+    let state: StateFile = serde_json::from_str(&contents)
+        .with_context(|| format!("Invalid state file {}", path.display()))?;
+    Ok(state.pm_history)
+}
+
+/// Writes the PM history, replacing the file atomically so a crash mid-write
+/// never leaves it truncated
+pub fn save(path: &Path, pm_history: Vec<SavedPmHistory>) -> Result<()> {
+    let contents = serde_json::to_string(&StateFile { pm_history })?;
+
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    std::fs::write(&temp_path, contents)
+        .with_context(|| format!("Failed to write state file {}", path.display()))?;
+    std::fs::rename(&temp_path, path)
+        .with_context(|| format!("Failed to replace state file {}", path.display()))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apollo::{ApolloStatus, SensorValue};
+    use crate::config::AqiMode;
+    use crate::metrics::{Metrics, MetricsOptions};
+    use std::collections::HashMap;
+
+    fn metrics_with_history() -> Metrics {
+        let options = MetricsOptions {
+            aqi_mode: AqiMode::NowCast,
+            aqi_smoothing: Some(0.5),
+            ..Default::default()
+        };
+        let metrics = Metrics::new(options).unwrap();
+
+        let mut sensors = HashMap::new();
+        sensors.insert(
+            "pm__2_5_m_weight_concentration".to_string(),
+            SensorValue {
+                value: 12.0,
+                unit: "µg/m³".to_string(),
+                name: "PM2.5".to_string(),
+            },
+        );
+        let status = ApolloStatus {
+            sensors,
+            device_name: "office".to_string(),
+            info: None,
+        };
+        metrics.update_device("192.168.1.100", &status).unwrap();
+        metrics
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = std::env::temp_dir().join(format!(
+            "apollo-air1-exporter-state-{}.json",
+            std::process::id()
+        ));
+
+        save(&path, metrics_with_history().save_pm_history()).unwrap();
+        let loaded = load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].device, "office");
+        assert_eq!(loaded[0].host, "192.168.1.100");
+
+        let metrics = Metrics::new(MetricsOptions::default()).unwrap();
+        metrics.restore_pm_history(loaded);
+        assert_eq!(metrics.save_pm_history().len(), 1);
+    }
+
+    #[test]
+    fn test_load_missing_file() {
+        let path = std::env::temp_dir().join("apollo-air1-exporter-missing-state.json");
+        assert!(load(&path).unwrap().is_empty());
+    }
+}